@@ -1,9 +1,24 @@
 use std::{
     path::{Path, PathBuf},
     process::Command,
+    time::Duration,
 };
 
 const RUN_ARGS: &[&str] = &["--no-reboot", "-s", "-d", "int,cpu_reset,guest_errors", "-D", "qemu.log"];
+const TEST_ARGS: &[&str] = &[
+    "-device",
+    "isa-debug-exit,iobase=0xf4,iosize=0x04",
+    "-serial",
+    "stdio",
+    "-display",
+    "none",
+    "--no-reboot",
+    "-smp",
+    "4",
+];
+const TEST_TIMEOUT_SECS: u64 = 30;
+// `QemuExitCode::Success` as reported by the isa-debug-exit device
+const TEST_SUCCESS_CODE: i32 = (0x10 << 1) | 1;
 
 fn main() {
     let mut args = std::env::args().skip(1); // skip executable name
@@ -21,6 +36,12 @@ fn main() {
         false
     };
 
+    // integration tests are placed in the `deps` directory by cargo
+    let is_test = kernel_binary_path
+        .parent()
+        .map(|path| path.ends_with("deps"))
+        .unwrap_or(false);
+
     let bios = create_disk_images(&kernel_binary_path);
 
     if no_boot {
@@ -32,6 +53,12 @@ fn main() {
     run_cmd
         .arg("-drive")
         .arg(format!("format=raw,file={}", bios.display()));
+
+    if is_test {
+        run_cmd.args(TEST_ARGS);
+        run_test(run_cmd);
+    }
+
     run_cmd.args(RUN_ARGS);
 
     let exit_status = run_cmd.status().unwrap();
@@ -40,6 +67,29 @@ fn main() {
     }
 }
 
+fn run_test(mut run_cmd: Command) -> ! {
+    let mut child = run_cmd.spawn().unwrap();
+    let timeout = Duration::from_secs(TEST_TIMEOUT_SECS);
+    let start = std::time::Instant::now();
+
+    let exit_status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            child.kill().unwrap();
+            eprintln!("test timed out after {} seconds", TEST_TIMEOUT_SECS);
+            std::process::exit(2);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    match exit_status.code() {
+        Some(TEST_SUCCESS_CODE) => std::process::exit(0),
+        other => std::process::exit(other.unwrap_or(1)),
+    }
+}
+
 pub fn create_disk_images(kernel_binary_path: &Path) -> PathBuf {
     let bootloader_manifest_path = bootloader_locator::locate_bootloader("bootloader").unwrap();
     let kernel_manifest_path = locate_cargo_manifest::locate_manifest().unwrap();
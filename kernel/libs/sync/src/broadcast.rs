@@ -0,0 +1,307 @@
+//! A channel delivering every value to every receiver.
+//!
+//! The channel keeps the last `capacity` values. Sending never waits: once
+//! the channel is full the oldest value is dropped, and a receiver that had
+//! not seen it yet learns how many values it missed.
+
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_core::Stream;
+
+/// Every receiver is gone, the value is given back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind and missed that many values. The next
+    /// receive returns the oldest value still kept.
+    Lagged(u64),
+    /// Every sender is gone and the receiver has seen every value.
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// See `RecvError::Lagged`.
+    Lagged(u64),
+    Closed,
+}
+
+struct Shared<T> {
+    state: Lock<State<T>>,
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// Position of the first value in `buffer` among all values sent.
+    head: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+impl<T> State<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+/// Creates a channel keeping the last `capacity` values.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Arc::new(Shared {
+        state: Lock::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            head: 0,
+            senders: 1,
+            receivers: 1,
+            waiters: WaitQueue::new(),
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared,
+            next: 0,
+            waiter: None,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to every receiver, returning how many there are.
+    ///
+    /// Can be called from interrupt handlers, it never allocates.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.state.lock();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if state.buffer.len() == state.capacity {
+            state.buffer.pop_front();
+            state.head += 1;
+        }
+        state.buffer.push_back(value);
+        state.waiters.wake_all();
+        Ok(state.receivers)
+    }
+
+    /// Creates a receiver of the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.state.lock();
+        state.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail(),
+            waiter: None,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.waiters.wake_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Position of the next value to receive among all values sent.
+    next: u64,
+    waiter: Option<u64>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for the next value.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock();
+        match next_value(&mut self.next, &mut state) {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(RecvError::Lagged(missed))) => Err(TryRecvError::Lagged(missed)),
+            Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let mut state = self.shared.state.lock();
+        if let Some(result) = next_value(&mut self.next, &mut state) {
+            return Poll::Ready(result);
+        }
+        state.waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// Receives the value at position `next`, returning `None` if there is
+/// nothing to receive yet.
+fn next_value<T: Clone>(next: &mut u64, state: &mut State<T>) -> Option<Result<T, RecvError>> {
+    if *next < state.head {
+        let missed = state.head - *next;
+        *next = state.head;
+        return Some(Err(RecvError::Lagged(missed)));
+    }
+    match state.buffer.get((*next - state.head) as usize) {
+        Some(value) => {
+            *next += 1;
+            Some(Ok(value.clone()))
+        }
+        None if state.senders == 0 => Some(Err(RecvError::Closed)),
+        None => None,
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone receives the same values as the original from now on.
+    fn clone(&self) -> Self {
+        self.shared.state.lock().receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+            waiter: None,
+        }
+    }
+}
+
+/// Yields every value, skipping over the ones missed by falling behind.
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        loop {
+            match self.poll_recv(cx) {
+                Poll::Ready(Ok(value)) => return Poll::Ready(Some(value)),
+                Poll::Ready(Err(RecvError::Lagged(_))) => continue,
+                Poll::Ready(Err(RecvError::Closed)) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receivers -= 1;
+        if let Some(id) = self.waiter {
+            state.waiters.remove(id);
+        }
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, RecvError, SendError, TryRecvError};
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_every_receiver_gets_every_value() {
+        let (sender, mut first) = channel(4);
+        let mut second = sender.subscribe();
+        assert_eq!(sender.send(1), Ok(2));
+        assert_eq!(sender.send(2), Ok(2));
+        for receiver in [&mut first, &mut second] {
+            assert_eq!(ready(receiver.recv()), Ok(1));
+            assert_eq!(ready(receiver.recv()), Ok(2));
+            assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        }
+    }
+
+    #[test]
+    fn test_subscriber_only_sees_later_values() {
+        let (sender, _receiver) = channel(4);
+        sender.send(1).unwrap();
+        let mut late = sender.subscribe();
+        sender.send(2).unwrap();
+        assert_eq!(late.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn test_slow_receiver_lags() {
+        let (sender, mut receiver) = channel(2);
+        for value in 0..5 {
+            sender.send(value).unwrap();
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(3)));
+        assert_eq!(receiver.try_recv(), Ok(3));
+        assert_eq!(receiver.try_recv(), Ok(4));
+    }
+
+    #[test]
+    fn test_send_wakes_waiting_receivers() {
+        let (sender, mut first) = channel(1);
+        let mut second = first.clone();
+        let (counter, waker) = CountingWaker::new();
+        assert!(poll(&mut first.recv(), &waker).is_pending());
+        assert!(poll(&mut second.recv(), &waker).is_pending());
+        sender.send(()).unwrap();
+        assert_eq!(counter.wakes(), 2);
+    }
+
+    #[test]
+    fn test_channel_closes_with_last_sender() {
+        let (sender, mut receiver) = channel(1);
+        sender.send(1).unwrap();
+        drop(sender);
+        assert_eq!(ready(receiver.recv()), Ok(1));
+        assert_eq!(ready(receiver.recv()), Err(RecvError::Closed));
+    }
+
+    #[test]
+    fn test_send_without_receivers_fails() {
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+}
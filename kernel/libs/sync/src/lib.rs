@@ -0,0 +1,34 @@
+//! Synchronization primitives for async tasks.
+//!
+//! Nothing here spins while waiting: a task that cannot go on registers its
+//! waker and returns `Poll::Pending`, leaving the executor to run others.
+//! The operations that never wait (the `try_` ones, sending on unbounded
+//! and broadcast channels, `Notify::notify_one`, adding permits) can also be
+//! called from interrupt handlers, once `set_critical_section` has been
+//! told how to mask interrupts.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+mod lock;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+
+#[cfg(test)]
+mod test_util;
+
+pub use lock::set_critical_section;
+pub use mutex::{Mutex, MutexGuard, MutexLockFuture};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadFuture, RwLockReadGuard, RwLockWriteFuture, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
@@ -0,0 +1,69 @@
+use spin::{MutexGuard, Once};
+
+type CriticalSection = (fn() -> bool, fn(bool));
+
+static CRITICAL_SECTION: Once<CriticalSection> = Once::new();
+
+/// Sets the functions run around every short internal lock, so that an
+/// interrupt handler never finds such a lock held by the code it
+/// interrupted: `enter` masks interrupts and returns whether they were
+/// enabled, `exit` gets that value back to restore them.
+///
+/// Only the first call has an effect. Without it, the primitives must not
+/// be used from interrupt handlers.
+pub fn set_critical_section(enter: fn() -> bool, exit: fn(bool)) {
+    CRITICAL_SECTION.call_once(|| (enter, exit));
+}
+
+/// The spinlock guarding the state of every primitive, only ever held for
+/// a few instructions.
+pub(crate) struct Lock<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Lock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Lock {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> LockGuard<'_, T> {
+        let restore = Restore(CRITICAL_SECTION.get().map(|(enter, _)| enter()));
+        LockGuard {
+            guard: self.inner.lock(),
+            _restore: restore,
+        }
+    }
+}
+
+/// Fields are dropped in order: the lock is released before interrupts are
+/// restored.
+pub(crate) struct LockGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    _restore: Restore,
+}
+
+impl<T> core::ops::Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> core::ops::DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+struct Restore(Option<bool>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let (Some(state), Some((_, exit))) = (self.0, CRITICAL_SECTION.get()) {
+            exit(state);
+        }
+    }
+}
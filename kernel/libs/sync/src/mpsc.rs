@@ -0,0 +1,325 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! A bounded channel makes `send` wait while it is full, and `try_send`
+//! fail instead; its buffer is allocated up front, so `try_send` does not
+//! allocate. An unbounded channel never makes senders wait.
+
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_core::Stream;
+
+/// The receiver is gone, the value is given back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and the channel is empty.
+    Disconnected,
+}
+
+struct Shared<T> {
+    state: Lock<State<T>>,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// `None` for an unbounded channel.
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+    send_waiters: WaitQueue,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.queue.len() >= capacity)
+    }
+
+    fn push(&mut self, value: T) {
+        self.queue.push_back(value);
+        if let Some(waker) = self.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Creates a channel holding at most `capacity` values.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    new(VecDeque::with_capacity(capacity), Some(capacity))
+}
+
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new(VecDeque::new(), None)
+}
+
+fn new<T>(queue: VecDeque<T>, capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Lock::new(State {
+            queue,
+            capacity,
+            senders: 1,
+            receiver_alive: true,
+            receiver_waker: None,
+            send_waiters: WaitQueue::new(),
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting while the channel is full.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+
+    /// Sends `value` unless the channel is full.
+    ///
+    /// Can be called from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state.lock();
+        if !state.receiver_alive {
+            return Err(TrySendError::Closed(value));
+        }
+        if state.is_full() {
+            return Err(TrySendError::Full(value));
+        }
+        state.push(value);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.state.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Option<u64>,
+}
+
+// The value is never pinned
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        let this = &mut *self;
+        let mut state = this.sender.shared.state.lock();
+        let value = this.value.take().expect("send polled after completion");
+        if !state.receiver_alive {
+            return Poll::Ready(Err(SendError(value)));
+        }
+        if state.is_full() {
+            this.value = Some(value);
+            state.send_waiters.register(&mut this.waiter, cx.waker());
+            return Poll::Pending;
+        }
+        if let Some(id) = this.waiter.take() {
+            state.send_waiters.remove(id);
+        }
+        state.push(value);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.sender.shared.state.lock();
+            if !state.send_waiters.remove(id) && !state.is_full() {
+                state.send_waiters.wake_one();
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for a value, or returns `None` once every sender is gone and
+    /// the channel is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock();
+        match Self::pop(&mut state) {
+            Some(value) => Ok(value),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.shared.state.lock();
+        if let Some(value) = Self::pop(&mut state) {
+            return Poll::Ready(Some(value));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        state.receiver_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn pop(state: &mut State<T>) -> Option<T> {
+        let value = state.queue.pop_front()?;
+        if state.capacity.is_some() {
+            state.send_waiters.wake_one();
+        }
+        Some(value)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver_alive = false;
+        state.send_waiters.wake_all();
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, unbounded, SendError, TryRecvError, TrySendError};
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_values_arrive_in_order() {
+        let (sender, mut receiver) = unbounded();
+        for value in 0..1000 {
+            sender.try_send(value).unwrap();
+        }
+        for value in 0..1000 {
+            assert_eq!(ready(receiver.recv()), Some(value));
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_full_channel_makes_sender_wait() {
+        let (sender, mut receiver) = channel(1);
+        ready(sender.send(1)).unwrap();
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+
+        let (counter, waker) = CountingWaker::new();
+        let mut send = sender.send(2);
+        assert!(poll(&mut send, &waker).is_pending());
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(counter.wakes(), 1);
+        assert_eq!(poll(&mut send, &waker), std::task::Poll::Ready(Ok(())));
+        assert_eq!(receiver.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn test_receiver_is_woken_by_send() {
+        let (sender, mut receiver) = channel(4);
+        let (counter, waker) = CountingWaker::new();
+        assert!(poll(&mut receiver.recv(), &waker).is_pending());
+        sender.try_send('a').unwrap();
+        assert_eq!(counter.wakes(), 1);
+        assert_eq!(ready(receiver.recv()), Some('a'));
+    }
+
+    #[test]
+    fn test_channel_ends_with_last_sender() {
+        let (sender, mut receiver) = unbounded();
+        let clone = sender.clone();
+        sender.try_send(1).unwrap();
+        drop(sender);
+        drop(clone);
+        assert_eq!(ready(receiver.recv()), Some(1));
+        assert_eq!(ready(receiver.recv()), None);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_send_to_dropped_receiver_fails() {
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(ready(sender.send(1)), Err(SendError(1)));
+        assert_eq!(sender.try_send(2), Err(TrySendError::Closed(2)));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A mutual exclusion lock whose `lock` waits asynchronously.
+///
+/// The lock is not fair: a freed lock goes to whoever polls for it first,
+/// so a task calling `lock` or `try_lock` right then can take it ahead of
+/// the waiter that was woken. That waiter keeps its place at the front of
+/// the queue, waiting tasks still get the lock in the order they asked.
+pub struct Mutex<T: ?Sized> {
+    state: Lock<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: Lock::new(MutexState {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Waits until the lock is free and takes it.
+    pub fn lock(&self) -> MutexLockFuture<'_, T> {
+        MutexLockFuture {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// No locking is needed, the borrow guarantees exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &&*guard).finish(),
+            None => f.debug_struct("Mutex").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// The future returned by `Mutex::lock`.
+pub struct MutexLockFuture<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    waiter: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for MutexLockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        if !state.locked {
+            state.locked = true;
+            if let Some(id) = self.waiter.take() {
+                state.waiters.remove(id);
+            }
+            return Poll::Ready(MutexGuard { mutex });
+        }
+        state.waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T: ?Sized> Drop for MutexLockFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.mutex.state.lock();
+            // Woken for a lock that is not taken after all
+            if !state.waiters.remove(id) && !state.locked {
+                state.waiters.wake_one();
+            }
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        state.waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mutex;
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let mutex = Mutex::new(1);
+        let guard = ready(mutex.lock());
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        *mutex.try_lock().unwrap() += 1;
+        assert_eq!(mutex.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_unlock_wakes_waiters_in_order() {
+        let mutex = Mutex::new(());
+        let guard = ready(mutex.lock());
+        let (first_counter, first_waker) = CountingWaker::new();
+        let (second_counter, second_waker) = CountingWaker::new();
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll(&mut first, &first_waker).is_pending());
+        assert!(poll(&mut second, &second_waker).is_pending());
+
+        drop(guard);
+        assert_eq!((first_counter.wakes(), second_counter.wakes()), (1, 0));
+        let guard = poll(&mut first, &first_waker);
+        assert!(guard.is_ready());
+        drop(guard);
+        assert_eq!(second_counter.wakes(), 1);
+        assert!(poll(&mut second, &second_waker).is_ready());
+    }
+
+    #[test]
+    fn test_overtaken_waiter_keeps_its_place() {
+        let mutex = Mutex::new(());
+        let guard = ready(mutex.lock());
+        let (first_counter, first_waker) = CountingWaker::new();
+        let (second_counter, second_waker) = CountingWaker::new();
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll(&mut first, &first_waker).is_pending());
+        assert!(poll(&mut second, &second_waker).is_pending());
+
+        drop(guard);
+        let guard = ready(mutex.lock());
+        assert!(poll(&mut first, &first_waker).is_pending());
+        drop(guard);
+        assert_eq!((first_counter.wakes(), second_counter.wakes()), (2, 0));
+        assert!(poll(&mut first, &first_waker).is_ready());
+    }
+
+    #[test]
+    fn test_dropped_waiter_passes_wakeup_on() {
+        let mutex = Mutex::new(());
+        let guard = ready(mutex.lock());
+        let (_, first_waker) = CountingWaker::new();
+        let (second_counter, second_waker) = CountingWaker::new();
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll(&mut first, &first_waker).is_pending());
+        assert!(poll(&mut second, &second_waker).is_pending());
+
+        drop(guard);
+        drop(first);
+        assert_eq!(second_counter.wakes(), 1);
+        assert!(poll(&mut second, &second_waker).is_ready());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Wakes waiting tasks without passing any data.
+///
+/// `notify_one` wakes a single waiter, or if nobody waits, lets the next
+/// call to `notified` return right away. `notify_waiters` wakes everybody
+/// waiting at that moment.
+pub struct Notify {
+    state: Lock<NotifyState>,
+}
+
+struct NotifyState {
+    /// Set by `notify_one` while nobody waited.
+    permit: bool,
+    /// Incremented by every `notify_waiters`.
+    generation: u64,
+    waiters: WaitQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: Lock::new(NotifyState {
+                permit: false,
+                generation: 0,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification. The future already counts as waiting for
+    /// `notify_waiters` once created, before it is first polled.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            generation: self.state.lock().generation,
+            waiter: None,
+        }
+    }
+
+    /// Can be called from interrupt handlers.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        if !state.waiters.wake_one() {
+            state.permit = true;
+        }
+    }
+
+    /// Can be called from interrupt handlers.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        state.waiters.wake_all();
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+/// The future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    generation: u64,
+    waiter: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+        let woken = self.waiter.is_some_and(|id| !state.waiters.contains(id));
+        if woken || state.generation != self.generation {
+            if let Some(id) = self.waiter.take() {
+                state.waiters.remove(id);
+            }
+            return Poll::Ready(());
+        }
+        if state.permit {
+            state.permit = false;
+            return Poll::Ready(());
+        }
+        state.waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.notify.state.lock();
+            // Hand a `notify_one` that went unnoticed to the next waiter
+            if !state.waiters.remove(id)
+                && state.generation == self.generation
+                && !state.waiters.wake_one()
+            {
+                state.permit = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Notify;
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_notify_one_without_waiter_is_kept() {
+        let notify = Notify::new();
+        notify.notify_one();
+        ready(notify.notified());
+        let (_, waker) = CountingWaker::new();
+        assert!(poll(&mut notify.notified(), &waker).is_pending());
+    }
+
+    #[test]
+    fn test_notify_one_wakes_a_single_waiter() {
+        let notify = Notify::new();
+        let (counter, waker) = CountingWaker::new();
+        let mut first = notify.notified();
+        let mut second = notify.notified();
+        assert!(poll(&mut first, &waker).is_pending());
+        assert!(poll(&mut second, &waker).is_pending());
+
+        notify.notify_one();
+        assert_eq!(counter.wakes(), 1);
+        assert!(poll(&mut first, &waker).is_ready());
+        assert!(poll(&mut second, &waker).is_pending());
+    }
+
+    #[test]
+    fn test_notify_waiters_reaches_unpolled_futures() {
+        let notify = Notify::new();
+        let (counter, waker) = CountingWaker::new();
+        let mut polled = notify.notified();
+        let mut unpolled = notify.notified();
+        assert!(poll(&mut polled, &waker).is_pending());
+
+        notify.notify_waiters();
+        assert_eq!(counter.wakes(), 1);
+        assert!(poll(&mut polled, &waker).is_ready());
+        assert!(poll(&mut unpolled, &waker).is_ready());
+        assert!(poll(&mut notify.notified(), &waker).is_pending());
+    }
+
+    #[test]
+    fn test_dropped_waiter_passes_notification_on() {
+        let notify = Notify::new();
+        let (_, waker) = CountingWaker::new();
+        let mut first = notify.notified();
+        assert!(poll(&mut first, &waker).is_pending());
+
+        notify.notify_one();
+        drop(first);
+        ready(notify.notified());
+    }
+}
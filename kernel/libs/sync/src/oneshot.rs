@@ -0,0 +1,155 @@
+//! A channel for sending a single value.
+
+use crate::lock::Lock;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing has been sent yet.
+    Empty,
+    /// The sender was dropped without sending.
+    Closed,
+}
+
+struct Shared<T> {
+    state: Lock<State<T>>,
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Lock::new(State {
+            value: None,
+            sender_alive: true,
+            receiver_alive: true,
+            receiver_waker: None,
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, or gives it back if the receiver is gone.
+    ///
+    /// Can be called from interrupt handlers.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock();
+        if !state.receiver_alive {
+            return Err(value);
+        }
+        state.value = Some(value);
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.sender_alive = false;
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A future resolving to the value sent.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Closed),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let mut state = self.shared.state.lock();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if !state.sender_alive {
+            return Poll::Ready(Err(RecvError));
+        }
+        state.receiver_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().receiver_alive = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, RecvError, TryRecvError};
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_value_is_received() {
+        let (sender, mut receiver) = channel();
+        let (counter, waker) = CountingWaker::new();
+        assert!(poll(&mut receiver, &waker).is_pending());
+        assert_eq!(sender.send(7), Ok(()));
+        assert_eq!(counter.wakes(), 1);
+        assert_eq!(ready(receiver), Ok(7));
+    }
+
+    #[test]
+    fn test_dropped_sender_closes() {
+        let (sender, mut receiver) = channel::<()>();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+        assert_eq!(ready(receiver), Err(RecvError));
+    }
+
+    #[test]
+    fn test_send_to_dropped_receiver_fails() {
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(1), Err(1));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A reader-writer lock whose `read` and `write` wait asynchronously.
+///
+/// Readers are let in whenever no writer holds the lock, so a steady stream
+/// of readers keeps writers waiting.
+pub struct RwLock<T: ?Sized> {
+    state: Lock<RwLockState>,
+    value: UnsafeCell<T>,
+}
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    read_waiters: WaitQueue,
+    write_waiters: WaitQueue,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: Lock::new(RwLockState {
+                readers: 0,
+                writer: false,
+                read_waiters: WaitQueue::new(),
+                write_waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Waits until no writer holds the lock and takes a shared reference.
+    pub fn read(&self) -> RwLockReadFuture<'_, T> {
+        RwLockReadFuture {
+            lock: self,
+            waiter: None,
+        }
+    }
+
+    /// Waits until nobody holds the lock and takes it exclusively.
+    pub fn write(&self) -> RwLockWriteFuture<'_, T> {
+        RwLockWriteFuture {
+            lock: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// The future returned by `RwLock::read`.
+pub struct RwLockReadFuture<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    waiter: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for RwLockReadFuture<'a, T> {
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockReadGuard<'a, T>> {
+        let lock = self.lock;
+        let mut state = lock.state.lock();
+        if !state.writer {
+            state.readers += 1;
+            if let Some(id) = self.waiter.take() {
+                state.read_waiters.remove(id);
+            }
+            return Poll::Ready(RwLockReadGuard { lock });
+        }
+        state.read_waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadFuture<'_, T> {
+    fn drop(&mut self) {
+        // Every waiting reader is woken at once, there is nothing to pass on
+        if let Some(id) = self.waiter {
+            self.lock.state.lock().read_waiters.remove(id);
+        }
+    }
+}
+
+/// The future returned by `RwLock::write`.
+pub struct RwLockWriteFuture<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    waiter: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for RwLockWriteFuture<'a, T> {
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockWriteGuard<'a, T>> {
+        let lock = self.lock;
+        let mut state = lock.state.lock();
+        if !state.writer && state.readers == 0 {
+            state.writer = true;
+            if let Some(id) = self.waiter.take() {
+                state.write_waiters.remove(id);
+            }
+            return Poll::Ready(RwLockWriteGuard { lock });
+        }
+        state.write_waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.lock.state.lock();
+            if !state.write_waiters.remove(id) && !state.writer && state.readers == 0 {
+                state.write_waiters.wake_one();
+            }
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.write_waiters.wake_one();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        // Whoever comes first takes the lock, the others wait again
+        state.read_waiters.wake_all();
+        state.write_waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RwLock;
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_readers_share_the_lock() {
+        let lock = RwLock::new(5);
+        let first = ready(lock.read());
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 10);
+        assert!(lock.try_write().is_none());
+    }
+
+    #[test]
+    fn test_writer_waits_for_readers() {
+        let lock = RwLock::new(0);
+        let reader = ready(lock.read());
+        let (counter, waker) = CountingWaker::new();
+        let mut write = lock.write();
+        assert!(poll(&mut write, &waker).is_pending());
+
+        drop(reader);
+        assert_eq!(counter.wakes(), 1);
+        match poll(&mut write, &waker) {
+            std::task::Poll::Ready(mut guard) => *guard = 1,
+            std::task::Poll::Pending => panic!("writer was not let in"),
+        }
+        drop(write);
+        assert_eq!(lock.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_writer_release_wakes_every_reader() {
+        let lock = RwLock::new(());
+        let writer = ready(lock.write());
+        let (counter, waker) = CountingWaker::new();
+        let mut reads = [lock.read(), lock.read()];
+        for read in &mut reads {
+            assert!(poll(read, &waker).is_pending());
+        }
+
+        drop(writer);
+        assert_eq!(counter.wakes(), 2);
+        for read in &mut reads {
+            assert!(poll(read, &waker).is_ready());
+        }
+    }
+}
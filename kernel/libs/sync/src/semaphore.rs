@@ -0,0 +1,154 @@
+use crate::{lock::Lock, wait_queue::WaitQueue};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A counter of permits, where taking one waits asynchronously while there
+/// are none left.
+pub struct Semaphore {
+    state: Lock<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Lock::new(SemaphoreState {
+                permits,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Waits for a permit and takes it.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.permits == 0 {
+            return None;
+        }
+        state.permits -= 1;
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds `count` permits, waking as many waiting tasks.
+    ///
+    /// Can be called from interrupt handlers.
+    pub fn add_permits(&self, count: usize) {
+        let mut state = self.state.lock();
+        state.permits += count;
+        for _ in 0..count {
+            if !state.waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+}
+
+/// The future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    waiter: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        let mut state = semaphore.state.lock();
+        if state.permits > 0 {
+            state.permits -= 1;
+            if let Some(id) = self.waiter.take() {
+                state.waiters.remove(id);
+            }
+            return Poll::Ready(SemaphorePermit { semaphore });
+        }
+        state.waiters.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.semaphore.state.lock();
+            if !state.waiters.remove(id) && state.permits > 0 {
+                state.waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// A permit taken from a `Semaphore`, given back when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken for good.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Semaphore;
+    use crate::test_util::{poll, ready, CountingWaker};
+
+    #[test]
+    fn test_permits_are_counted() {
+        let semaphore = Semaphore::new(2);
+        let first = ready(semaphore.acquire());
+        let second = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        drop(first);
+        second.forget();
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_added_permits_wake_waiters() {
+        let semaphore = Semaphore::new(0);
+        let (counter, waker) = CountingWaker::new();
+        let mut acquires = [
+            semaphore.acquire(),
+            semaphore.acquire(),
+            semaphore.acquire(),
+        ];
+        for acquire in &mut acquires {
+            assert!(poll(acquire, &waker).is_pending());
+        }
+
+        semaphore.add_permits(2);
+        assert_eq!(counter.wakes(), 2);
+        let [first, second, third] = &mut acquires;
+        let permits = [poll(first, &waker), poll(second, &waker)];
+        assert!(permits.iter().all(|permit| permit.is_ready()));
+        assert!(poll(third, &waker).is_pending());
+    }
+}
@@ -0,0 +1,68 @@
+use alloc::collections::BTreeMap;
+use core::{mem, task::Waker};
+
+/// The tasks waiting on a primitive, woken in the order they started
+/// waiting.
+///
+/// A waiter is known by the id `register` gave it. Being woken removes it,
+/// so a future whose id is gone knows it was woken; if it is then dropped
+/// without making use of that, it has to pass the wakeup on.
+///
+/// Wakers are called with the lock of the primitive held. They only
+/// schedule their task, so they never come back to the primitive.
+pub(crate) struct WaitQueue {
+    next_id: u64,
+    waiters: BTreeMap<u64, Waker>,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            next_id: 0,
+            waiters: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `waker` under `id`, or at the back of the queue under a new
+    /// id if it has none yet. A waiter that was woken but has to wait again
+    /// gets its old place back, ahead of everyone who came later.
+    pub(crate) fn register(&mut self, id: &mut Option<u64>, waker: &Waker) {
+        let id = *id.get_or_insert_with(|| {
+            self.next_id += 1;
+            self.next_id - 1
+        });
+        match self.waiters.get_mut(&id) {
+            Some(existing) if existing.will_wake(waker) => {}
+            Some(existing) => *existing = waker.clone(),
+            None => {
+                self.waiters.insert(id, waker.clone());
+            }
+        }
+    }
+
+    /// Removes a waiter, returning whether it had not been woken yet.
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        self.waiters.remove(&id).is_some()
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.waiters.contains_key(&id)
+    }
+
+    /// Wakes the longest waiting task, returning whether there was one.
+    pub(crate) fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_first() {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn wake_all(&mut self) {
+        for waker in mem::take(&mut self.waiters).into_values() {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use super::sdt::{self, SdtHeader};
+use core::mem;
+use x86_64::PhysAddr;
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+
+/// The `SLP_TYPa` and `SLP_TYPb` values of a sleep state package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Finds the sleep type package of the sleep state `name` (e.g. `_S5_`) in
+/// the DSDT at the given physical address.
+///
+/// This is not a full AML interpreter: it looks for the `Name(_Sx_,
+/// Package() {...})` definition the firmware uses for sleep states and
+/// decodes its first two integer elements.
+pub fn find_sleep_type(dsdt: PhysAddr, name: &[u8; 4]) -> Option<SleepType> {
+    let aml = unsafe { &sdt::bytes(dsdt)[mem::size_of::<SdtHeader>()..] };
+
+    let position = aml.windows(name.len()).enumerate().position(|(i, window)| {
+        // The name may be preceded by the root prefix `\`
+        let op = match i.checked_sub(1).map(|j| aml[j]) {
+            Some(b'\\') => i.checked_sub(2).map(|j| aml[j]),
+            op => op,
+        };
+        window == name && op == Some(NAME_OP)
+    })?;
+
+    let mut bytes = aml[position + name.len()..].iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // The two high bits of the first byte encode how many bytes follow in
+    // the package length
+    let pkg_length_lead = bytes.next()?;
+    for _ in 0..(pkg_length_lead >> 6) {
+        bytes.next()?;
+    }
+    let _element_count = bytes.next()?;
+
+    let mut integer = || match bytes.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => bytes.next(),
+        _ => None,
+    };
+    let a = integer()?;
+    let b = integer()?;
+
+    Some(SleepType { a, b })
+}
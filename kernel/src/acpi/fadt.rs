@@ -0,0 +1,119 @@
+use super::sdt::{self, GenericAddress, RawGenericAddress, SdtHeader};
+use x86_64::PhysAddr;
+
+/// Set in `flags` when the reset register is supported.
+const RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct RawFadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_block_length: u8,
+    gpe1_block_length: u8,
+    gpe1_base: u8,
+    cstate_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved2: u8,
+    flags: u32,
+    reset_register: RawGenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_event_block: RawGenericAddress,
+    x_pm1b_event_block: RawGenericAddress,
+    x_pm1a_control_block: RawGenericAddress,
+    x_pm1b_control_block: RawGenericAddress,
+    x_pm2_control_block: RawGenericAddress,
+    x_pm_timer_block: RawGenericAddress,
+}
+
+/// Fixed ACPI Description Table, describing the fixed power management hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    /// Port used to hand over power management from the firmware, if ACPI
+    /// is not already enabled.
+    pub smi_command_port: u16,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: Option<u16>,
+    pub pm_timer_block: Option<u16>,
+    /// Index of the CMOS register holding the century, if there is one.
+    pub century_register: Option<u8>,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// Parses the FADT found at the given physical address.
+pub fn parse(addr: PhysAddr) -> Fadt {
+    let raw: RawFadt = unsafe { sdt::read_table(addr) };
+
+    // The 64-bit fields take precedence when present
+    let dsdt = match raw.x_dsdt {
+        0 => raw.dsdt as u64,
+        x_dsdt => x_dsdt,
+    };
+    let io_port = |legacy: u32, extended: RawGenericAddress| match legacy {
+        0 => GenericAddress::from_raw(extended).map_or(0, |gas| gas.address as u16),
+        port => port as u16,
+    };
+    let non_zero = |port: u16| if port == 0 { None } else { Some(port) };
+
+    let flags = raw.flags;
+    let reset_register = if flags & RESET_REG_SUP != 0 {
+        GenericAddress::from_raw(raw.reset_register)
+    } else {
+        None
+    };
+
+    Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: raw.sci_interrupt,
+        smi_command_port: raw.smi_command as u16,
+        acpi_enable: raw.acpi_enable,
+        acpi_disable: raw.acpi_disable,
+        pm1a_control_block: io_port(raw.pm1a_control_block, raw.x_pm1a_control_block),
+        pm1b_control_block: non_zero(io_port(raw.pm1b_control_block, raw.x_pm1b_control_block)),
+        pm_timer_block: non_zero(io_port(raw.pm_timer_block, raw.x_pm_timer_block)),
+        century_register: if raw.century == 0 { None } else { Some(raw.century) },
+        flags,
+        reset_register,
+        reset_value: raw.reset_value,
+    }
+}
@@ -0,0 +1,43 @@
+use super::sdt::{self, GenericAddress, RawGenericAddress, SdtHeader};
+use x86_64::PhysAddr;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct RawHpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: RawGenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+/// High Precision Event Timer description table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub base_address: PhysAddr,
+    pub hpet_number: u8,
+    /// Minimum tick, in periods of the main counter, usable in periodic mode.
+    pub minimum_tick: u16,
+    pub comparator_count: u8,
+    pub counter_is_64_bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+}
+
+/// Parses the HPET table found at the given physical address.
+pub fn parse(addr: PhysAddr) -> Option<Hpet> {
+    let raw: RawHpet = unsafe { sdt::read_table(addr) };
+    let base_address = GenericAddress::from_raw(raw.base_address)?;
+    let id = raw.event_timer_block_id;
+
+    Some(Hpet {
+        base_address: PhysAddr::new(base_address.address),
+        hpet_number: raw.hpet_number,
+        minimum_tick: raw.minimum_tick,
+        comparator_count: ((id >> 8) & 0x1F) as u8 + 1,
+        counter_is_64_bit: id & (1 << 13) != 0,
+        legacy_replacement: id & (1 << 15) != 0,
+        pci_vendor_id: (id >> 16) as u16,
+    })
+}
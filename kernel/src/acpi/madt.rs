@@ -0,0 +1,197 @@
+use super::sdt::{self, SdtHeader};
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Describes an ISA interrupt that is not wired to the IO-APIC pin with the
+/// same number, or that uses a non-default polarity or trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the system also has the legacy dual 8259 PICs installed.
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Madt {
+    /// Returns the override for the given ISA IRQ, if there is one.
+    pub fn interrupt_override(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.interrupt_overrides.iter().find(|o| o.source == irq)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct EntryHeader {
+    entry_type: u8,
+    length: u8,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct LocalApicEntry {
+    header: EntryHeader,
+    processor_uid: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct IoApicEntry {
+    header: EntryHeader,
+    id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct InterruptSourceOverrideEntry {
+    header: EntryHeader,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct LocalApicAddressOverrideEntry {
+    header: EntryHeader,
+    reserved: u16,
+    address: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct LocalX2ApicEntry {
+    header: EntryHeader,
+    reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    processor_uid: u32,
+}
+
+/// Parses the MADT found at the given physical address.
+pub fn parse(addr: PhysAddr) -> Madt {
+    let header: SdtHeader = unsafe { sdt::read(addr) };
+    let local_apic_address: u32 = unsafe { sdt::read(addr + mem::size_of::<SdtHeader>()) };
+    let flags: u32 = unsafe { sdt::read(addr + mem::size_of::<SdtHeader>() + 4usize) };
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(local_apic_address as u64),
+        has_legacy_pics: flags & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        interrupt_overrides: Vec::new(),
+    };
+
+    let end = addr + header.length as u64;
+    let mut entry_addr = addr + mem::size_of::<SdtHeader>() + 8usize;
+    while entry_addr + mem::size_of::<EntryHeader>() <= end {
+        let entry: EntryHeader = unsafe { sdt::read(entry_addr) };
+        if entry.length < 2 {
+            break; // malformed table, avoid looping forever
+        }
+
+        match entry.entry_type {
+            ENTRY_LOCAL_APIC => {
+                let entry: LocalApicEntry = unsafe { sdt::read(entry_addr) };
+                madt.processors.push(Processor {
+                    processor_uid: entry.processor_uid as u32,
+                    apic_id: entry.apic_id as u32,
+                    enabled: entry.flags & 1 != 0,
+                });
+            }
+            ENTRY_IO_APIC => {
+                let entry: IoApicEntry = unsafe { sdt::read(entry_addr) };
+                madt.io_apics.push(IoApic {
+                    id: entry.id,
+                    address: PhysAddr::new(entry.address as u64),
+                    gsi_base: entry.gsi_base,
+                });
+            }
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                let entry: InterruptSourceOverrideEntry = unsafe { sdt::read(entry_addr) };
+                // Bits 0-1 and 2-3 of the flags select the polarity and the
+                // trigger mode, 0 meaning "conforms to the bus" (ISA: active
+                // high and edge triggered)
+                let polarity = match entry.flags & 0b11 {
+                    0b11 => Polarity::ActiveLow,
+                    _ => Polarity::ActiveHigh,
+                };
+                let trigger_mode = match (entry.flags >> 2) & 0b11 {
+                    0b11 => TriggerMode::Level,
+                    _ => TriggerMode::Edge,
+                };
+                madt.interrupt_overrides.push(InterruptSourceOverride {
+                    source: entry.source,
+                    gsi: entry.gsi,
+                    polarity,
+                    trigger_mode,
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                let entry: LocalApicAddressOverrideEntry = unsafe { sdt::read(entry_addr) };
+                madt.local_apic_address = PhysAddr::new(entry.address);
+            }
+            ENTRY_LOCAL_X2APIC => {
+                let entry: LocalX2ApicEntry = unsafe { sdt::read(entry_addr) };
+                madt.processors.push(Processor {
+                    processor_uid: entry.processor_uid,
+                    apic_id: entry.x2apic_id,
+                    enabled: entry.flags & 1 != 0,
+                });
+            }
+            _ => {}
+        }
+
+        entry_addr += entry.length as u64;
+    }
+
+    madt
+}
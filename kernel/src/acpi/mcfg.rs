@@ -0,0 +1,56 @@
+use super::sdt::{self, SdtHeader};
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct RawConfigRegion {
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+/// A PCI Express enhanced configuration access mechanism (ECAM) region.
+#[derive(Debug, Clone, Copy)]
+pub struct PciConfigRegion {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl PciConfigRegion {
+    /// Returns the base of the configuration space of `bus`, if this region covers it.
+    pub fn bus_address(&self, bus: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus {
+            return None;
+        }
+        // Each bus has 32 devices with 8 functions of 4 KiB each, and the
+        // base address is the one of bus 0 even if the region starts later
+        Some(self.base_address + ((bus as u64) << 20))
+    }
+}
+
+/// Parses the MCFG table found at the given physical address.
+pub fn parse(addr: PhysAddr) -> Vec<PciConfigRegion> {
+    let header: SdtHeader = unsafe { sdt::read(addr) };
+    // The region list follows 8 reserved bytes after the header
+    let entries_start = addr + mem::size_of::<SdtHeader>() + 8usize;
+    let entries_len = (header.length as usize).saturating_sub(mem::size_of::<SdtHeader>() + 8);
+
+    (0..entries_len / mem::size_of::<RawConfigRegion>())
+        .map(|i| {
+            let raw: RawConfigRegion =
+                unsafe { sdt::read(entries_start + i * mem::size_of::<RawConfigRegion>()) };
+            PciConfigRegion {
+                base_address: PhysAddr::new(raw.base_address),
+                segment_group: raw.segment_group,
+                start_bus: raw.start_bus,
+                end_bus: raw.end_bus,
+            }
+        })
+        .collect()
+}
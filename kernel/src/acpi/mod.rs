@@ -0,0 +1,219 @@
+use crate::serial_println;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{mem, slice};
+use dsdt::SleepType;
+use fadt::Fadt;
+use hpet::Hpet;
+use madt::{Madt, Processor};
+use mcfg::PciConfigRegion;
+use sdt::SdtHeader;
+use x86_64::PhysAddr;
+
+pub mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+mod sdt;
+
+pub use sdt::{AddressSpace, GenericAddress};
+
+/// Size of the RSDP structure in ACPI 1.0, covered by its first checksum.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Root System Description Pointer, as found by the bootloader.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below are only valid from revision 2 onwards
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    InvalidRsdpSignature,
+    InvalidRsdpChecksum,
+    InvalidRootTable,
+}
+
+struct Tables {
+    addresses: Vec<PhysAddr>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Vec<PciConfigRegion>,
+    s5_sleep_type: Option<SleepType>,
+}
+
+static TABLES: OnceCell<Tables> = OnceCell::uninit();
+
+/// Validates the RSDP and the RSDT or XSDT it points to, and parses the
+/// tables the kernel knows about.
+///
+/// Tables with an invalid checksum are ignored.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that
+/// `rsdp_addr` is the physical address of the RSDP and that the physical
+/// memory mapping has been set up.
+pub unsafe fn init(rsdp_addr: PhysAddr) -> Result<(), AcpiError> {
+    let rsdp: Rsdp = sdt::read(rsdp_addr);
+    if &rsdp.signature != b"RSD PTR " {
+        return Err(AcpiError::InvalidRsdpSignature);
+    }
+
+    let rsdp_bytes = slice::from_raw_parts(
+        crate::memory::phys_to_virt(rsdp_addr).as_ptr::<u8>(),
+        mem::size_of::<Rsdp>(),
+    );
+    if !sdt::checksum_is_valid(&rsdp_bytes[..RSDP_V1_LENGTH]) {
+        return Err(AcpiError::InvalidRsdpChecksum);
+    }
+
+    // ACPI 2.0+ provides the XSDT with 64-bit pointers, older firmware
+    // only has the RSDT with 32-bit ones
+    let use_xsdt = rsdp.revision >= 2
+        && rsdp.xsdt_address != 0
+        && sdt::checksum_is_valid(&rsdp_bytes[..mem::size_of::<Rsdp>()]);
+    let (root_addr, entry_size) = if use_xsdt {
+        (PhysAddr::new(rsdp.xsdt_address), mem::size_of::<u64>())
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), mem::size_of::<u32>())
+    };
+
+    let header: SdtHeader = sdt::read(root_addr);
+    let expected_signature = if use_xsdt { b"XSDT" } else { b"RSDT" };
+    if &header.signature != expected_signature
+        || (header.length as usize) < mem::size_of::<SdtHeader>()
+        || !sdt::checksum_is_valid(sdt::bytes(root_addr))
+    {
+        return Err(AcpiError::InvalidRootTable);
+    }
+
+    let entries_start = root_addr + mem::size_of::<SdtHeader>();
+    let entry_count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+
+    let addresses = (0..entry_count)
+        .map(|i| {
+            let entry_addr = entries_start + i * entry_size;
+            let table_addr = if use_xsdt {
+                sdt::read::<u64>(entry_addr)
+            } else {
+                sdt::read::<u32>(entry_addr) as u64
+            };
+            PhysAddr::new(table_addr)
+        })
+        .filter(|&addr| {
+            let valid = sdt::checksum_is_valid(sdt::bytes(addr));
+            if !valid {
+                let header: SdtHeader = sdt::read(addr);
+                serial_println!(
+                    "WARNING: ignoring ACPI table {:?} with invalid checksum",
+                    core::str::from_utf8(&header.signature)
+                );
+            }
+            valid
+        })
+        .collect::<Vec<_>>();
+
+    let find = |signature: &[u8; 4]| {
+        addresses.iter().copied().find(|&addr| {
+            let header: SdtHeader = sdt::read(addr);
+            &header.signature == signature
+        })
+    };
+    let madt = find(b"APIC").map(madt::parse);
+    let fadt = find(b"FACP").map(fadt::parse);
+    let hpet = find(b"HPET").and_then(hpet::parse);
+    let mcfg = find(b"MCFG").map(mcfg::parse).unwrap_or_default();
+    let s5_sleep_type = fadt
+        .filter(|fadt| fadt.dsdt.as_u64() != 0 && sdt::checksum_is_valid(sdt::bytes(fadt.dsdt)))
+        .and_then(|fadt| dsdt::find_sleep_type(fadt.dsdt, b"_S5_"));
+
+    TABLES.init_once(|| Tables {
+        addresses,
+        madt,
+        fadt,
+        hpet,
+        mcfg,
+        s5_sleep_type,
+    });
+
+    Ok(())
+}
+
+/// Returns the physical address of the first valid table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    TABLES.get()?.addresses.iter().copied().find(|&addr| {
+        let header: SdtHeader = unsafe { sdt::read(addr) };
+        &header.signature == signature
+    })
+}
+
+/// Returns the Multiple APIC Description Table, if the firmware provides one.
+pub fn madt() -> Option<&'static Madt> {
+    TABLES.get()?.madt.as_ref()
+}
+
+/// Returns the Fixed ACPI Description Table, if the firmware provides one.
+pub fn fadt() -> Option<&'static Fadt> {
+    TABLES.get()?.fadt.as_ref()
+}
+
+/// Returns the High Precision Event Timer table, if the firmware provides one.
+pub fn hpet() -> Option<&'static Hpet> {
+    TABLES.get()?.hpet.as_ref()
+}
+
+/// Returns the PCI Express configuration regions listed in the MCFG table.
+pub fn mcfg() -> &'static [PciConfigRegion] {
+    TABLES.get().map_or(&[], |tables| &tables.mcfg)
+}
+
+/// Returns the processors listed in the MADT, including disabled ones.
+pub fn processors() -> &'static [Processor] {
+    madt().map_or(&[], |madt| &madt.processors)
+}
+
+/// Returns the local APIC ids of the usable processors.
+pub fn apic_ids() -> impl Iterator<Item = u32> {
+    processors().iter().filter(|p| p.enabled).map(|p| p.apic_id)
+}
+
+/// Returns the index of the CMOS register holding the century, if there is one.
+pub fn century_register() -> Option<u8> {
+    fadt()?.century_register
+}
+
+/// Returns the I/O ports of the PM1a and (optional) PM1b control blocks.
+pub fn pm1_control_ports() -> Option<(u16, Option<u16>)> {
+    let fadt = fadt()?;
+    if fadt.pm1a_control_block == 0 {
+        return None;
+    }
+    Some((fadt.pm1a_control_block, fadt.pm1b_control_block))
+}
+
+/// Returns the sleep type values used to enter the S5 (soft off) state.
+pub fn s5_sleep_type() -> Option<SleepType> {
+    TABLES.get()?.s5_sleep_type
+}
+
+/// Returns the base of the memory-mapped PCI Express configuration space of
+/// the given bus.
+pub fn pcie_ecam_base(segment_group: u16, bus: u8) -> Option<PhysAddr> {
+    mcfg()
+        .iter()
+        .filter(|region| region.segment_group == segment_group)
+        .find_map(|region| region.bus_address(bus))
+}
@@ -0,0 +1,112 @@
+use crate::memory::phys_to_virt;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+
+/// The header shared by every ACPI system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Describes the location of a register, as used by the FADT and HPET tables.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct RawGenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Returns `None` for the all-zero address firmware uses for absent registers.
+    pub(super) fn from_raw(raw: RawGenericAddress) -> Option<Self> {
+        if raw.address == 0 {
+            return None;
+        }
+        let address_space = match raw.address_space {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        Some(GenericAddress {
+            address_space,
+            bit_width: raw.bit_width,
+            bit_offset: raw.bit_offset,
+            access_size: raw.access_size,
+            address: raw.address,
+        })
+    }
+}
+
+/// Reads a (possibly unaligned) `T` from physical memory.
+///
+/// This function is unsafe because the caller must guarantee that `addr`
+/// points to a valid `T`.
+pub(super) unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr())
+}
+
+/// Reads a table whose layout grew over ACPI revisions.
+///
+/// Only the bytes covered by the length in the table header are read, the
+/// remaining fields of `T` are zeroed.
+///
+/// This function is unsafe because the caller must guarantee that `addr`
+/// points to a valid table starting with a `SdtHeader`, and that `T` is
+/// valid when zeroed.
+pub(super) unsafe fn read_table<T: Copy>(addr: PhysAddr) -> T {
+    let header: SdtHeader = read(addr);
+    let len = (header.length as usize).min(mem::size_of::<T>());
+
+    let mut table = mem::MaybeUninit::<T>::zeroed();
+    ptr::copy_nonoverlapping(
+        phys_to_virt(addr).as_ptr::<u8>(),
+        table.as_mut_ptr() as *mut u8,
+        len,
+    );
+    table.assume_init()
+}
+
+/// Returns the bytes of the table at `addr`, as long as its header says.
+///
+/// This function is unsafe because the caller must guarantee that `addr`
+/// points to a valid table starting with a `SdtHeader`.
+pub(super) unsafe fn bytes(addr: PhysAddr) -> &'static [u8] {
+    let header: SdtHeader = read(addr);
+    slice::from_raw_parts(phys_to_virt(addr).as_ptr(), header.length as usize)
+}
+
+/// Returns whether all bytes of `bytes` sum up to zero, as required for
+/// every ACPI structure protected by a checksum.
+pub(super) fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
@@ -1,6 +1,11 @@
+use crate::{memory, serial_println};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
-use fixed_size_block::FixedSizeBlockAllocator;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use fixed_size_block::{FixedSizeBlockAllocator, SIZE_CLASSES};
+use slab::CacheStats;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -11,9 +16,24 @@ use x86_64::{
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
+pub mod tracking;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 32 * 1024 * 1024;
+/// Size of the heap mapped by `init_heap`, it then grows on demand.
+pub const HEAP_INITIAL_SIZE: usize = 8 * 1024 * 1024;
+/// Default for `set_heap_limit`.
+pub const HEAP_DEFAULT_LIMIT: usize = 512 * 1024 * 1024;
+/// Largest heap limit, which keeps the heap within a single level 4 entry
+/// so that its growth is visible in every address space.
+pub const HEAP_MAX_LIMIT: usize = 64 * 1024 * 1024 * 1024;
+/// The heap grows by at least this much at a time.
+const HEAP_GROWTH_STEP: usize = 1024 * 1024;
+const MAX_LOW_MEMORY_HANDLERS: usize = 8;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_DEFAULT_LIMIT);
+static LOW_MEMORY_HANDLERS: [AtomicUsize; MAX_LOW_MEMORY_HANDLERS] =
+    [const { AtomicUsize::new(0) }; MAX_LOW_MEMORY_HANDLERS];
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
@@ -24,7 +44,7 @@ pub fn init_heap(
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + HEAP_INITIAL_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -34,17 +54,187 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, HEAP_INITIAL_SIZE);
     }
+    register_low_memory_handler(|_| {
+        slab::reclaim_caches();
+    });
 
     Ok(())
 }
 
+/// Sets how large the heap may grow, in bytes.
+///
+/// The limit is clamped to `HEAP_MAX_LIMIT`, and a heap already larger than
+/// it does not shrink.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_LIMIT), Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Usage counters kept by each allocator.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Bytes requested by the live allocations.
+    pub bytes: usize,
+    /// Highest value `bytes` has reached.
+    pub peak_bytes: usize,
+}
+
+impl AllocatorStats {
+    pub const fn new() -> Self {
+        AllocatorStats {
+            allocations: 0,
+            bytes: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, layout: &Layout) {
+        self.allocations += 1;
+        self.bytes += layout.size();
+        self.peak_bytes = self.peak_bytes.max(self.bytes);
+    }
+
+    fn record_dealloc(&mut self, layout: &Layout) {
+        self.allocations -= 1;
+        self.bytes -= layout.size();
+    }
+}
+
+/// State of the kernel heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes mapped so far.
+    pub size: usize,
+    pub limit: usize,
+    /// Bytes handed out by the fallback allocator, including the slabs of
+    /// the size classes.
+    pub fallback_bytes: usize,
+    pub allocator: AllocatorStats,
+    pub size_classes: [CacheStats; SIZE_CLASSES],
+}
+
+pub fn heap_stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.lock();
+        HeapStats {
+            size: allocator.size(),
+            limit: heap_limit(),
+            fallback_bytes: allocator.fallback_bytes(),
+            allocator: allocator.stats(),
+            size_classes: allocator.size_classes(),
+        }
+    })
+}
+
+/// Prints the heap statistics over serial.
+pub fn print_heap_stats() {
+    let stats = heap_stats();
+    serial_println!(
+        "heap: {} KiB mapped of {} KiB, {} KiB from the fallback allocator",
+        stats.size / 1024,
+        stats.limit / 1024,
+        stats.fallback_bytes / 1024
+    );
+    serial_println!(
+        "heap: {} live allocations, {} bytes, peak {} bytes",
+        stats.allocator.allocations,
+        stats.allocator.bytes,
+        stats.allocator.peak_bytes
+    );
+    for cache in stats.size_classes.into_iter().chain(slab::caches()) {
+        serial_println!(
+            "{}: {} allocated, {} free, peak {}, {} slabs of {} KiB, {} freed",
+            cache.name,
+            cache.allocated,
+            cache.free,
+            cache.peak_allocated,
+            cache.slabs,
+            cache.slab_size / 1024,
+            cache.freed_slabs
+        );
+    }
+}
+
+/// Called with the size of an allocation that failed even though the heap
+/// grew as far as it could. Handlers should free what they can, e.g. drop
+/// caches; the allocation is retried once they have all run.
+///
+/// Handlers may run in interrupt context and must not block.
+pub type LowMemoryHandler = fn(usize);
+
+/// Registers a handler to call when the heap is out of memory.
+///
+/// Returns `false` if too many handlers are registered already.
+pub fn register_low_memory_handler(handler: LowMemoryHandler) -> bool {
+    LOW_MEMORY_HANDLERS.iter().any(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Runs the low memory handlers, unless they are already running, in which
+/// case the allocation comes from one of them and must simply fail.
+///
+/// Returns whether any handler ran.
+fn run_low_memory_handlers(size: usize) -> bool {
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    if RUNNING.swap(1, Ordering::Acquire) != 0 {
+        return false;
+    }
+    let mut ran = false;
+    for slot in LOW_MEMORY_HANDLERS.iter() {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: LowMemoryHandler = unsafe { core::mem::transmute(handler) };
+            handler(size);
+            ran = true;
+        }
+    }
+    RUNNING.store(0, Ordering::Release);
+    ran
+}
+
+/// Maps at least `size` more bytes at `heap_top`, within the heap limit.
+///
+/// Returns the number of bytes mapped, zero if the heap cannot grow.
+fn grow_heap(heap_top: usize, size: usize) -> usize {
+    let heap_end = HEAP_START + heap_limit();
+    let available = heap_end.saturating_sub(heap_top);
+    let size = align_up(size, Page::<Size4KiB>::SIZE as usize);
+    if size > available {
+        return 0;
+    }
+
+    // Grow by a whole step if possible, then by just what is needed
+    let step = HEAP_GROWTH_STEP.max(size).min(available);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for size in [step, size] {
+        let start = Page::containing_address(VirtAddr::new(heap_top as u64));
+        let pages = Page::range(
+            start,
+            start + (size / Page::<Size4KiB>::SIZE as usize) as u64,
+        );
+        if memory::map_kernel_pages(pages, flags).is_ok() {
+            return size;
+        }
+    }
+    0
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -69,14 +259,18 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
 
 /// Align the given address `addr` upwards to alignment `align`.
 ///
 /// Requires that `align` is a power of two.
-fn align_up(addr: usize, align: usize) -> usize {
+const fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
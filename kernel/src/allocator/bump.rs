@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, AllocatorStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,6 +7,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    stats: AllocatorStats,
 }
 
 impl BumpAllocator {
@@ -17,6 +18,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -29,6 +31,15 @@ impl BumpAllocator {
         self.heap_end = heap_start.saturating_add(heap_size);
         self.next = heap_start;
     }
+
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    /// Returns the number of bytes left before the end of the heap.
+    pub fn remaining(&self) -> usize {
+        self.heap_end - self.next
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -46,14 +57,16 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.stats.record_alloc(&layout);
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
         bump.allocations -= 1;
+        bump.stats.record_dealloc(&layout);
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
@@ -1,40 +1,92 @@
-use super::Locked;
-use alloc::alloc::{GlobalAlloc, Layout};
-use core::{
-    mem,
-    ptr::{self, NonNull},
+use super::{
+    grow_heap, run_low_memory_handlers,
+    slab::{CacheStats, SlabCache, SlabSource},
+    tracking, AllocatorStats, Locked,
 };
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use x86_64::instructions::interrupts;
+
+/// Number of size classes.
+pub const SIZE_CLASSES: usize = 13;
 
-/// The block sizes to use.
-///
-/// The sizes must each be power of 2 because they are also used as
-/// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
-
-/// Choose an appropriate block size for the given layout.
-///
-/// Returns an index into the `BLOCK_SIZES` array.
-fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+/// Creates the cache of a size class. Its objects are aligned to the largest
+/// power of two dividing `size`.
+const fn size_class(name: &'static str, size: usize) -> SlabCache {
+    SlabCache::new(name, size, size & size.wrapping_neg(), None)
 }
 
-struct ListNode {
-    next: Option<&'static mut ListNode>,
+/// Takes the slabs and the large allocations from the heap, growing it when
+/// it is full.
+struct Fallback {
+    heap: linked_list_allocator::Heap,
 }
 
+impl Fallback {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // The hole at the top may not be large or aligned enough, ask for
+        // room for the worst case
+        let grown = grow_heap(self.heap.top(), layout.size() + layout.align());
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.heap.extend(grown) };
+        match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.heap.deallocate(NonNull::new(ptr).unwrap(), layout);
+    }
+}
+
+impl SlabSource for Fallback {
+    fn alloc_slab(&mut self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn free_slab(&mut self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+}
+
+/// Serves small allocations from slab caches of fixed sizes, and larger
+/// ones from a linked list heap that also provides the slabs.
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
+    caches: [SlabCache; SIZE_CLASSES],
+    fallback: Fallback,
+    stats: AllocatorStats,
 }
 
 impl FixedSizeBlockAllocator {
     /// Creates an empty FixedSizeBlockAllocator.
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            caches: [
+                size_class("heap-8", 8),
+                size_class("heap-16", 16),
+                size_class("heap-32", 32),
+                size_class("heap-48", 48),
+                size_class("heap-64", 64),
+                size_class("heap-96", 96),
+                size_class("heap-128", 128),
+                size_class("heap-192", 192),
+                size_class("heap-256", 256),
+                size_class("heap-384", 384),
+                size_class("heap-512", 512),
+                size_class("heap-1024", 1024),
+                size_class("heap-2048", 2048),
+            ],
+            fallback: Fallback {
+                heap: linked_list_allocator::Heap::empty(),
+            },
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -44,60 +96,100 @@ impl FixedSizeBlockAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.fallback_allocator.init(heap_start, heap_size);
+        self.fallback.heap.init(heap_start, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
-    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+    /// Returns the size of the heap, in bytes.
+    pub fn size(&self) -> usize {
+        self.fallback.heap.size()
+    }
+
+    /// Returns the bytes handed out by the fallback allocator, including the
+    /// slabs of the size classes.
+    pub fn fallback_bytes(&self) -> usize {
+        self.fallback.heap.used()
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    pub fn size_classes(&self) -> [CacheStats; SIZE_CLASSES] {
+        let mut classes = [CacheStats::default(); SIZE_CLASSES];
+        for (class, cache) in classes.iter_mut().zip(self.caches.iter()) {
+            *class = cache.stats();
+        }
+        classes
+    }
+
+    /// Gives the empty slabs of the size classes back to the fallback heap,
+    /// and returns the bytes freed.
+    pub fn reclaim(&mut self) -> usize {
+        let Self {
+            caches, fallback, ..
+        } = self;
+        caches.iter_mut().map(|cache| cache.reclaim(fallback)).sum()
+    }
+
+    /// Chooses the size class for the given layout.
+    fn class_index(&self, layout: &Layout) -> Option<usize> {
+        self.caches.iter().position(|cache| {
+            cache.object_size() >= layout.size() && cache.align() >= layout.align()
+        })
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.class_index(&layout) {
+            Some(index) => self.caches[index].alloc(&mut self.fallback),
+            None => self.fallback.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match self.class_index(&layout) {
+            Some(index) => self.caches[index].dealloc(ptr, &mut self.fallback),
+            None => self.fallback.dealloc(ptr, layout),
         }
     }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
-            None => allocator.fallback_alloc(layout),
+        // Interrupt handlers may allocate, so the lock must not be held
+        // when one of them runs on the same CPU
+        let ptr = interrupts::without_interrupts(|| self.alloc_locked(layout));
+        // The handlers free memory, so they run without the lock
+        if ptr.is_null() && run_low_memory_handlers(layout.size()) {
+            return interrupts::without_interrupts(|| self.alloc_locked(layout));
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.dealloc_locked(ptr, layout))
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
-            }
+        let mut ptr = allocator.alloc(layout);
+        // The heap cannot grow anymore, but the other size classes may
+        // hold on to empty slabs
+        if ptr.is_null() && allocator.reclaim() > 0 {
+            ptr = allocator.alloc(layout);
+        }
+        if !ptr.is_null() {
+            allocator.stats.record_alloc(&layout);
+            tracking::record_alloc(ptr, layout.size());
         }
+        ptr
+    }
+
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.stats.record_dealloc(&layout);
+        tracking::record_dealloc(ptr);
+        allocator.dealloc(ptr, layout);
     }
 }
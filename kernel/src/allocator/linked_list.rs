@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, AllocatorStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -23,6 +23,7 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    stats: AllocatorStats,
 }
 
 impl LinkedListAllocator {
@@ -30,9 +31,27 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            stats: AllocatorStats::new(),
         }
     }
 
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    /// Returns the number of free regions and their total size in bytes.
+    pub fn free_regions(&self) -> (usize, usize) {
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut current = &self.head.next;
+        while let Some(region) = current {
+            count += 1;
+            bytes += region.size;
+            current = &region.next;
+        }
+        (count, bytes)
+    }
+
     /// Initialize the allocator with the given heap bounds.
     ///
     /// This function is unsafe because the caller must guarantee that the given
@@ -130,6 +149,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.stats.record_alloc(&layout);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -140,6 +160,8 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.stats.record_dealloc(&layout);
+        allocator.add_free_region(ptr as usize, size)
     }
 }
@@ -0,0 +1,596 @@
+//! A slab allocator for objects of a fixed size.
+//!
+//! Each cache carves its objects out of slabs, blocks of a few pages aligned
+//! to their own size so that the slab of an object is found by rounding its
+//! address down. Slabs whose objects are all free are given back to where
+//! they came from, so memory does not stay tied to one object size after a
+//! burst of allocations.
+
+use super::{align_up, Locked};
+use alloc::{
+    alloc::{self as heap, AllocError, Allocator, Layout},
+    sync::Arc,
+};
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// Slabs are at least a page large.
+const MIN_SLAB_SIZE: usize = 4096;
+/// Slabs grow until this many objects fit in them.
+const MIN_OBJECTS: usize = 4;
+/// Empty slabs kept for reuse by each cache, further ones are freed.
+const MAX_EMPTY_SLABS: usize = 1;
+const MAX_CACHES: usize = 32;
+
+const LINK_SIZE: usize = mem::size_of::<*mut u8>();
+
+static CACHES: [Registration; MAX_CACHES] = [const { Registration::new() }; MAX_CACHES];
+
+/// Initializes an object when its slab is created.
+pub type Constructor = fn(*mut u8);
+
+/// Provides the memory of the slabs.
+pub trait SlabSource {
+    /// Returns a block for `layout`, or null if there is no memory left.
+    fn alloc_slab(&mut self, layout: Layout) -> *mut u8;
+
+    /// Frees a block returned by `alloc_slab`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was allocated with the same layout and is no longer in use.
+    unsafe fn free_slab(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// Takes the slabs from the kernel heap.
+pub struct HeapSource;
+
+impl SlabSource for HeapSource {
+    fn alloc_slab(&mut self, layout: Layout) -> *mut u8 {
+        unsafe { heap::alloc(layout) }
+    }
+
+    unsafe fn free_slab(&mut self, ptr: *mut u8, layout: Layout) {
+        heap::dealloc(ptr, layout)
+    }
+}
+
+/// Counters of a single cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// Objects in use.
+    pub allocated: usize,
+    /// Objects that can be handed out without creating a slab.
+    pub free: usize,
+    pub slabs: usize,
+    pub slab_size: usize,
+    /// Highest value `allocated` has reached.
+    pub peak_allocated: usize,
+    /// Slabs given back to their source.
+    pub freed_slabs: usize,
+}
+
+/// Header at the start of every slab.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    /// First free object, the others are linked from it.
+    free: *mut u8,
+    in_use: usize,
+}
+
+/// A doubly linked list of slabs, so that any slab can be moved to another
+/// list when one of its objects is freed.
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        let Slab { next, prev, .. } = *slab;
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+/// A cache of objects of one size and alignment.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    /// Distance between two objects of a slab.
+    stride: usize,
+    /// Where a free object stores the link to the next one.
+    link_offset: usize,
+    /// Offset of the first object, after the header.
+    first_offset: usize,
+    slab_size: usize,
+    objects_per_slab: usize,
+    constructor: Option<Constructor>,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    allocated: usize,
+    peak_allocated: usize,
+    freed_slabs: usize,
+}
+
+// The slabs are only reached through the cache, which owns them.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Creates an empty cache of objects of `size` bytes aligned to `align`,
+    /// which must be a power of two.
+    ///
+    /// With a constructor, objects are initialized once when their slab is
+    /// created and a freed object is handed out again as it was left, so
+    /// users must return objects to their initial state before freeing them.
+    pub const fn new(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        constructor: Option<Constructor>,
+    ) -> Self {
+        let align = if align < LINK_SIZE { LINK_SIZE } else { align };
+        // Free objects hold the link in their first word, unless they have to
+        // keep their contents, in which case the link goes after them
+        let after_object = align_up(size, LINK_SIZE);
+        let (link_offset, used) = match constructor {
+            Some(_) => (after_object, after_object + LINK_SIZE),
+            None if size < LINK_SIZE => (0, LINK_SIZE),
+            None => (0, size),
+        };
+        let stride = align_up(used, align);
+        let first_offset = align_up(mem::size_of::<Slab>(), align);
+        let mut slab_size = MIN_SLAB_SIZE;
+        while slab_size < first_offset + MIN_OBJECTS * stride {
+            slab_size *= 2;
+        }
+
+        SlabCache {
+            name,
+            object_size: size,
+            align,
+            stride,
+            link_offset,
+            first_offset,
+            slab_size,
+            objects_per_slab: (slab_size - first_offset) / stride,
+            constructor,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            allocated: 0,
+            peak_allocated: 0,
+            freed_slabs: 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slabs = self.partial.len + self.full.len + self.empty.len;
+        CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            allocated: self.allocated,
+            free: slabs * self.objects_per_slab - self.allocated,
+            slabs,
+            slab_size: self.slab_size,
+            peak_allocated: self.peak_allocated,
+            freed_slabs: self.freed_slabs,
+        }
+    }
+
+    /// Returns a free object, creating a slab from `source` if needed.
+    ///
+    /// Returns null if no slab can be created.
+    pub fn alloc(&mut self, source: &mut impl SlabSource) -> *mut u8 {
+        unsafe {
+            let slab = if !self.partial.head.is_null() {
+                self.partial.head
+            } else {
+                let slab = match self.empty.pop() {
+                    Some(slab) => slab,
+                    None => self.create_slab(source),
+                };
+                if slab.is_null() {
+                    return ptr::null_mut();
+                }
+                self.partial.push(slab);
+                slab
+            };
+
+            let object = (*slab).free;
+            (*slab).free = *self.link(object);
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.objects_per_slab {
+                self.partial.remove(slab);
+                self.full.push(slab);
+            }
+            self.allocated += 1;
+            self.peak_allocated = self.peak_allocated.max(self.allocated);
+            object
+        }
+    }
+
+    /// Returns an object to the cache. If that empties its slab, the slab is
+    /// kept for reuse or given back to `source`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was returned by `alloc` of this cache and is no longer in use.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, source: &mut impl SlabSource) {
+        let slab = (ptr as usize & !(self.slab_size - 1)) as *mut Slab;
+        if (*slab).in_use == self.objects_per_slab {
+            self.full.remove(slab);
+        } else {
+            self.partial.remove(slab);
+        }
+
+        *self.link(ptr) = (*slab).free;
+        (*slab).free = ptr;
+        (*slab).in_use -= 1;
+        self.allocated -= 1;
+
+        if (*slab).in_use > 0 {
+            self.partial.push(slab);
+        } else if self.empty.len < MAX_EMPTY_SLABS {
+            self.empty.push(slab);
+        } else {
+            self.free_slab(slab, source);
+        }
+    }
+
+    /// Gives the empty slabs back to `source` and returns the bytes freed.
+    pub fn reclaim(&mut self, source: &mut impl SlabSource) -> usize {
+        let mut freed = 0;
+        while let Some(slab) = unsafe { self.empty.pop() } {
+            unsafe { self.free_slab(slab, source) };
+            freed += self.slab_size;
+        }
+        freed
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    unsafe fn link(&self, object: *mut u8) -> *mut *mut u8 {
+        object.add(self.link_offset).cast()
+    }
+
+    unsafe fn create_slab(&mut self, source: &mut impl SlabSource) -> *mut Slab {
+        let slab = source.alloc_slab(self.slab_layout()).cast::<Slab>();
+        if slab.is_null() {
+            return slab;
+        }
+
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object = slab.cast::<u8>().add(self.first_offset + i * self.stride);
+            if let Some(constructor) = self.constructor {
+                constructor(object);
+            }
+            *self.link(object) = free;
+            free = object;
+        }
+        slab.write(Slab {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        slab
+    }
+
+    unsafe fn free_slab(&mut self, slab: *mut Slab, source: &mut impl SlabSource) {
+        source.free_slab(slab.cast(), self.slab_layout());
+        self.freed_slabs += 1;
+    }
+}
+
+/// A named cache of objects of type `T`, for kernel objects that are
+/// allocated and freed often.
+///
+/// The slabs come from the kernel heap. The cache is registered on first use
+/// so that it shows up in `caches` and is shrunk when the heap runs out.
+pub struct ObjectCache<T> {
+    cache: Locked<SlabCache>,
+    constructed: bool,
+    registered: AtomicBool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self::with_constructor(name, None)
+    }
+
+    const fn with_constructor(name: &'static str, constructor: Option<Constructor>) -> Self {
+        ObjectCache {
+            cache: Locked::new(SlabCache::new(
+                name,
+                mem::size_of::<T>(),
+                mem::align_of::<T>(),
+                constructor,
+            )),
+            constructed: constructor.is_some(),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into an object of the cache.
+    pub fn alloc(&'static self, value: T) -> Option<CacheBox<T>> {
+        let object = self.take()?;
+        unsafe {
+            if self.constructed {
+                // Drops the object left by the previous user
+                *object.as_ptr() = value;
+            } else {
+                object.as_ptr().write(value);
+            }
+        }
+        Some(CacheBox {
+            object,
+            cache: self,
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        interrupts::without_interrupts(|| self.cache.lock().stats())
+    }
+
+    /// Gives the empty slabs back to the heap and returns the bytes freed.
+    pub fn reclaim(&self) -> usize {
+        interrupts::without_interrupts(|| self.cache.lock().reclaim(&mut HeapSource))
+    }
+
+    fn take(&'static self) -> Option<NonNull<T>> {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            add(&self.cache, true);
+        }
+        // Interrupt handlers may use the cache too
+        let object = interrupts::without_interrupts(|| self.cache.lock().alloc(&mut HeapSource));
+        NonNull::new(object.cast())
+    }
+
+    /// Returns an object taken with `take`, without dropping it.
+    unsafe fn give(&self, object: NonNull<T>) {
+        interrupts::without_interrupts(|| {
+            self.cache
+                .lock()
+                .dealloc(object.as_ptr().cast(), &mut HeapSource)
+        });
+    }
+}
+
+impl<T: Default> ObjectCache<T> {
+    /// Creates a cache whose objects are built with `T::default` when their
+    /// slab is created, and are not dropped when they are freed.
+    ///
+    /// Objects keep their state from one user to the next, which saves
+    /// setting up expensive objects again, so `T` must not own other
+    /// allocations.
+    pub const fn constructed(name: &'static str) -> Self {
+        Self::with_constructor(name, Some(construct::<T>))
+    }
+
+    /// Returns an object of a constructed cache, as the previous user left it.
+    pub fn get(&'static self) -> Option<CacheBox<T>> {
+        assert!(self.constructed, "cache has no constructor");
+        let object = self.take()?;
+        Some(CacheBox {
+            object,
+            cache: self,
+        })
+    }
+}
+
+fn construct<T: Default>(object: *mut u8) {
+    unsafe { object.cast::<T>().write(T::default()) };
+}
+
+/// An object allocated from an `ObjectCache`, returned to it when dropped.
+pub struct CacheBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for CacheBox<T> {}
+unsafe impl<T: Sync> Sync for CacheBox<T> {}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.cache.constructed {
+                ptr::drop_in_place(self.object.as_ptr());
+            }
+            self.cache.give(self.object);
+        }
+    }
+}
+
+/// A reference counted object allocated from an `ArcCache`.
+pub type CacheArc<T> = Arc<T, &'static ArcCache<T>>;
+
+/// The allocation of an `Arc`, laid out as the one of `alloc::sync`.
+#[repr(C)]
+struct ArcInner<T> {
+    strong: AtomicUsize,
+    weak: AtomicUsize,
+    data: T,
+}
+
+/// A named cache of reference counted objects of type `T`, whose `Arc`s are
+/// allocated with `Arc::new_in(value, &CACHE)`.
+///
+/// Like an `ObjectCache`, it takes its slabs from the kernel heap and is
+/// listed in `caches` once used.
+pub struct ArcCache<T> {
+    objects: ObjectCache<ArcInner<T>>,
+}
+
+impl<T> ArcCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        ArcCache {
+            objects: ObjectCache::new(name),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.objects.stats()
+    }
+}
+
+unsafe impl<T> Allocator for &'static ArcCache<T> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // Only the allocation of an `Arc<T>` fits in the objects
+        if layout != Layout::new::<ArcInner<T>>() {
+            return Err(AllocError);
+        }
+        let object = self.objects.take().ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(object.cast(), layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        self.objects.give(ptr.cast());
+    }
+}
+
+/// A slot of `CACHES`.
+struct Registration {
+    cache: AtomicPtr<Locked<SlabCache>>,
+    /// Whether the slabs come from the heap, so that `reclaim_caches` may
+    /// give them back to it.
+    from_heap: AtomicBool,
+}
+
+impl Registration {
+    const fn new() -> Self {
+        Registration {
+            cache: AtomicPtr::new(ptr::null_mut()),
+            from_heap: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Lists a cache whose slabs do not come from the heap in `caches`.
+///
+/// `reclaim_caches` leaves such a cache alone, its owner gives the empty
+/// slabs back to their source.
+pub fn register(cache: &'static Locked<SlabCache>) {
+    add(cache, false);
+}
+
+fn add(cache: &'static Locked<SlabCache>, from_heap: bool) {
+    let ptr = cache as *const _ as *mut _;
+    let slot = CACHES.iter().find(|slot| {
+        slot.cache
+            .compare_exchange(ptr::null_mut(), ptr, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+    match slot {
+        Some(slot) => slot.from_heap.store(from_heap, Ordering::Release),
+        None => {
+            let name = interrupts::without_interrupts(|| cache.lock().name());
+            log::warn!("too many object caches, not listing {}", name);
+        }
+    }
+}
+
+/// Returns the statistics of every registered cache.
+pub fn caches() -> impl Iterator<Item = CacheStats> {
+    CACHES.iter().filter_map(|slot| {
+        let cache = unsafe { slot.cache.load(Ordering::Acquire).as_ref()? };
+        Some(interrupts::without_interrupts(|| cache.lock().stats()))
+    })
+}
+
+/// Gives the empty slabs of every registered cache with slabs from the heap
+/// back to it, and returns the bytes freed.
+///
+/// Caches that are locked are skipped, as the heap may be out of memory
+/// while one of them creates a slab.
+pub fn reclaim_caches() -> usize {
+    CACHES
+        .iter()
+        .filter(|slot| slot.from_heap.load(Ordering::Acquire))
+        .filter_map(|slot| unsafe { slot.cache.load(Ordering::Acquire).as_ref() })
+        .map(|cache| {
+            interrupts::without_interrupts(|| match cache.try_lock() {
+                Some(mut cache) => cache.reclaim(&mut HeapSource),
+                None => 0,
+            })
+        })
+        .sum()
+}
@@ -0,0 +1,160 @@
+//! Records where the live heap allocations were made, to find leaks.
+//!
+//! Tracking is only compiled in with the `heap-debug` feature. Call sites
+//! are found by following the saved frame pointers, so the kernel must
+//! also be built with `-C force-frame-pointers=yes` for them to be
+//! meaningful. The return addresses can be resolved with `addr2line`.
+
+use crate::serial_println;
+
+/// Return addresses kept for each allocation, innermost first.
+pub const CALL_STACK_DEPTH: usize = 8;
+
+#[cfg(feature = "heap-debug")]
+mod records {
+    use super::CALL_STACK_DEPTH;
+    use core::arch::asm;
+    use spin::Mutex;
+
+    /// Allocations beyond this many are counted but not recorded.
+    const MAX_RECORDS: usize = 4096;
+    /// Frames larger than this end the walk, as a corrupt frame pointer is
+    /// more likely than such a large frame.
+    const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+    #[derive(Clone, Copy)]
+    pub struct Record {
+        pub addr: usize,
+        pub size: usize,
+        pub call_stack: [usize; CALL_STACK_DEPTH],
+    }
+
+    #[derive(Clone, Copy)]
+    enum Slot {
+        Empty,
+        Removed,
+        Used(Record),
+    }
+
+    /// An open addressing hash table, since the heap cannot be used here.
+    pub struct Records {
+        slots: [Slot; MAX_RECORDS],
+        pub dropped: usize,
+    }
+
+    pub static RECORDS: Mutex<Records> = Mutex::new(Records {
+        slots: [Slot::Empty; MAX_RECORDS],
+        dropped: 0,
+    });
+
+    impl Records {
+        pub fn insert(&mut self, record: Record) {
+            for i in probe(record.addr) {
+                if let Slot::Empty | Slot::Removed = self.slots[i] {
+                    self.slots[i] = Slot::Used(record);
+                    return;
+                }
+            }
+            self.dropped += 1;
+        }
+
+        pub fn remove(&mut self, addr: usize) {
+            for i in probe(addr) {
+                match self.slots[i] {
+                    Slot::Used(record) if record.addr == addr => {
+                        self.slots[i] = Slot::Removed;
+                        return;
+                    }
+                    Slot::Empty => return,
+                    _ => {}
+                }
+            }
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &Record> {
+            self.slots.iter().filter_map(|slot| match slot {
+                Slot::Used(record) => Some(record),
+                _ => None,
+            })
+        }
+    }
+
+    fn probe(addr: usize) -> impl Iterator<Item = usize> {
+        let start = (addr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % MAX_RECORDS;
+        (0..MAX_RECORDS).map(move |i| (start + i) % MAX_RECORDS)
+    }
+
+    pub fn call_stack() -> [usize; CALL_STACK_DEPTH] {
+        let mut call_stack = [0; CALL_STACK_DEPTH];
+        let mut rbp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+        // The outermost frame of every thread has a null frame pointer
+        for entry in call_stack.iter_mut() {
+            if rbp == 0 || rbp & 7 != 0 {
+                break;
+            }
+            let frame = rbp as *const usize;
+            let (next, return_addr) = unsafe { (frame.read(), frame.add(1).read()) };
+            *entry = return_addr;
+            if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+                break;
+            }
+            rbp = next;
+        }
+        call_stack
+    }
+}
+
+/// Records an allocation, called with the heap lock held.
+#[cfg(feature = "heap-debug")]
+pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+    let record = records::Record {
+        addr: ptr as usize,
+        size,
+        call_stack: records::call_stack(),
+    };
+    records::RECORDS.lock().insert(record);
+}
+
+#[cfg(not(feature = "heap-debug"))]
+#[inline(always)]
+pub(super) fn record_alloc(_ptr: *mut u8, _size: usize) {}
+
+/// Forgets an allocation, called with the heap lock held.
+#[cfg(feature = "heap-debug")]
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    records::RECORDS.lock().remove(ptr as usize);
+}
+
+#[cfg(not(feature = "heap-debug"))]
+#[inline(always)]
+pub(super) fn record_dealloc(_ptr: *mut u8) {}
+
+/// Prints every live allocation with its call stack over serial.
+pub fn dump_live_allocations() {
+    #[cfg(feature = "heap-debug")]
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let records = records::RECORDS.lock();
+        let mut count = 0;
+        let mut bytes = 0;
+        for record in records.iter() {
+            count += 1;
+            bytes += record.size;
+            crate::serial_print!("{:#x} {} bytes:", record.addr, record.size);
+            for &addr in record.call_stack.iter().take_while(|&&addr| addr != 0) {
+                crate::serial_print!(" {:#x}", addr);
+            }
+            serial_println!();
+        }
+        serial_println!(
+            "{} live allocations, {} bytes, {} not recorded",
+            count,
+            bytes,
+            records.dropped
+        );
+    });
+
+    #[cfg(not(feature = "heap-debug"))]
+    serial_println!("Allocation tracking is disabled, enable the heap-debug feature");
+}
@@ -0,0 +1,106 @@
+use crate::acpi::madt::{Polarity, TriggerMode};
+use core::ptr;
+use x86_64::VirtAddr;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REGISTER_VERSION: u32 = 0x01;
+const REGISTER_REDIRECTION_TABLE: u32 = 0x10;
+
+const POLARITY_ACTIVE_LOW: u32 = 1 << 13;
+const TRIGGER_MODE_LEVEL: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+
+/// A redirection table entry, routing one IO-APIC pin to a vector on a CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub destination: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    fn low(&self) -> u32 {
+        // Fixed delivery mode and physical destination mode are both 0
+        let mut low = self.vector as u32;
+        if self.polarity == Polarity::ActiveLow {
+            low |= POLARITY_ACTIVE_LOW;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            low |= TRIGGER_MODE_LEVEL;
+        }
+        if self.masked {
+            low |= MASKED;
+        }
+        low
+    }
+
+    fn high(&self) -> u32 {
+        (self.destination as u32) << 24
+    }
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    /// Creates a handle to the IO-APIC registers mapped at `base`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `base`
+    /// is an uncacheable mapping of the IO-APIC registers.
+    pub unsafe fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        IoApic { base, gsi_base }
+    }
+
+    /// Returns the number of pins (redirection entries) of this IO-APIC.
+    pub fn pin_count(&mut self) -> u32 {
+        let version = unsafe { self.read(REGISTER_VERSION) };
+        ((version >> 16) & 0xFF) + 1
+    }
+
+    /// Returns whether the given global system interrupt is wired to this IO-APIC.
+    pub fn handles(&mut self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.pin_count()
+    }
+
+    pub fn set_entry(&mut self, gsi: u32, entry: RedirectionEntry) {
+        let register = REGISTER_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        unsafe {
+            // Mask the pin while the entry is half written
+            self.write(register, MASKED);
+            self.write(register + 1, entry.high());
+            self.write(register, entry.low());
+        }
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let register = REGISTER_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        unsafe {
+            let low = self.read(register);
+            self.write(register, if masked { low | MASKED } else { low & !MASKED });
+        }
+    }
+
+    pub fn mask_all(&mut self) {
+        for pin in 0..self.pin_count() {
+            self.set_masked(self.gsi_base + pin, true);
+        }
+    }
+
+    unsafe fn read(&mut self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+        ptr::read_volatile((self.base + IOWIN).as_ptr())
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+        ptr::write_volatile((self.base + IOWIN).as_mut_ptr(), value);
+    }
+}
@@ -0,0 +1,167 @@
+use core::ptr;
+use x86_64::{registers::model_specific::Msr, VirtAddr};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+const ICR_DELIVERY_MODE_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_MODE_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    Id = 0x020,
+    Version = 0x030,
+    TaskPriority = 0x080,
+    EndOfInterrupt = 0x0B0,
+    SpuriousInterruptVector = 0x0F0,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfiguration = 0x3E0,
+}
+
+/// The local APIC of the executing CPU.
+///
+/// Every CPU sees its own local APIC at the same physical address, so a
+/// single mapping is shared by all of them.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// Creates a handle to the local APIC registers mapped at `base`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `base`
+    /// is an uncacheable mapping of the local APIC registers.
+    pub const unsafe fn new(base: VirtAddr) -> Self {
+        LocalApic { base }
+    }
+
+    /// Enables the local APIC of the executing CPU and routes spurious
+    /// interrupts to `spurious_vector`.
+    pub fn enable(&self, spurious_vector: u8) {
+        unsafe {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_GLOBAL_ENABLE);
+
+            // Accept interrupts of every priority class
+            self.write(Register::TaskPriority, 0);
+            self.write(Register::SpuriousInterruptVector, 0x100 | spurious_vector as u32);
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(Register::Id) >> 24 }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(Register::EndOfInterrupt, 0) };
+    }
+
+    /// Sends an INIT inter-processor interrupt, resetting the target CPU.
+    pub fn send_init(&self, apic_id: u32) {
+        self.send_ipi_raw(apic_id, ICR_DELIVERY_MODE_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends a startup inter-processor interrupt, making the target CPU
+    /// execute real mode code at physical address `page << 12`.
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.send_ipi_raw(apic_id, ICR_DELIVERY_MODE_STARTUP | page as u32);
+    }
+
+    /// Raises the interrupt `vector` on the CPU with the given APIC id.
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.send_ipi_raw(
+            apic_id,
+            ICR_DELIVERY_MODE_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+        );
+    }
+
+    fn send_ipi_raw(&self, apic_id: u32, command: u32) {
+        unsafe {
+            self.write(Register::InterruptCommandHigh, apic_id << 24);
+            // Writing the low half sends the interrupt
+            self.write(Register::InterruptCommandLow, command);
+            while self.read(Register::InterruptCommandLow) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Starts the timer counting down from `initial_count` without raising
+    /// any interrupt, so that its rate can be measured.
+    pub fn start_timer_one_shot(&self, initial_count: u32) {
+        unsafe {
+            self.write(Register::TimerDivideConfiguration, TIMER_DIVIDE_BY_16);
+            self.write(Register::LvtTimer, LVT_MASKED);
+            self.write(Register::TimerInitialCount, initial_count);
+        }
+    }
+
+    /// Starts the timer in one-shot mode, raising `vector` once after
+    /// `initial_count` timer ticks.
+    pub fn start_timer_one_shot_interrupt(&self, vector: u8, initial_count: u32) {
+        unsafe {
+            self.write(Register::TimerDivideConfiguration, TIMER_DIVIDE_BY_16);
+            self.write(Register::LvtTimer, vector as u32);
+            self.write(Register::TimerInitialCount, initial_count);
+        }
+    }
+
+    /// Starts the timer in periodic mode, raising `vector` every
+    /// `initial_count` timer ticks.
+    pub fn start_timer_periodic(&self, vector: u8, initial_count: u32) {
+        unsafe {
+            self.write(Register::TimerDivideConfiguration, TIMER_DIVIDE_BY_16);
+            self.write(Register::LvtTimer, TIMER_MODE_PERIODIC | vector as u32);
+            self.write(Register::TimerInitialCount, initial_count);
+        }
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(Register::LvtTimer, LVT_MASKED);
+            self.write(Register::TimerInitialCount, 0);
+        }
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read(Register::TimerCurrentCount) }
+    }
+
+    /// Reads a local APIC register.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because some registers have side effects when read.
+    pub unsafe fn read(&self, register: Register) -> u32 {
+        ptr::read_volatile((self.base + register as usize).as_ptr())
+    }
+
+    /// Writes a local APIC register.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because writes can reconfigure interrupt
+    /// delivery or send interrupts to other CPUs.
+    pub unsafe fn write(&self, register: Register, value: u32) {
+        ptr::write_volatile((self.base + register as usize).as_mut_ptr(), value);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{
+    acpi::{
+        self,
+        madt::{Polarity, TriggerMode},
+    },
+    interrupts::{InterruptIndex, IRQ_OFFSET},
+    memory, pic,
+};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use io::{IoApic, RedirectionEntry};
+use local::LocalApic;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod io;
+pub mod local;
+
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xFF;
+/// Vector of the inter-processor interrupt used to wake a halted CPU.
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+/// Vector of the inter-processor interrupt asking a CPU to flush its TLB.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+/// Vector of the local APIC timer, which serves the deadlines of `timer`
+/// and drives the scheduler.
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+const ISA_IRQ_COUNT: u8 = 16;
+/// The ISA IRQ chaining the two PICs, which no device raises.
+const CASCADE_IRQ: u8 = 2;
+const LOCAL_APIC_SIZE: usize = 0x400;
+const IO_APIC_SIZE: usize = 0x20;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IO_APICS: OnceCell<Vec<Mutex<IoApic>>> = OnceCell::uninit();
+
+/// Masks the legacy PICs and routes the ISA interrupts through the IO-APICs
+/// to the local APIC of the executing CPU.
+///
+/// Every ISA IRQ stays masked until a handler is installed with
+/// `interrupts::set_irq_handler`, except for the PS/2 keyboard and mouse
+/// whose handlers are part of the IDT.
+pub fn init() {
+    let madt = acpi::madt().expect("MADT not found, cannot set up the APIC");
+
+    if madt.has_legacy_pics {
+        pic::disable();
+    }
+
+    let local_apic_base = memory::map_mmio(madt.local_apic_address, LOCAL_APIC_SIZE)
+        .expect("failed to map the local APIC");
+    let local_apic = unsafe { LocalApic::new(local_apic_base) };
+    local_apic.enable(SPURIOUS_INTERRUPT_VECTOR);
+    let destination = local_apic.id() as u8;
+    LOCAL_APIC.init_once(|| local_apic);
+
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|info| {
+            let base = memory::map_mmio(info.address, IO_APIC_SIZE)
+                .expect("failed to map an IO-APIC");
+            let mut io_apic = unsafe { IoApic::new(base, info.gsi_base) };
+            io_apic.mask_all();
+            Mutex::new(io_apic)
+        })
+        .collect();
+    IO_APICS.init_once(|| io_apics);
+
+    for irq in (0..ISA_IRQ_COUNT).filter(|&irq| has_own_gsi(irq)) {
+        route_irq(irq, IRQ_OFFSET + irq, destination);
+    }
+    set_irq_masked(InterruptIndex::Keyboard.irq(), false);
+    set_irq_masked(InterruptIndex::PS2.irq(), false);
+
+    interrupts::enable();
+}
+
+/// Enables the local APIC of an application processor.
+///
+/// Must be called on every CPU other than the bootstrap one, after `init`.
+pub fn init_ap() {
+    local_apic().enable(SPURIOUS_INTERRUPT_VECTOR);
+}
+
+/// Returns the local APIC of the executing CPU.
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.get().expect("APIC not initialized")
+}
+
+/// Signals the end of the interrupt currently being handled.
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.get() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/// Routes the given ISA IRQ to `vector` on the CPU with the APIC id
+/// `destination`, honouring the interrupt source overrides of the MADT.
+///
+/// The IRQ is left masked.
+pub fn route_irq(irq: u8, vector: u8, destination: u8) {
+    let (gsi, polarity, trigger_mode) = irq_to_gsi(irq);
+    let entry = RedirectionEntry {
+        vector,
+        destination,
+        polarity,
+        trigger_mode,
+        masked: true,
+    };
+    with_io_apic(gsi, |io_apic| io_apic.set_entry(gsi, entry));
+}
+
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let (gsi, _, _) = irq_to_gsi(irq);
+    with_io_apic(gsi, |io_apic| io_apic.set_masked(gsi, masked));
+}
+
+/// Returns the global system interrupt an ISA IRQ is wired to, along with
+/// its polarity and trigger mode.
+fn irq_to_gsi(irq: u8) -> (u32, Polarity, TriggerMode) {
+    match acpi::madt().and_then(|madt| madt.interrupt_override(irq)) {
+        Some(o) => (o.gsi, o.polarity, o.trigger_mode),
+        None => (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+    }
+}
+
+/// Returns whether an ISA IRQ is wired to a GSI of its own.
+///
+/// An IRQ without an override is identity mapped, unless another IRQ is
+/// redirected to that GSI: on most machines IRQ 0 is wired to GSI 2, and
+/// routing IRQ 2 as well would take the timer interrupt over.
+fn has_own_gsi(irq: u8) -> bool {
+    if irq == CASCADE_IRQ {
+        return false;
+    }
+    match acpi::madt() {
+        Some(madt) => {
+            madt.interrupt_override(irq).is_some()
+                || !madt
+                    .interrupt_overrides
+                    .iter()
+                    .any(|o| o.gsi == u32::from(irq))
+        }
+        None => true,
+    }
+}
+
+fn with_io_apic(gsi: u32, f: impl FnOnce(&mut IoApic)) {
+    let io_apics = IO_APICS.get().expect("APIC not initialized");
+    interrupts::without_interrupts(|| {
+        for io_apic in io_apics {
+            let mut io_apic = io_apic.lock();
+            if io_apic.handles(gsi) {
+                f(&mut io_apic);
+                return;
+            }
+        }
+    });
+}
@@ -1,3 +1,4 @@
+use crate::clocksource;
 use crate::cmos::CMOS;
 use crate::time;
 
@@ -5,7 +6,12 @@ const DAYS_BEFORE_MONTH: [u64; 13] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 27
 
 // NOTE: This clock is monotonic
 pub fn uptime() -> f64 {
-    time::time_between_ticks() * time::ticks() as f64
+    uptime_nanoseconds() as f64 / 1e9
+}
+
+/// Returns the time since boot, read from the best clock source.
+pub fn uptime_nanoseconds() -> u64 {
+    clocksource::nanoseconds()
 }
 
 // NOTE: This clock is not monotonic
@@ -19,7 +25,8 @@ pub fn realtime() -> f64 {
         + 60 * rtc.minute as u64
         + rtc.second as u64;
 
-    let fract = time::time_between_ticks() * (time::ticks() - time::last_rtc_update()) as f64;
+    let since_update = uptime_nanoseconds().saturating_sub(time::last_rtc_update());
+    let fract = (since_update as f64 / 1e9).min(1.0);
 
     (timestamp as f64) + fract
 }
@@ -0,0 +1,99 @@
+//! The high precision event timer, found through the ACPI HPET table.
+//!
+//! Only its main counter is used, as a clock source. The comparators stay
+//! disabled: the local APIC timers drive the scheduler and the PIT the
+//! tick count.
+
+use super::ClockSource;
+use core::ptr;
+use x86_64::VirtAddr;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+const CAPABILITIES_COUNTER_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    Capabilities = 0x000,
+    Configuration = 0x010,
+    InterruptStatus = 0x020,
+    MainCounter = 0x0F0,
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    /// Period of the main counter, in femtoseconds.
+    period: u64,
+}
+
+impl Hpet {
+    /// Creates a handle to the HPET registers mapped at `base`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `base`
+    /// is an uncacheable mapping of the HPET registers.
+    pub unsafe fn new(base: VirtAddr) -> Self {
+        let mut hpet = Hpet { base, period: 0 };
+        hpet.period = hpet.read(Register::Capabilities) >> 32;
+        hpet
+    }
+
+    pub fn counter_is_64_bit(&self) -> bool {
+        unsafe { self.read(Register::Capabilities) & CAPABILITIES_COUNTER_64_BIT != 0 }
+    }
+
+    /// Starts the main counter, leaving the PIT and RTC interrupts routed
+    /// as they are.
+    pub fn enable(&self) {
+        unsafe {
+            let configuration = self.read(Register::Configuration);
+            let configuration = configuration & !CONFIGURATION_LEGACY_REPLACEMENT;
+            self.write(
+                Register::Configuration,
+                configuration | CONFIGURATION_ENABLE,
+            );
+        }
+    }
+
+    /// Reads an HPET register.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the register block must still be
+    /// mapped.
+    pub unsafe fn read(&self, register: Register) -> u64 {
+        ptr::read_volatile((self.base + register as usize).as_ptr())
+    }
+
+    /// Writes an HPET register.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because writes can stop the counter or
+    /// reroute the legacy timer interrupts.
+    pub unsafe fn write(&self, register: Register, value: u64) {
+        ptr::write_volatile((self.base + register as usize).as_mut_ptr(), value);
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period.max(1)
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { self.read(Register::MainCounter) }
+    }
+}
@@ -0,0 +1,75 @@
+//! The local APIC timer, one per CPU.
+
+use super::{measure, ClockEvent, ClockSource, NANOSECONDS_PER_SECOND};
+use crate::apic::{self, TIMER_VECTOR};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Time the timer is measured for against the clock source.
+const CALIBRATION_TIME: u64 = 10_000_000;
+
+/// Timer ticks per second, 0 until calibrated. The timers of all the CPUs
+/// run at the same rate.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The timer of the executing CPU, raising `apic::TIMER_VECTOR`.
+pub struct LocalApicTimer;
+
+impl LocalApicTimer {
+    /// Measures the rate of the timers against the clock source in use,
+    /// unless that was already done.
+    pub fn calibrate(&self) {
+        if !self.is_calibrated() {
+            let frequency = measure_frequency(super::current());
+            FREQUENCY.store(frequency, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        FREQUENCY.load(Ordering::Relaxed) != 0
+    }
+
+    fn counts(&self, nanoseconds: u64) -> u32 {
+        let frequency = FREQUENCY.load(Ordering::Relaxed) as u128;
+        let counts = nanoseconds as u128 * frequency / NANOSECONDS_PER_SECOND as u128;
+        counts.clamp(1, u32::MAX as u128) as u32
+    }
+}
+
+impl ClockEvent for LocalApicTimer {
+    fn name(&self) -> &'static str {
+        "lapic"
+    }
+
+    /// The timer frequency is unknown, so the first call measures it
+    /// against the clock source in use.
+    fn start_periodic(&self, frequency: u32) {
+        self.calibrate();
+        let period = self.counts(NANOSECONDS_PER_SECOND / frequency.max(1) as u64);
+        apic::local_apic().start_timer_periodic(TIMER_VECTOR, period);
+    }
+
+    /// Does nothing until the timer has been calibrated, which cannot be
+    /// done from an interrupt handler.
+    fn start_one_shot(&self, nanoseconds: u64) {
+        if self.is_calibrated() {
+            let counts = self.counts(nanoseconds);
+            apic::local_apic().start_timer_one_shot_interrupt(TIMER_VECTOR, counts);
+        }
+    }
+
+    fn stop(&self) {
+        apic::local_apic().stop_timer();
+    }
+}
+
+fn measure_frequency(reference: &dyn ClockSource) -> u64 {
+    let local_apic = apic::local_apic();
+    local_apic.start_timer_one_shot(u32::MAX);
+    let (start, end, elapsed) = measure(reference, CALIBRATION_TIME, || {
+        local_apic.timer_current_count()
+    });
+    local_apic.stop_timer();
+
+    // The timer counts down
+    ((start - end) as u128 * NANOSECONDS_PER_SECOND as u128 / elapsed as u128) as u64
+}
@@ -0,0 +1,213 @@
+//! Clock sources, free-running counters the time is read from, and clock
+//! event devices, timers raising interrupts at a fixed rate.
+//!
+//! Every clock source found at boot is registered and the one with the
+//! highest rating backs `nanoseconds`. The others stay registered, as
+//! references to calibrate the newer ones against.
+
+use crate::{acpi, cpu, memory, serial_println};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::hint::spin_loop;
+use spin::{Mutex, RwLock};
+use x86_64::{instructions::interrupts, PhysAddr};
+
+mod hpet;
+mod lapic;
+mod pit;
+mod tsc;
+
+pub use hpet::Hpet;
+pub use lapic::LocalApicTimer;
+pub use pit::{Pit, PIT_FREQUENCY};
+pub use tsc::Tsc;
+
+/// Size of the HPET register block.
+const HPET_SIZE: usize = 0x400;
+/// Time the TSC is measured for against the best other clock source.
+const TSC_CALIBRATION_TIME: u64 = 50_000_000;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+static TSC: OnceCell<Tsc> = OnceCell::uninit();
+
+static SOURCES: Mutex<Vec<&'static dyn ClockSource>> = Mutex::new(Vec::new());
+static CURRENT: RwLock<Option<Current>> = RwLock::new(None);
+
+/// A counter that keeps running at a fixed rate.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good the source is, the highest rated one is used.
+    fn rating(&self) -> u32;
+
+    /// Number of counts per second.
+    fn frequency(&self) -> u64;
+
+    /// Reads the counter, which must never go backwards.
+    fn read(&self) -> u64;
+
+    /// Converts the counter to nanoseconds since it started.
+    fn nanoseconds(&self) -> u64 {
+        counts_to_nanoseconds(self.read(), self.frequency())
+    }
+}
+
+/// A timer raising its interrupt at a fixed rate.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Starts raising the interrupt of the timer `frequency` times per
+    /// second, on the executing CPU if the timer is per CPU.
+    fn start_periodic(&self, frequency: u32);
+
+    /// Raises the interrupt of the timer once, after at least
+    /// `nanoseconds`, or as late as the timer can count if that is sooner.
+    fn start_one_shot(&self, nanoseconds: u64);
+
+    fn stop(&self);
+}
+
+/// The clock source in use, and where it took over from the previous one.
+#[derive(Clone, Copy)]
+struct Current {
+    source: &'static dyn ClockSource,
+    start: u64,
+    offset: u64,
+}
+
+/// Registers the clock sources besides the PIT and picks the best one, then
+/// calibrates the local APIC timers, which take over the timer interrupts.
+/// The PIT is stopped unless it is still the clock source.
+///
+/// Must be called once, after `apic::init` and after the PIT has been
+/// started by `time::init`, with interrupts enabled, as the newer clocks
+/// are measured against the others.
+pub fn init() {
+    if let Some(table) = acpi::hpet() {
+        init_hpet(table.base_address);
+    }
+
+    let features = cpu::features();
+    if features.tsc {
+        let tsc = Tsc::calibrate(current(), TSC_CALIBRATION_TIME);
+        register(TSC.get_or_init(|| tsc));
+        if !features.invariant_tsc {
+            serial_println!("WARNING: the TSC is not invariant, it is only used for calibration");
+        }
+    }
+
+    LocalApicTimer.calibrate();
+    let source = current();
+    if source.name() != ClockSource::name(&Pit) {
+        Pit.stop();
+    }
+    serial_println!(
+        "Clocksource: {} at {} Hz",
+        source.name(),
+        source.frequency()
+    );
+}
+
+fn init_hpet(addr: PhysAddr) {
+    let base = match memory::map_mmio(addr, HPET_SIZE) {
+        Ok(base) => base,
+        Err(err) => {
+            serial_println!("WARNING: failed to map the HPET: {:?}", err);
+            return;
+        }
+    };
+    let hpet = unsafe { Hpet::new(base) };
+    // A 32-bit counter wraps within minutes
+    if !hpet.counter_is_64_bit() {
+        serial_println!("WARNING: ignoring the HPET, its counter is only 32 bits");
+        return;
+    }
+    hpet.enable();
+    register(HPET.get_or_init(|| hpet));
+}
+
+/// Adds a clock source, switching to it if it is rated higher than the one
+/// in use. The time keeps going on from where the previous source left it.
+pub fn register(source: &'static dyn ClockSource) {
+    // Interrupt handlers may read the time
+    interrupts::without_interrupts(|| {
+        SOURCES.lock().push(source);
+
+        let mut current = CURRENT.write();
+        let better = current.is_none_or(|current| source.rating() > current.source.rating());
+        if better {
+            let offset = current.map_or(0, |current| current.nanoseconds());
+            *current = Some(Current {
+                source,
+                start: source.read(),
+                offset,
+            });
+        }
+    });
+}
+
+/// Returns the clock source in use.
+///
+/// Panics if no clock source has been registered yet.
+pub fn current() -> &'static dyn ClockSource {
+    CURRENT.read().expect("no clock source registered").source
+}
+
+/// Returns the TSC once it has been calibrated by `init`, also when it is
+/// not the clock source in use.
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.get()
+}
+
+/// Returns every registered clock source.
+pub fn sources() -> Vec<&'static dyn ClockSource> {
+    interrupts::without_interrupts(|| SOURCES.lock().clone())
+}
+
+/// Returns the nanoseconds elapsed since the first clock source was
+/// registered, or 0 before that.
+pub fn nanoseconds() -> u64 {
+    CURRENT.read().map_or(0, |current| current.nanoseconds())
+}
+
+impl Current {
+    fn nanoseconds(&self) -> u64 {
+        let counts = self.source.read().saturating_sub(self.start);
+        self.offset + counts_to_nanoseconds(counts, self.source.frequency())
+    }
+}
+
+/// Calls `read` when `reference` changes and again once `duration`
+/// nanoseconds have passed on it, to measure another counter. Returns both
+/// values and the nanoseconds actually elapsed.
+///
+/// Spins rather than halts: the interrupt a reference depends on may be
+/// routed to another CPU.
+fn measure<T>(
+    reference: &dyn ClockSource,
+    duration: u64,
+    mut read: impl FnMut() -> T,
+) -> (T, T, u64) {
+    // Start on a change of the reference, which may count in steps
+    let previous = reference.read();
+    let mut start = reference.read();
+    while start == previous {
+        spin_loop();
+        start = reference.read();
+    }
+    let first = read();
+
+    let frequency = reference.frequency();
+    let mut elapsed = 0;
+    while elapsed < duration {
+        spin_loop();
+        elapsed = counts_to_nanoseconds(reference.read() - start, frequency);
+    }
+    (first, read(), elapsed)
+}
+
+fn counts_to_nanoseconds(counts: u64, frequency: u64) -> u64 {
+    (counts as u128 * NANOSECONDS_PER_SECOND as u128 / frequency.max(1) as u128) as u64
+}
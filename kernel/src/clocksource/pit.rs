@@ -0,0 +1,108 @@
+//! The 8254 programmable interval timer.
+//!
+//! Only channel 0 is used, as a square wave generator on IRQ 0. As its counter
+//! cannot be read without racing the interrupt, the clock source counts
+//! the oscillations between interrupts instead.
+
+use super::{ClockEvent, ClockSource};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Frequency of the PIT oscillator, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL: u8 = 0;
+
+// Operating modes, already shifted into place
+const INTERRUPT_ON_TERMINAL_COUNT: u8 = 0;
+const SQUARE_WAVE_GENERATOR: u8 = 6;
+
+/// Current divider of channel 0, 0 standing for 65536.
+static DIVIDER: AtomicU16 = AtomicU16::new(0);
+/// Oscillations counted by the interrupts of channel 0.
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub struct Pit;
+
+impl Pit {
+    /// Returns the number of oscillations between two interrupts.
+    pub fn divider(&self) -> u64 {
+        match DIVIDER.load(Ordering::Relaxed) {
+            0 => 65536,
+            divider => divider as u64,
+        }
+    }
+
+    /// Accounts for one interrupt of channel 0.
+    pub fn tick(&self) {
+        COUNT.fetch_add(self.divider(), Ordering::Relaxed);
+    }
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_FREQUENCY
+    }
+
+    fn read(&self) -> u64 {
+        COUNT.load(Ordering::Relaxed)
+    }
+}
+
+impl ClockEvent for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    /// Raises IRQ 0, as close to `frequency` times per second as the
+    /// divider allows.
+    fn start_periodic(&self, frequency: u32) {
+        let divider = (PIT_FREQUENCY + frequency as u64 / 2) / frequency.max(1) as u64;
+        // 0 acts as 65536
+        let divider = divider.clamp(1, 65536) as u16;
+        DIVIDER.store(divider, Ordering::Relaxed);
+        set_divider(divider, SQUARE_WAVE_GENERATOR);
+    }
+
+    /// Raises IRQ 0 once, after at most 55 ms. The clock source counts up
+    /// to the interrupt and then stands still.
+    fn start_one_shot(&self, nanoseconds: u64) {
+        let counts = nanoseconds as u128 * PIT_FREQUENCY as u128 / 1_000_000_000;
+        let counts = counts.clamp(1, u16::MAX as u128) as u16;
+        DIVIDER.store(counts, Ordering::Relaxed);
+        set_divider(counts, INTERRUPT_ON_TERMINAL_COUNT);
+    }
+
+    /// Puts channel 0 in one-shot mode without starting a count, so that it
+    /// stops raising IRQ 0.
+    fn stop(&self) {
+        interrupts::without_interrupts(|| {
+            let mut cmd: Port<u8> = Port::new(0x43);
+            let access_mode = 3; // Lobyte + Hibyte
+            unsafe { cmd.write((CHANNEL << 6) | (access_mode << 4) | INTERRUPT_ON_TERMINAL_COUNT) };
+        });
+    }
+}
+
+/// The frequency divider must be between 0 and 65535, with 0 acting as 65536
+fn set_divider(divider: u16, operating_mode: u8) {
+    interrupts::without_interrupts(|| {
+        let bytes = divider.to_le_bytes();
+        let mut cmd: Port<u8> = Port::new(0x43);
+        let mut data: Port<u8> = Port::new(0x40 + CHANNEL as u16);
+        let access_mode = 3; // Lobyte + Hibyte
+        unsafe {
+            cmd.write((CHANNEL << 6) | (access_mode << 4) | operating_mode);
+            data.write(bytes[0]);
+            data.write(bytes[1]);
+        }
+    });
+}
@@ -0,0 +1,55 @@
+//! The time stamp counter of the CPU.
+//!
+//! Its frequency is not reported anywhere reliable, so it is measured
+//! against another clock source. Without an invariant TSC the rate changes
+//! with the power state, and the source is rated below the PIT.
+
+use super::{measure, ClockSource, NANOSECONDS_PER_SECOND};
+use crate::cpu;
+use core::arch::x86_64::{_mm_lfence, _rdtsc};
+
+pub struct Tsc {
+    frequency: u64,
+    invariant: bool,
+}
+
+impl Tsc {
+    /// Measures the TSC against `reference` for `duration` nanoseconds.
+    pub fn calibrate(reference: &dyn ClockSource, duration: u64) -> Self {
+        let (start, end, elapsed) = measure(reference, duration, rdtsc);
+        let frequency = (end - start) as u128 * NANOSECONDS_PER_SECOND as u128 / elapsed as u128;
+        Tsc {
+            frequency: frequency as u64,
+            invariant: cpu::features().invariant_tsc,
+        }
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant {
+            300
+        } else {
+            50
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe {
+        _mm_lfence();
+        _rdtsc()
+    }
+}
@@ -1,5 +1,6 @@
 use core::hint::spin_loop;
 
+use crate::acpi;
 use bit_field::BitField;
 use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
@@ -71,6 +72,9 @@ impl CMOS {
             }
         }
 
+        let century_register = acpi::century_register();
+        let mut century = century_register.map(|reg| self.read_raw_register(reg));
+
         let b = self.read_register(Register::B);
 
         if b & 0x04 == 0 {
@@ -81,6 +85,7 @@ impl CMOS {
             rtc.day = (rtc.day & 0x0F) + ((rtc.day / 16) * 10);
             rtc.month = (rtc.month & 0x0F) + ((rtc.month / 16) * 10);
             rtc.year = (rtc.year & 0x0F) + ((rtc.year / 16) * 10);
+            century = century.map(|c| (c & 0x0F) + ((c / 16) * 10));
         }
 
         if (b & 0x02 == 0) && (rtc.hour & 0x80 == 0) {
@@ -88,7 +93,8 @@ impl CMOS {
             rtc.hour = ((rtc.hour & 0x7F) + 12) % 24;
         }
 
-        rtc.year += 2000; // TODO: Don't forget to change this next century
+        // Without a century register in the FADT, assume the 21st century
+        rtc.year += century.map_or(2000, |c| c as u16 * 100);
 
         rtc
     }
@@ -156,8 +162,12 @@ impl CMOS {
     }
 
     fn read_register(&mut self, reg: Register) -> u8 {
+        self.read_raw_register(reg as u8)
+    }
+
+    fn read_raw_register(&mut self, reg: u8) -> u8 {
         unsafe {
-            self.addr.write(reg as u8);
+            self.addr.write(reg);
             self.data.read()
         }
     }
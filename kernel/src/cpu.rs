@@ -0,0 +1,411 @@
+//! CPU identification with CPUID and the protections enabled from it.
+//!
+//! Every CPU runs `init` once, the features are assumed to be the same on
+//! all of them.
+
+use crate::serial_println;
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid_count, CpuidResult},
+    },
+    fmt, str,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    model_specific::{Efer, EferFlags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+const CPUID_VENDOR: u32 = 0;
+const CPUID_FEATURES: u32 = 1;
+const CPUID_CACHE_PARAMETERS: u32 = 4;
+const CPUID_EXTENDED_FEATURES: u32 = 7;
+const CPUID_EXTENDED_STATE: u32 = 0xD;
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_EXTENDED_PROCESSOR_INFO: u32 = 0x8000_0001;
+const CPUID_BRAND_STRING: u32 = 0x8000_0002;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_AMD_CACHE_PARAMETERS: u32 = 0x8000_001D;
+
+/// Number of cache levels and kinds kept, more than any current CPU has.
+const MAX_CACHES: usize = 8;
+/// Size of the area saved by `fxsave`.
+const FXSAVE_AREA_SIZE: usize = 512;
+
+static INFO: OnceCell<CpuInfo> = OnceCell::uninit();
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+static EXTENDED_STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+/// The features supported by the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// The time stamp counter can be read with `rdtsc`.
+    pub tsc: bool,
+    /// The time stamp counter runs at a constant rate in every power state.
+    pub invariant_tsc: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    /// The extended state can be saved with `xsave`.
+    pub xsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    /// Pages can be marked no-execute.
+    pub nx: bool,
+    /// Level 3 tables can map 1 GiB pages.
+    pub huge_pages: bool,
+    /// Supervisor mode execution prevention: the kernel cannot execute
+    /// user pages.
+    pub smep: bool,
+    /// Supervisor mode access prevention: the kernel cannot access user
+    /// pages outside of `stac`/`clac` pairs.
+    pub smap: bool,
+}
+
+impl Features {
+    fn names(&self) -> [(&'static str, bool); 11] {
+        [
+            ("tsc", self.tsc),
+            ("invariant-tsc", self.invariant_tsc),
+            ("apic", self.apic),
+            ("x2apic", self.x2apic),
+            ("xsave", self.xsave),
+            ("avx", self.avx),
+            ("rdrand", self.rdrand),
+            ("nx", self.nx),
+            ("1g-pages", self.huge_pages),
+            ("smep", self.smep),
+            ("smap", self.smap),
+        ]
+    }
+}
+
+impl fmt::Display for Features {
+    /// Lists the supported features, separated by spaces.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut supported = self.names().into_iter().filter(|&(_, supported)| supported);
+        if let Some((name, _)) = supported.next() {
+            write!(f, "{}", name)?;
+        }
+        for (name, _) in supported {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A cache, as described by the deterministic cache parameters leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Size in bytes.
+    pub size: u64,
+    pub line_size: u32,
+    pub ways: u32,
+    /// Number of logical CPUs sharing the cache.
+    pub shared_by: u32,
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CacheKind::Data => "d",
+            CacheKind::Instruction => "i",
+            CacheKind::Unified => "",
+        };
+        write!(
+            f,
+            "L{}{} {} KiB, {}-way, {} B lines, shared by {}",
+            self.level,
+            kind,
+            self.size / 1024,
+            self.ways,
+            self.line_size,
+            self.shared_by
+        )
+    }
+}
+
+/// The identification of the CPU.
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+    caches: [Option<Cache>; MAX_CACHES],
+}
+
+impl CpuInfo {
+    fn read() -> Self {
+        let max_leaf = cpuid(CPUID_VENDOR).eax;
+        let max_extended_leaf = cpuid(CPUID_EXTENDED_MAX).eax;
+        let leaf = |leaf| (leaf <= max_leaf).then(|| cpuid(leaf));
+        let extended_leaf = |leaf| (leaf <= max_extended_leaf).then(|| cpuid(leaf));
+
+        let vendor_id = cpuid(CPUID_VENDOR);
+        let mut vendor = [0; 12];
+        for (chunk, register) in
+            vendor
+                .chunks_mut(4)
+                .zip([vendor_id.ebx, vendor_id.edx, vendor_id.ecx])
+        {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut brand = [0; 48];
+        if max_extended_leaf >= CPUID_BRAND_STRING + 2 {
+            for (i, chunk) in brand.chunks_mut(16).enumerate() {
+                let result = cpuid(CPUID_BRAND_STRING + i as u32);
+                for (bytes, register) in chunk
+                    .chunks_mut(4)
+                    .zip([result.eax, result.ebx, result.ecx, result.edx])
+                {
+                    bytes.copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let version = leaf(CPUID_FEATURES).map_or(0, |result| result.eax);
+        let base_family = (version >> 8) & 0xF;
+        let base_model = (version >> 4) & 0xF;
+        let family = match base_family {
+            0xF => base_family + ((version >> 20) & 0xFF),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xF => base_model | ((version >> 12) & 0xF0),
+            _ => base_model,
+        };
+
+        let basic = leaf(CPUID_FEATURES);
+        let extended = leaf(CPUID_EXTENDED_FEATURES);
+        let processor_info = extended_leaf(CPUID_EXTENDED_PROCESSOR_INFO);
+        let power_management = extended_leaf(CPUID_POWER_MANAGEMENT);
+        let bit =
+            |result: Option<u32>, bit: u32| result.is_some_and(|value| value & (1 << bit) != 0);
+        let features = Features {
+            tsc: bit(basic.map(|result| result.edx), 4),
+            invariant_tsc: bit(power_management.map(|result| result.edx), 8),
+            apic: bit(basic.map(|result| result.edx), 9),
+            x2apic: bit(basic.map(|result| result.ecx), 21),
+            xsave: bit(basic.map(|result| result.ecx), 26),
+            avx: bit(basic.map(|result| result.ecx), 28),
+            rdrand: bit(basic.map(|result| result.ecx), 30),
+            nx: bit(processor_info.map(|result| result.edx), 20),
+            huge_pages: bit(processor_info.map(|result| result.edx), 26),
+            smep: bit(extended.map(|result| result.ebx), 7),
+            smap: bit(extended.map(|result| result.ebx), 20),
+        };
+
+        // AMD has the same leaf at a different number, when it supports
+        // topology extensions
+        let cache_leaf = if &vendor == b"AuthenticAMD" {
+            let topology = bit(processor_info.map(|result| result.ecx), 22);
+            (topology && CPUID_AMD_CACHE_PARAMETERS <= max_extended_leaf)
+                .then_some(CPUID_AMD_CACHE_PARAMETERS)
+        } else {
+            (CPUID_CACHE_PARAMETERS <= max_leaf).then_some(CPUID_CACHE_PARAMETERS)
+        };
+        let mut caches = [None; MAX_CACHES];
+        if let Some(cache_leaf) = cache_leaf {
+            for (i, slot) in caches.iter_mut().enumerate() {
+                *slot = cache(__cpuid_count(cache_leaf, i as u32));
+                if slot.is_none() {
+                    break;
+                }
+            }
+        }
+
+        CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: version & 0xF,
+            features,
+            caches,
+        }
+    }
+
+    /// Returns the vendor string, such as `GenuineIntel` or `AuthenticAMD`.
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Returns the brand string, or an empty string if the CPU has none.
+    pub fn brand(&self) -> &str {
+        let end = self.brand.iter().position(|&byte| byte == 0);
+        let brand = &self.brand[..end.unwrap_or(self.brand.len())];
+        str::from_utf8(brand).unwrap_or("").trim()
+    }
+
+    /// Returns the caches, from the lowest level.
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().map_while(Option::as_ref)
+    }
+}
+
+/// Returns the identification of the CPU, read on the first call.
+pub fn info() -> &'static CpuInfo {
+    INFO.get_or_init(CpuInfo::read)
+}
+
+/// Returns the features supported by the CPU.
+pub fn features() -> Features {
+    info().features
+}
+
+/// Prints the identification of the CPU to the serial port.
+pub fn print_info() {
+    let info = info();
+    serial_println!(
+        "CPU: {} {} (family {:#x}, model {:#x}, stepping {})",
+        info.vendor(),
+        info.brand(),
+        info.family,
+        info.model,
+        info.stepping
+    );
+    serial_println!("CPU: features {}", info.features);
+    for cache in info.caches() {
+        serial_println!("CPU: {}", cache);
+    }
+}
+
+/// Enables no-execute pages, write protection of read-only pages in ring 0,
+/// SMEP and SMAP on the executing CPU, as far as it supports them, as well
+/// as the SSE and AVX registers.
+pub fn init() {
+    let features = features();
+    if features.nx {
+        unsafe { Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE) };
+    }
+    unsafe { Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT) };
+
+    let mut cr4 = Cr4Flags::empty();
+    if features.smep {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.smap {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    unsafe { Cr4::update(|flags| *flags |= cr4) };
+    SMAP_ENABLED.store(features.smap, Ordering::Relaxed);
+
+    init_extended_state(features);
+}
+
+/// Lets code use the x87, SSE and, if supported, AVX registers, whose
+/// contents the scheduler saves for every thread.
+///
+/// The kernel itself is built for soft-float, so interrupt handlers never
+/// touch them.
+fn init_extended_state(features: Features) {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| *flags |= Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+    }
+    if !features.xsave {
+        return;
+    }
+
+    let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+    if features.avx {
+        xcr0 |= XCr0Flags::AVX;
+    }
+    unsafe {
+        Cr4::update(|flags| *flags |= Cr4Flags::OSXSAVE);
+        XCr0::write(xcr0);
+    }
+    // The size depends on the components enabled in XCR0
+    let size = __cpuid_count(CPUID_EXTENDED_STATE, 0).ebx as usize;
+    EXTENDED_STATE_SIZE.store(size.max(FXSAVE_AREA_SIZE), Ordering::Relaxed);
+    XSAVE_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Checks whether the extended state is saved with `xsave` rather than
+/// `fxsave`.
+pub fn xsave_enabled() -> bool {
+    XSAVE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the size of the area the extended state is saved to.
+pub fn extended_state_size() -> usize {
+    EXTENDED_STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Checks whether the kernel needs `stac` to access user memory.
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Allows the kernel to access user pages until the guard is dropped.
+///
+/// Does nothing when SMAP is disabled, as `stac` and `clac` do not exist
+/// on CPUs without it.
+pub struct UserAccess(());
+
+impl UserAccess {
+    pub fn allow() -> Self {
+        if smap_enabled() {
+            // Not `nomem`, accesses to user memory must stay between the pair
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccess(())
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if smap_enabled() {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+/// Decodes a subleaf of the cache parameters leaf, `None` marking the end
+/// of the list.
+fn cache(result: CpuidResult) -> Option<Cache> {
+    let kind = match result.eax & 0x1F {
+        1 => CacheKind::Data,
+        2 => CacheKind::Instruction,
+        3 => CacheKind::Unified,
+        _ => return None,
+    };
+    let line_size = (result.ebx & 0xFFF) + 1;
+    let partitions = ((result.ebx >> 12) & 0x3FF) + 1;
+    let ways = (result.ebx >> 22) + 1;
+    let sets = u64::from(result.ecx) + 1;
+    // Only bogus values from a hypervisor get near overflowing even a u64
+    let set_size = u64::from(ways) * u64::from(partitions) * u64::from(line_size);
+    let size = set_size.saturating_mul(sets);
+    Some(Cache {
+        level: ((result.eax >> 5) & 0x7) as u8,
+        kind,
+        size,
+        line_size,
+        ways,
+        shared_by: ((result.eax >> 14) & 0xFFF) + 1,
+    })
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    __cpuid_count(leaf, 0)
+}
@@ -0,0 +1,373 @@
+//! Loader for statically linked ELF64 executables.
+//!
+//! Only what is needed to start a program is parsed: the file header and
+//! the program headers. Every `PT_LOAD` segment is mapped in a fresh
+//! address space with the permissions it asks for, and the main thread
+//! starts at the entry point with the System V stack layout: `argc`, the
+//! `argv` and `envp` arrays and the auxiliary vector.
+
+use crate::{
+    memory::{Backing, VmError},
+    process::{self, AddressSpace, ProcessHandle, USER_STACK_SIZE},
+};
+use alloc::vec::Vec;
+use core::slice;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header it announces.
+    Truncated,
+    InvalidMagic,
+    /// Not a 64-bit file.
+    UnsupportedClass,
+    UnsupportedEndianness,
+    UnsupportedVersion,
+    /// Not built for x86_64.
+    UnsupportedMachine,
+    /// Not a statically linked executable.
+    NotExecutable,
+    InvalidProgramHeader,
+    /// A segment would be mapped outside of the user range.
+    SegmentOutOfRange,
+    OverlappingSegments,
+    /// The entry point is not in an executable segment.
+    InvalidEntryPoint,
+    /// The arguments and environment do not fit on the stack.
+    ArgumentsTooLarge,
+    OutOfMemory,
+}
+
+/// A program header, as found in the file.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+impl ProgramHeader {
+    pub fn page_table_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.memory_size
+    }
+}
+
+/// A validated ELF64 executable.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers_offset: u64,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the file header and reads the program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let elf = Self::parse_headers(data)?;
+        elf.validate()?;
+        Ok(elf)
+    }
+
+    /// Reads the headers of an executable that is already loaded, such as
+    /// the kernel itself. Its segments are not checked, since they need
+    /// not be in the user range.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `image` must point to the mapped file
+    /// header, followed by the program headers at the offset it gives.
+    pub unsafe fn from_loaded(image: *const u8) -> Result<Elf<'static>, ElfError> {
+        let header = slice::from_raw_parts(image, HEADER_SIZE);
+        let program_headers_offset = read_u64(header, 32)? as usize;
+        let size = (read_u16(header, 54)? as usize)
+            .checked_mul(read_u16(header, 56)? as usize)
+            .and_then(|size| size.checked_add(program_headers_offset))
+            .ok_or(ElfError::InvalidProgramHeader)?;
+        Elf::parse_headers(slice::from_raw_parts(image, size.max(HEADER_SIZE)))
+    }
+
+    fn parse_headers(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::InvalidMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianness);
+        }
+        if data[6] != VERSION_CURRENT || read_u32(data, 20)? != VERSION_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if read_u16(data, 18)? != MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+        if read_u16(data, 16)? != TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let entry = read_u64(data, 24)?;
+        let program_headers_offset = read_u64(data, 32)?;
+        let entry_size = read_u16(data, 54)? as usize;
+        let count = read_u16(data, 56)? as usize;
+        if count > 0 && entry_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::InvalidProgramHeader);
+        }
+
+        let program_headers = (0..count)
+            .map(|i| {
+                let offset = usize::try_from(program_headers_offset)
+                    .ok()
+                    .and_then(|offset| offset.checked_add(i * entry_size))
+                    .ok_or(ElfError::Truncated)?;
+                read_program_header(data, offset)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Elf {
+            data,
+            entry,
+            program_headers_offset,
+            program_headers,
+        })
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    /// Returns the `PT_LOAD` program headers.
+    pub fn segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|header| header.kind == PT_LOAD)
+    }
+
+    fn validate(&self) -> Result<(), ElfError> {
+        for segment in self.segments() {
+            if segment.file_size > segment.memory_size {
+                return Err(ElfError::InvalidProgramHeader);
+            }
+            let file_end = segment.offset.checked_add(segment.file_size);
+            if !file_end.is_some_and(|end| end <= self.data.len() as u64) {
+                return Err(ElfError::Truncated);
+            }
+            let in_range = VirtAddr::try_new(segment.vaddr)
+                .is_ok_and(|addr| process::is_user_range(addr, segment.memory_size));
+            if !in_range {
+                return Err(ElfError::SegmentOutOfRange);
+            }
+        }
+
+        let executable = self
+            .segments()
+            .any(|segment| segment.flags & PF_X != 0 && segment.contains(self.entry));
+        if !executable {
+            return Err(ElfError::InvalidEntryPoint);
+        }
+        Ok(())
+    }
+
+    /// Returns where the program headers are in memory once loaded, if they
+    /// are part of a segment at all.
+    fn program_headers_addr(&self) -> Option<u64> {
+        if let Some(header) = self.program_headers.iter().find(|h| h.kind == PT_PHDR) {
+            return Some(header.vaddr);
+        }
+        let offset = self.program_headers_offset;
+        self.segments()
+            .find(|segment| offset >= segment.offset && offset - segment.offset < segment.file_size)
+            .map(|segment| segment.vaddr + (offset - segment.offset))
+    }
+
+    /// Maps every loadable segment in `address_space` and copies its
+    /// contents, the rest of the segment being zeroed.
+    pub fn load(&self, address_space: &mut AddressSpace) -> Result<(), ElfError> {
+        for segment in self.segments() {
+            if segment.memory_size == 0 {
+                continue;
+            }
+            let start = segment.vaddr & !(PAGE_SIZE - 1);
+            let size = segment.vaddr + segment.memory_size - start;
+            address_space
+                .map(
+                    VirtAddr::new(start),
+                    size,
+                    segment.page_table_flags(),
+                    Backing::Anonymous,
+                )
+                .map_err(|err| match err {
+                    VmError::Overlap => ElfError::OverlappingSegments,
+                    VmError::InvalidRange => ElfError::SegmentOutOfRange,
+                    _ => ElfError::OutOfMemory,
+                })?;
+
+            let offset = segment.offset as usize;
+            let contents = &self.data[offset..offset + segment.file_size as usize];
+            address_space
+                .write(VirtAddr::new(segment.vaddr), contents)
+                .map_err(|_| ElfError::OutOfMemory)?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads the executable in `data` in a new process and starts it with the
+/// given arguments and environment.
+pub fn spawn(data: &[u8], args: &[&str], env: &[&str]) -> Result<ProcessHandle, ElfError> {
+    let elf = Elf::parse(data)?;
+    let mut address_space = AddressSpace::new().map_err(|_| ElfError::OutOfMemory)?;
+    elf.load(&mut address_space)?;
+
+    let stack_top = address_space
+        .map_stack()
+        .map_err(|_| ElfError::OutOfMemory)?;
+    let stack_pointer = push_arguments(&elf, &mut address_space, stack_top, args, env)?;
+
+    Ok(process::spawn(address_space, elf.entry(), stack_pointer))
+}
+
+/// Writes the initial stack of the program below `stack_top` and returns
+/// the stack pointer to start with, pointing to `argc`.
+fn push_arguments(
+    elf: &Elf,
+    address_space: &mut AddressSpace,
+    stack_top: VirtAddr,
+    args: &[&str],
+    env: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let stack_bottom = stack_top.as_u64() - USER_STACK_SIZE;
+    let too_large = || ElfError::ArgumentsTooLarge;
+
+    // The strings go at the very top, in the order they are given
+    let mut cursor = stack_top.as_u64();
+    let mut push_string = |string: &str| {
+        let len = string.len() as u64 + 1;
+        cursor = cursor
+            .checked_sub(len)
+            .filter(|&addr| addr >= stack_bottom)?;
+        let addr = VirtAddr::new(cursor);
+        address_space.write(addr, string.as_bytes()).ok()?;
+        address_space.write(addr + string.len(), &[0]).ok()?;
+        Some(cursor)
+    };
+    let arg_addrs = args
+        .iter()
+        .map(|arg| push_string(arg))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(too_large)?;
+    let env_addrs = env
+        .iter()
+        .map(|var| push_string(var))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(too_large)?;
+
+    let mut auxv = Vec::new();
+    if let Some(addr) = elf.program_headers_addr() {
+        auxv.extend([
+            AT_PHDR,
+            addr,
+            AT_PHENT,
+            PROGRAM_HEADER_SIZE as u64,
+            AT_PHNUM,
+            elf.program_headers.len() as u64,
+        ]);
+    }
+    auxv.extend([AT_PAGESZ, PAGE_SIZE, AT_ENTRY, elf.entry, AT_NULL, 0]);
+
+    let mut words = Vec::with_capacity(args.len() + env.len() + auxv.len() + 3);
+    words.push(args.len() as u64);
+    words.extend(arg_addrs);
+    words.push(0);
+    words.extend(env_addrs);
+    words.push(0);
+    words.extend(auxv);
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    // The stack pointer must be 16-byte aligned at the entry point
+    let stack_pointer = cursor
+        .checked_sub(bytes.len() as u64)
+        .map(|addr| addr & !0xF)
+        .filter(|&addr| addr >= stack_bottom)
+        .ok_or_else(too_large)?;
+    let stack_pointer = VirtAddr::new(stack_pointer);
+    address_space
+        .write(stack_pointer, &bytes)
+        .map_err(|_| too_large())?;
+    Ok(stack_pointer)
+}
+
+fn read_program_header(data: &[u8], offset: usize) -> Result<ProgramHeader, ElfError> {
+    Ok(ProgramHeader {
+        kind: read_u32(data, offset)?,
+        flags: read_u32(data, offset + 4)?,
+        offset: read_u64(data, offset + 8)?,
+        vaddr: read_u64(data, offset + 16)?,
+        file_size: read_u64(data, offset + 32)?,
+        memory_size: read_u64(data, offset + 40)?,
+    })
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    let end = offset.checked_add(N).ok_or(ElfError::Truncated)?;
+    let bytes = data.get(offset..end).ok_or(ElfError::Truncated)?;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read_bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read_bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    read_bytes(data, offset).map(u64::from_le_bytes)
+}
@@ -0,0 +1,186 @@
+use crate::{
+    interrupts::{
+        DOUBLE_FAULT_IST_INDEX, GENERAL_PROTECTION_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX,
+    },
+    memory, percpu,
+};
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use lazy_static::lazy_static;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+/// Size of each interrupt stack table entry.
+pub const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Names of the interrupt stacks, in the order `new_tss` takes them.
+const IST_STACK_NAMES: [&str; 3] = [
+    "double fault stack",
+    "page fault stack",
+    "general protection fault stack",
+];
+
+/// Returns the top of a freshly reserved interrupt stack.
+///
+/// Each IST slot needs its own stack: the CPU switches to it unconditionally,
+/// so a fault taken while another IST handler is running must not land on the
+/// same memory.
+///
+/// These stacks have no guard page, they are only used until
+/// `init_interrupt_stacks` replaces them.
+macro_rules! ist_stack {
+    () => {{
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + IST_STACK_SIZE
+    }};
+}
+
+/// The TSS of the bootstrap processor.
+///
+/// The kernel changes the stacks of a TSS while the CPU uses it, so a TSS is
+/// only ever reached through a raw pointer.
+struct BootTss(UnsafeCell<TaskStateSegment>);
+
+// Only the bootstrap processor writes to it.
+unsafe impl Sync for BootTss {}
+
+lazy_static! {
+    static ref TSS: BootTss = BootTss(UnsafeCell::new(new_tss([
+        ist_stack!(),
+        ist_stack!(),
+        ist_stack!()
+    ])));
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(TSS.0.get());
+}
+
+/// The TSS loaded on each CPU, by CPU index.
+static TSSES: [AtomicPtr<TaskStateSegment>; percpu::MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; percpu::MAX_CPUS];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Creates a TSS using the given stacks for the double fault, page fault
+/// and general protection fault handlers.
+fn new_tss(ist_stacks: [VirtAddr; 3]) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stacks[0];
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist_stacks[1];
+    tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = ist_stacks[2];
+    tss
+}
+
+/// Creates a GDT whose TSS descriptor points to `tss`, which must never be
+/// freed.
+fn new_gdt(tss: *const TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // The order of the kernel and user segments is dictated by the
+    // `syscall`/`sysret` instructions, which derive the selectors from a
+    // single base in the STAR register.
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(tss) });
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_code,
+            user_data,
+            tss,
+        },
+    )
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// Loads the GDT and TSS of the bootstrap processor and reloads the
+/// segment registers.
+///
+/// Must be called before `interrupts::init_idt`, since the IDT entries refer
+/// to the interrupt stacks of the TSS loaded here.
+pub fn init() {
+    load(&GDT.0, &GDT.1);
+    TSSES[0].store(TSS.0.get(), Ordering::Release);
+}
+
+/// Moves the interrupt stacks of the bootstrap processor to stacks with a
+/// guard page, once the kernel address space is set up.
+///
+/// Must not be called from an interrupt handler running on one of them.
+pub fn init_interrupt_stacks() {
+    let tss = new_tss(guarded_ist_stacks());
+    unsafe { (*TSS.0.get()).interrupt_stack_table = tss.interrupt_stack_table };
+}
+
+/// Returns the top of the stack the bootstrap processor switches to for
+/// the interrupt stack table entry `index`.
+pub fn interrupt_stack_top(index: u16) -> VirtAddr {
+    unsafe { (*TSS.0.get()).interrupt_stack_table[usize::from(index)] }
+}
+
+/// Loads a GDT and TSS of its own on the application processor `cpu_id`.
+///
+/// Every CPU needs a separate TSS, since the interrupt stacks cannot be
+/// shared. The tables are allocated on the heap and never freed.
+pub fn init_ap(cpu_id: usize) {
+    let tss = Box::into_raw(Box::new(new_tss(guarded_ist_stacks())));
+    let (gdt, selectors) = new_gdt(tss);
+    let gdt = Box::leak(Box::new(gdt));
+    load(gdt, &selectors);
+    TSSES[cpu_id].store(tss, Ordering::Release);
+}
+
+fn guarded_ist_stacks() -> [VirtAddr; 3] {
+    IST_STACK_NAMES.map(|name| {
+        memory::allocate_stack(name, IST_STACK_SIZE as u64)
+            .expect("no memory for the interrupt stacks")
+    })
+}
+
+/// Returns the segment selectors, which are the same on every CPU.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the executing CPU switches to when an interrupt arrives
+/// in user mode.
+///
+/// Must be called after `percpu::init`.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    let tss = TSSES[percpu::current().id()].load(Ordering::Acquire);
+    assert!(!tss.is_null(), "no TSS loaded on this CPU");
+    unsafe { (*tss).privilege_stack_table[0] = stack_top };
+}
@@ -1,4 +1,8 @@
-use crate::{hlt_loop, pic, serial_println};
+use crate::{
+    apic, hlt_loop,
+    memory::{self, PageFault, Vma},
+    process, serial_println, smp, thread,
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
@@ -7,13 +11,11 @@ use x86_64::{
         port::{Port, PortReadOnly},
     },
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
-const PIC1: u16 = 0x21;
-const PIC2: u16 = 0xA1;
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// Vector of the first ISA IRQ, the ones before are reserved for CPU exceptions.
+pub const IRQ_OFFSET: u8 = 32;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PAGE_FAULT_IST_INDEX: u16 = 1;
@@ -22,7 +24,7 @@ pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
+    Timer = IRQ_OFFSET,
     Keyboard,
     Cascade,
     COM2,
@@ -48,6 +50,11 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// Returns the ISA IRQ number of this interrupt.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - IRQ_OFFSET
+    }
 }
 
 fn default_irq_handler() {}
@@ -56,7 +63,9 @@ lazy_static! {
     pub static ref IRQ_HANDLERS: Mutex<[fn(); 16]> = Mutex::new([default_irq_handler; 16]);
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -88,15 +97,61 @@ lazy_static! {
         idt[InterruptIndex::FPU.as_usize()].set_handler_fn(irq13_handler);
         idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(irq14_handler);
         idt[InterruptIndex::SecondaryATA.as_usize()].set_handler_fn(irq15_handler);
+        idt[apic::TIMER_VECTOR as usize].set_handler_fn(timer_interrupt_handler);
+        idt[apic::WAKEUP_VECTOR as usize].set_handler_fn(wakeup_interrupt_handler);
+        idt[apic::TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb_shootdown_interrupt_handler);
+        idt[apic::SPURIOUS_INTERRUPT_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
 pub fn init_idt() {
     IDT.load();
+    sync::set_critical_section(enter_critical_section, exit_critical_section);
+}
+
+/// Masks interrupts while the async primitives of the `sync` crate hold
+/// their internal lock, so that e.g. the keyboard handler can send on a
+/// channel a task was receiving from.
+fn enter_critical_section() -> bool {
+    let enabled = instructions::interrupts::are_enabled();
+    instructions::interrupts::disable();
+    enabled
+}
+
+fn exit_critical_section(enabled: bool) {
+    if enabled {
+        instructions::interrupts::enable();
+    }
+}
+
+/// Kills the running process instead of the kernel if the exception was
+/// raised in user mode.
+///
+/// Handlers running on an interrupt stack may call this as well: the
+/// process never resumes, so nothing is left on that stack to clobber.
+fn kill_if_user_mode(stack_frame: &InterruptStackFrame, reason: &str) {
+    if is_user_mode(stack_frame) {
+        process::kill_current(reason);
+    }
+}
+
+fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    kill_if_user_mode(&stack_frame, "divide error");
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    kill_if_user_mode(&stack_frame, "invalid opcode");
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    kill_if_user_mode(&stack_frame, "breakpoint");
     panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -104,6 +159,7 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) {
+    kill_if_user_mode(&stack_frame, "general protection fault");
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
 }
 
@@ -111,6 +167,7 @@ extern "x86-interrupt" fn stack_segment_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) {
+    kill_if_user_mode(&stack_frame, "stack segment fault");
     panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
 }
 
@@ -118,6 +175,7 @@ extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) {
+    kill_if_user_mode(&stack_frame, "segment not present");
     panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
 }
 
@@ -127,13 +185,73 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
+    let fault = PageFault::new(Cr2::read(), error_code);
+    if is_user_mode(&stack_frame) {
+        match handle_user_page_fault(&fault) {
+            Ok(()) => return,
+            Err(nearest) => {
+                report_page_fault(&fault, nearest);
+                process::kill_current("page fault");
+            }
+        }
+    }
+
+    if let Some(stack) =
+        memory::try_kernel_space().and_then(|space| space.guarded_stack(fault.addr))
+    {
+        serial_println!("EXCEPTION: stack overflow in {}", stack);
+    } else {
+        serial_println!("EXCEPTION: PAGE FAULT");
+    }
+    report_page_fault(&fault, nearest_region(fault.addr));
     serial_println!("Error Code: {:?}", error_code);
     serial_println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
+/// Maps the faulting page if it belongs to memory of the running process
+/// that was not accessed yet, or returns the area nearest to the address.
+fn handle_user_page_fault(fault: &PageFault) -> Result<(), Option<Vma>> {
+    let process = process::current().ok_or(None)?;
+    // Waiting for the lock would keep interrupts disabled on the interrupt
+    // stack, so the access is retried instead and faults again
+    let mut address_space = match process.address_space().try_lock() {
+        Some(address_space) => address_space,
+        None => return Ok(()),
+    };
+    address_space
+        .handle_fault(fault)
+        .map_err(|_| address_space.nearest(fault.addr).copied())
+}
+
+/// Returns the area nearest to `addr`, in the address space of the running
+/// process or in the kernel one. Locks are not waited for.
+fn nearest_region(addr: VirtAddr) -> Option<Vma> {
+    if process::is_user_range(addr, 1) {
+        let process = process::current()?;
+        let address_space = process.address_space().try_lock()?;
+        let nearest = address_space.nearest(addr).copied();
+        nearest
+    } else {
+        memory::try_kernel_space()?.nearest(addr).copied()
+    }
+}
+
+fn report_page_fault(fault: &PageFault, nearest: Option<Vma>) {
+    serial_println!("Page fault: {}", fault);
+    if let Some(vma) = nearest {
+        serial_println!(
+            "Nearest region: {:#x}..{:#x} {:?} {:?}",
+            vma.start.as_u64(),
+            vma.end().as_u64(),
+            vma.flags,
+            vma.backing
+        );
+    } else {
+        serial_println!("Nearest region: none");
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
@@ -146,11 +264,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        pic::PICS
-            .lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -158,21 +272,34 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
     let packet = unsafe { port.read() };
     crate::task::mouse::MOUSE.lock().process_packet(packet);
 
-    unsafe {
-        pic::PICS
-            .lock()
-            .notify_end_of_interrupt(InterruptIndex::PS2.as_u8());
-    }
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Acknowledge first, the scheduler may switch to another thread
+    apic::end_of_interrupt();
+    thread::tick();
 }
 
+/// Only used to bring a halted CPU out of `hlt`, see `smp::wake_cpu`.
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    smp::handle_tlb_shootdown();
+    apic::end_of_interrupt();
+}
+
+/// Spurious interrupts are not real interrupts and must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 macro_rules! irq_handler {
     ($handler:ident, $irq:expr) => {
         pub extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame) {
             let handlers = IRQ_HANDLERS.lock();
-            handlers[$irq as usize - PIC_1_OFFSET as usize]();
-            unsafe {
-                pic::PICS.lock().notify_end_of_interrupt($irq);
-            }
+            handlers[$irq as usize - IRQ_OFFSET as usize]();
+            apic::end_of_interrupt();
         }
     };
 }
@@ -202,17 +329,9 @@ pub fn set_irq_handler(irq: u8, handler: fn()) {
 }
 
 pub fn set_irq_mask(irq: u8) {
-    let mut port: Port<u8> = Port::new(if irq < 8 { PIC1 } else { PIC2 });
-    unsafe {
-        let value = port.read() | (1 << (if irq < 8 { irq } else { irq - 8 }));
-        port.write(value);
-    }
+    apic::set_irq_masked(irq, true);
 }
 
 pub fn clear_irq_mask(irq: u8) {
-    let mut port: Port<u8> = Port::new(if irq < 8 { PIC1 } else { PIC2 });
-    unsafe {
-        let value = port.read() & !(1 << if irq < 8 { irq } else { irq - 8 });
-        port.write(value);
-    }
+    apic::set_irq_masked(irq, false);
 }
@@ -2,49 +2,68 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 #![feature(exclusive_range_pattern)]
 
 extern crate alloc;
 extern crate log;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
 pub mod clock;
+pub mod clocksource;
 pub mod cmos;
+pub mod cpu;
+pub mod elf;
+pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
 pub mod pic;
+pub mod power;
+pub mod process;
 pub mod serial;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
 pub mod time;
+pub mod timer;
 
-use crate::{
-    memory::BootInfoFrameAllocator,
-    task::{executor::Executor, keyboard, Task},
-};
+use crate::task::{executor::Executor, keyboard, Priority, Task};
 use alloc::string::String;
 use bootloader::{boot_info::FrameBufferInfo, BootInfo};
 use core::panic::PanicInfo;
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
 
 pub fn main(boot_info: &'static mut BootInfo) -> ! {
+    gdt::init();
     interrupts::init_idt();
+    cpu::init();
+    cpu::print_info();
 
     if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
         let info: FrameBufferInfo = framebuffer.info();
 
         if let Some(physical_memory_offset) = boot_info.physical_memory_offset.as_mut() {
             let phys_mem_offset = VirtAddr::new(*physical_memory_offset);
-            let mut mapper = unsafe { memory::init(phys_mem_offset) };
-            let mut frame_allocator =
-                unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+            unsafe { memory::init(phys_mem_offset, &boot_info.memory_regions) };
 
-            allocator::init_heap(&mut mapper, &mut frame_allocator)
+            allocator::init_heap(&mut *memory::mapper(), &mut *memory::frame_allocator())
                 .expect("heap initialization failed");
+            memory::protect_kernel();
         } else {
             panic!("Could not find physical memory offset");
         }
 
+        match boot_info.rsdp_addr.into_option() {
+            Some(rsdp_addr) => unsafe { acpi::init(PhysAddr::new(rsdp_addr)) }
+                .expect("ACPI initialization failed"),
+            None => panic!("Could not find the RSDP"),
+        }
+
         let renderer = userland::init_renderer(
             framebuffer.buffer_mut(),
             info.horizontal_resolution,
@@ -55,19 +74,30 @@ pub fn main(boot_info: &'static mut BootInfo) -> ! {
 
         init();
 
-        let mut executor = Executor::new();
-        executor.spawn(Task::new(keyboard::print_keypresses()));
-        executor.run();
+        let executor = thread::spawn_thread(|| {
+            let mut executor = Executor::new();
+            executor.set_global();
+            let keyboard = Task::new(keyboard::dispatch_key_events());
+            executor.spawn(keyboard.with_priority(Priority::High));
+            executor.run()
+        });
+        executor.join();
     }
 
     loop {}
 }
 
 fn init() {
+    gdt::init_interrupt_stacks();
+    memory::guard_boot_stack();
     task::mouse::init();
 
-    pic::init();
+    apic::init();
     time::init();
+    percpu::init(0, apic::local_apic().id());
+    thread::init();
+    syscall::init();
+    smp::init();
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -86,6 +116,41 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// Sets up what most integration tests need: descriptor tables, CPU
+/// features, memory with the heap, and the ACPI tables if there are any.
+///
+/// Interrupt controllers, timers and threads are left to the tests that
+/// use them.
+pub fn test_init(boot_info: &'static BootInfo) {
+    gdt::init();
+    interrupts::init_idt();
+    cpu::init();
+
+    let physical_memory_offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("no physical memory offset");
+    unsafe { memory::init(VirtAddr::new(physical_memory_offset), &boot_info.memory_regions) };
+    allocator::init_heap(&mut *memory::mapper(), &mut *memory::frame_allocator())
+        .expect("heap initialization failed");
+
+    if let Some(rsdp_addr) = boot_info.rsdp_addr.into_option() {
+        unsafe { acpi::init(PhysAddr::new(rsdp_addr)) }.expect("ACPI initialization failed");
+    }
+}
+
+/// Ends the line of a test case started with `serial_print!`, and exits
+/// QEMU with a failure if `ok` is false.
+pub fn test_check(ok: bool) {
+    if ok {
+        serial_println!("[ok]");
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop();
+    }
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
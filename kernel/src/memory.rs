@@ -1,18 +1,303 @@
-use bootloader::boot_info::{MemoryRegions, MemoryRegionKind};
+use crate::{
+    elf::{Elf, ProgramHeader},
+    smp,
+};
+use alloc::vec::Vec;
+use bootloader::boot_info::MemoryRegions;
+use conquer_once::spin::OnceCell;
+use core::{
+    hint,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Mutex, MutexGuard};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    instructions::{interrupts, tlb},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
-/// Initialize a new OffsetPageTable.
+mod frame_allocator;
+mod user;
+pub mod vmm;
+
+pub use frame_allocator::{BuddyFrameAllocator, FrameStats, MAX_ORDER};
+pub use user::{copy_from_user, copy_to_user};
+pub use vmm::{
+    allocate_stack, guard_boot_stack, kernel_space, try_kernel_space, Access, AddressSpace,
+    Backing, KernelStack, PageFault, VmError, Vma,
+};
+
+/// Frames below this address are kept out of the general pool, so they stay
+/// available for code that has to run in real mode.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// End of the highest memory region, which the physical memory mapping
+/// covers.
+static PHYSICAL_MEMORY_END: AtomicU64 = AtomicU64::new(0);
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+static MAPPER: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+static FRAME_ALLOCATOR: OnceCell<Mutex<BuddyFrameAllocator>> = OnceCell::uninit();
+
+/// Initialize the kernel page table and frame allocator.
+///
+/// # Safety
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// `physical_memory_offset` and that the passed memory map is valid. Also,
+/// this function must be only called once to avoid aliasing `&mut`
+/// references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryRegions) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let memory_end = memory_map.iter().map(|region| region.end).max();
+    PHYSICAL_MEMORY_END.store(memory_end.unwrap_or(0), Ordering::Relaxed);
+    let (level_4_table_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_PAGE_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    MAPPER.init_once(|| Mutex::new(mapper));
+
+    let frame_allocator = BuddyFrameAllocator::init(memory_map);
+    FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
+
+    vmm::init();
+}
+
+/// Returns the page table of the kernel address space.
+///
+/// Interrupts are disabled until the guard is dropped, since the heap may
+/// have to map pages from any context.
+///
+/// Nothing may be allocated on the heap while the guard is held: growing
+/// the heap locks the mapper with the heap allocator locked, so such an
+/// allocation deadlocks as soon as the heap runs out of mapped memory.
+pub fn mapper() -> IrqSafeGuard<OffsetPageTable<'static>> {
+    IrqSafeGuard::lock(MAPPER.get().expect("memory not initialized"))
+}
+
+/// Returns the frame of the level 4 table of the kernel address space.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Returns the allocator used for physical frames.
+///
+/// Interrupts are disabled until the guard is dropped, so that frames can
+/// also be freed by the scheduler when it drops an exited process.
+///
+/// The heap takes frames from here when it grows, while holding its own
+/// lock. Allocating on the heap with this guard held can therefore
+/// deadlock, just like with the `mapper` guard.
+pub fn frame_allocator() -> IrqSafeGuard<BuddyFrameAllocator> {
+    IrqSafeGuard::lock(FRAME_ALLOCATOR.get().expect("memory not initialized"))
+}
+
+/// Returns the number of physical frames in use and available.
+pub fn frame_stats() -> FrameStats {
+    frame_allocator().stats()
+}
+
+/// Backs `pages` with newly allocated frames in the kernel address space.
+///
+/// Nothing stays mapped if this fails part way.
+pub fn map_kernel_pages(
+    pages: PageRange<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = mapper();
+    let mut frame_allocator = frame_allocator();
+    for (i, page) in pages.enumerate() {
+        let result = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) });
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for page in pages.take(i) {
+                    let (frame, flush) = mapper.unmap(page).expect("page was just mapped");
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" {
+    /// The ELF header of the kernel, which the linker places at the start
+    /// of its first segment.
+    static __ehdr_start: u8;
+}
+
+/// Maps the kernel image with the permissions of its segments, so that code
+/// is read-only and data is not executable, and makes the physical memory
+/// mapping non-executable.
+///
+/// The bootloader maps everything writable and executable. Must be called
+/// once, after the heap is set up.
+pub fn protect_kernel() {
+    let kernel = unsafe { Elf::from_loaded(&raw const __ehdr_start) }
+        .expect("the kernel has no valid ELF header");
+    let segments: Vec<ProgramHeader> = kernel
+        .segments()
+        .filter(|segment| segment.memory_size > 0)
+        .copied()
+        .collect();
+    let no_execute = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+
+    let mut mapper = mapper();
+    for segment in segments.iter() {
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+        let last = VirtAddr::new(segment.vaddr + segment.memory_size - 1);
+        for page in Page::range_inclusive(start, Page::containing_address(last)) {
+            let mut flags = kernel_page_flags(&segments, page);
+            if !no_execute {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+            // Huge pages are left alone
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.ignore();
+            }
+        }
+    }
+
+    if no_execute {
+        let memory_end = PHYSICAL_MEMORY_END.load(Ordering::Relaxed).max(1);
+        let first = phys_to_virt(PhysAddr::zero()).p4_index();
+        let last = phys_to_virt(PhysAddr::new(memory_end - 1)).p4_index();
+        let table = mapper.level_4_table();
+        for index in u16::from(first)..=u16::from(last) {
+            // The kernel could be within the mapping if it starts at zero
+            let has_kernel = segments.iter().any(|segment| {
+                VirtAddr::new(segment.vaddr).p4_index() == PageTableIndex::new(index)
+            });
+            let entry = &mut table[index as usize];
+            if !entry.is_unused() && !has_kernel {
+                entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+            }
+        }
+    }
+    tlb::flush_all();
+}
+
+/// Returns the flags of a page of the kernel image. A page shared by two
+/// segments gets the permissions of both.
+fn kernel_page_flags(segments: &[ProgramHeader], page: Page) -> PageTableFlags {
+    let start = page.start_address().as_u64();
+    let end = start + page.size();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    for segment in segments
+        .iter()
+        .filter(|segment| segment.vaddr < end && segment.vaddr + segment.memory_size > start)
+    {
+        let segment_flags = segment.page_table_flags();
+        flags |= segment_flags & PageTableFlags::WRITABLE;
+        if !segment_flags.contains(PageTableFlags::NO_EXECUTE) {
+            flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+    }
+    flags
+}
+
+/// A lock guard that keeps interrupts disabled on the executing CPU while
+/// it is held, so that the lock can also be taken by interrupt handlers
+/// and by the scheduler.
+pub struct IrqSafeGuard<T: 'static> {
+    guard: ManuallyDrop<MutexGuard<'static, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSafeGuard<T> {
+    fn try_lock(mutex: &'static Mutex<T>) -> Option<Self> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match mutex.try_lock() {
+            Some(guard) => Some(IrqSafeGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+            }),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    fn lock(mutex: &'static Mutex<T>) -> Self {
+        loop {
+            if let Some(guard) = Self::try_lock(mutex) {
+                return guard;
+            }
+            // The holder may be waiting for this CPU to flush its TLB, and
+            // interrupts may have been disabled before locking
+            while mutex.is_locked() {
+                smp::handle_tlb_shootdown();
+                hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqSafeGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeGuard<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeGuard<T> {
+    fn drop(&mut self) {
+        // Unlock before an interrupt can preempt the holder
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Translates a physical address into its address in the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Maps `size` bytes of device memory starting at `addr` as uncacheable.
+///
+/// The physical memory mapping set up by the bootloader is cacheable and may
+/// not cover device memory at all, so registers get a mapping of their own
+/// in the kernel address space. Returns the virtual address corresponding
+/// to `addr`.
+pub fn map_mmio(addr: PhysAddr, size: usize) -> Result<VirtAddr, VmError> {
+    let start = addr.align_down(Page::<Size4KiB>::SIZE);
+    let size = (addr - start) + size.max(1) as u64;
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    let virt = kernel_space().allocate(size, flags, Backing::Physical(start))?;
+    Ok(virt + (addr - start))
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -41,44 +326,3 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
         None
     }
 }
-
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
@@ -0,0 +1,322 @@
+use super::{phys_to_virt, LOW_MEMORY_END};
+use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::{mem, slice};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Blocks go from a single frame up to `2^MAX_ORDER` frames (4 MiB).
+pub const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Marks the end of a free list.
+const NONE: u64 = u64::MAX;
+
+/// Frame counts of a `BuddyFrameAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Links of a free list, stored at the start of every free block.
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// A buddy allocator for the usable physical memory above 1 MiB.
+///
+/// Free blocks of each order are kept in an intrusive doubly linked list,
+/// reached through the physical memory mapping, so that a block can be
+/// taken out of its list when its buddy is freed. A bitmap per order tells
+/// whether a block is in its free list. The bitmaps are the only metadata,
+/// about two bits per frame, and are stored in frames taken from the usable
+/// memory itself since the heap does not exist yet at boot.
+pub struct BuddyFrameAllocator {
+    /// Address of the block of index 0, aligned to the largest block size.
+    base: u64,
+    frame_count: usize,
+    free_lists: [u64; ORDERS],
+    bitmaps: [&'static mut [u64]; ORDERS],
+    total_frames: usize,
+    free_frames: usize,
+    memory_map: &'static MemoryRegions,
+    next_low: usize,
+}
+
+// The memory map is never modified after boot, so it can be read from any CPU.
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator owning every usable frame of the memory map
+    /// above 1 MiB.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames that are marked as `USABLE` in it are
+    /// really unused, and that the physical memory mapping is set up.
+    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
+        let usable = || usable_ranges(memory_map);
+        let start = usable().map(|(start, _)| start).min().unwrap_or(0);
+        let end = usable().map(|(_, end)| end).max().unwrap_or(0);
+        let base = start & !(block_size(MAX_ORDER) - 1);
+        let frame_count = ((end.max(base) - base) / FRAME_SIZE) as usize;
+
+        let mut words = [0; ORDERS];
+        for (order, words) in words.iter_mut().enumerate() {
+            *words = ((frame_count >> order) + 1).div_ceil(64);
+        }
+        let metadata_size = (words.iter().sum::<usize>() * 8) as u64;
+        let metadata_size = metadata_size.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+        let metadata = usable()
+            .find(|(start, end)| end - start >= metadata_size)
+            .map(|(start, _)| start)
+            .expect("no usable region can hold the frame allocator bitmaps");
+
+        let mut storage = slice::from_raw_parts_mut(
+            phys_to_virt(PhysAddr::new(metadata)).as_mut_ptr::<u64>(),
+            metadata_size as usize / 8,
+        );
+        storage.fill(0);
+        let bitmaps = words.map(|words| {
+            let (bitmap, rest) = mem::take(&mut storage).split_at_mut(words);
+            storage = rest;
+            bitmap
+        });
+
+        let mut allocator = BuddyFrameAllocator {
+            base,
+            frame_count,
+            free_lists: [NONE; ORDERS],
+            bitmaps,
+            total_frames: 0,
+            free_frames: 0,
+            memory_map,
+            next_low: 0,
+        };
+
+        let metadata_end = metadata + metadata_size;
+        for (start, end) in usable() {
+            // Leave out the frames holding the bitmaps
+            if start < metadata_end && metadata < end {
+                allocator.add_range(start, metadata);
+                allocator.add_range(metadata_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            used: self.total_frames - self.free_frames,
+            free: self.free_frames,
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first.
+    ///
+    /// The first frame is aligned to the smallest power of two not below
+    /// `count`, and at most `2^MAX_ORDER` frames can be allocated at once.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let order = order_for(count)?;
+        let index = self.allocate_block(order)?;
+        // Give back the frames rounding up to a whole block added
+        self.free_range(index + count, (1 << order) - count);
+        Some(self.frame(index))
+    }
+
+    /// Frees `count` frames starting at `frame`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// frames were allocated by this allocator and are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        self.free_range(self.index(frame), count);
+    }
+
+    /// Allocates a frame below 1 MiB, e.g. for real mode startup code.
+    ///
+    /// These frames are never freed.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self
+            .memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .flat_map(|region| (region.start..region.end).step_by(FRAME_SIZE as usize))
+            .filter(|&addr| addr != 0 && addr + FRAME_SIZE <= LOW_MEMORY_END)
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .nth(self.next_low);
+        self.next_low += 1;
+        frame
+    }
+
+    fn add_range(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let count = ((end - start) / FRAME_SIZE) as usize;
+        self.total_frames += count;
+        self.free_range(((start - self.base) / FRAME_SIZE) as usize, count);
+    }
+
+    /// Frees a range of frames as the largest aligned blocks it contains.
+    fn free_range(&mut self, mut index: usize, mut count: usize) {
+        while count > 0 {
+            let mut order = 0;
+            while order < MAX_ORDER && index & ((2 << order) - 1) == 0 && 1 << (order + 1) <= count
+            {
+                order += 1;
+            }
+            self.free_block(index, order);
+            index += 1 << order;
+            count -= 1 << order;
+        }
+    }
+
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let available = (order..ORDERS).find(|&order| self.free_lists[order] != NONE)?;
+        let index = self.index_of(self.free_lists[available]);
+        self.remove(index, available);
+
+        // Split the block, freeing the upper halves
+        for order in (order..available).rev() {
+            self.push(index + (1 << order), order);
+        }
+        self.free_frames -= 1 << order;
+        Some(index)
+    }
+
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        debug_assert!(!self.is_free(index, order), "frame freed twice");
+        self.free_frames += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    fn is_free(&self, index: usize, order: usize) -> bool {
+        let bit = index >> order;
+        self.bitmaps[order]
+            .get(bit / 64)
+            .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+    }
+
+    fn set_free(&mut self, index: usize, order: usize, free: bool) {
+        let bit = index >> order;
+        let word = &mut self.bitmaps[order][bit / 64];
+        if free {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let addr = self.addr(index);
+        let head = self.free_lists[order];
+        unsafe {
+            block(addr).write(FreeBlock {
+                next: head,
+                prev: NONE,
+            });
+            if head != NONE {
+                (*block(head)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.set_free(index, order, true);
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let addr = self.addr(index);
+        unsafe {
+            let FreeBlock { next, prev } = block(addr).read();
+            if prev == NONE {
+                self.free_lists[order] = next;
+            } else {
+                (*block(prev)).next = next;
+            }
+            if next != NONE {
+                (*block(next)).prev = prev;
+            }
+        }
+        self.set_free(index, order, false);
+    }
+
+    fn addr(&self, index: usize) -> u64 {
+        self.base + index as u64 * FRAME_SIZE
+    }
+
+    fn index_of(&self, addr: u64) -> usize {
+        ((addr - self.base) / FRAME_SIZE) as usize
+    }
+
+    fn frame(&self, index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(self.addr(index)))
+    }
+
+    fn index(&self, frame: PhysFrame) -> usize {
+        let index = self.index_of(frame.start_address().as_u64());
+        assert!(index < self.frame_count, "frame not owned by the allocator");
+        index
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let index = self.allocate_block(0)?;
+        Some(self.frame(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_block(self.index(frame), 0);
+    }
+}
+
+/// Returns the usable regions of the memory map above 1 MiB, with their
+/// bounds rounded inwards to whole frames.
+fn usable_ranges(memory_map: &'static MemoryRegions) -> impl Iterator<Item = (u64, u64)> {
+    memory_map
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .map(|region| {
+            let start = region.start.max(LOW_MEMORY_END).div_ceil(FRAME_SIZE) * FRAME_SIZE;
+            let end = region.end & !(FRAME_SIZE - 1);
+            (start, end)
+        })
+        .filter(|(start, end)| start < end)
+}
+
+fn block(addr: u64) -> *mut FreeBlock {
+    phys_to_virt(PhysAddr::new(addr)).as_mut_ptr()
+}
+
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the order of the smallest block holding `count` frames.
+fn order_for(count: usize) -> Option<usize> {
+    let order = count.checked_next_power_of_two()?.trailing_zeros() as usize;
+    (count > 0 && order <= MAX_ORDER).then_some(order)
+}
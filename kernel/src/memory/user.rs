@@ -0,0 +1,43 @@
+//! Copies between kernel and user memory.
+//!
+//! With SMAP enabled, the kernel faults when it touches a user page, except
+//! within these helpers.
+
+use crate::{
+    cpu::UserAccess,
+    process::{is_user_range, NotMapped},
+};
+use core::ptr;
+use x86_64::VirtAddr;
+
+/// Copies `dst.len()` bytes from the user memory at `src`.
+///
+/// # Safety
+///
+/// This function is unsafe because the range must be mapped readable in the
+/// active address space, see `AddressSpace::is_accessible`, and stay mapped
+/// during the copy.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), NotMapped> {
+    if !is_user_range(src, dst.len() as u64) {
+        return Err(NotMapped(src));
+    }
+    let _access = UserAccess::allow();
+    ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    Ok(())
+}
+
+/// Copies `src` to the user memory at `dst`.
+///
+/// # Safety
+///
+/// This function is unsafe because the range must be mapped writable in the
+/// active address space, see `AddressSpace::is_accessible`, and stay mapped
+/// during the copy.
+pub unsafe fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), NotMapped> {
+    if !is_user_range(dst, src.len() as u64) {
+        return Err(NotMapped(dst));
+    }
+    let _access = UserAccess::allow();
+    ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len());
+    Ok(())
+}
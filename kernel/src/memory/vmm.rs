@@ -0,0 +1,967 @@
+//! Address spaces made of virtual memory areas.
+//!
+//! Every mapping made through an `AddressSpace` is recorded as a `Vma`, so
+//! that free ranges can be found and unmapping knows which frames belong to
+//! it. The kernel address space manages a window of the kernel half for
+//! device memory, stacks and other mappings made after boot; each process
+//! gets an address space of its own that shares the kernel half.
+//!
+//! Anonymous memory of processes is only backed by frames once it is
+//! accessed, the page fault handler maps the pages through `handle_fault`.
+//! Kernel mappings are always backed right away, since the kernel may touch
+//! them with locks held.
+
+use super::{
+    frame_allocator, kernel_page_table, mapper, phys_to_virt, BuddyFrameAllocator, IrqSafeGuard,
+};
+use crate::{
+    allocator::{
+        slab::{self, SlabCache, SlabSource},
+        Locked,
+    },
+    process::{
+        is_user_range, NotMapped, USER_END, USER_STACK_LIMIT, USER_STACK_SIZE, USER_STACK_TOP,
+        USER_START,
+    },
+    serial_println, smp,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    alloc::Layout,
+    fmt, mem,
+    ops::{Bound, Range},
+    ptr, slice,
+};
+use spin::Mutex;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+            PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the window managed by the kernel address space.
+///
+/// The window lies within a single level 4 entry, which is created at boot
+/// so that the address spaces of processes see every mapping made in it.
+pub const KERNEL_VM_START: u64 = 0x_5555_0000_0000;
+pub const KERNEL_VM_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The boot stack is searched for a guard page up to this size.
+const MAX_BOOT_STACK_SIZE: u64 = 1024 * 1024;
+
+static KERNEL_SPACE: OnceCell<Mutex<AddressSpace>> = OnceCell::uninit();
+
+/// Frames of page tables, kept when an address space is dropped so that the
+/// next one does not take them from the frame allocator one by one.
+static PAGE_TABLES: Locked<SlabCache> = Locked::new(SlabCache::new(
+    "page-tables",
+    PAGE_SIZE as usize,
+    PAGE_SIZE as usize,
+    None,
+));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// No frame is left for the pages or their page tables.
+    OutOfMemory,
+    /// No free range is large enough.
+    OutOfSpace,
+    /// The range is empty, not page aligned or outside of the address space.
+    InvalidRange,
+    /// Part of the range is mapped already.
+    Overlap,
+    /// Part of the range is not mapped.
+    NotMapped,
+    /// The access is not allowed by the flags of the area.
+    Protection,
+}
+
+impl From<MapToError<Size4KiB>> for VmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmError::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => VmError::Overlap,
+        }
+    }
+}
+
+/// What the pages of a `Vma` are mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames, freed when the pages are unmapped.
+    Anonymous,
+    /// The physical memory starting at this page aligned address, such as
+    /// device registers or a framebuffer. It is left alone on unmap.
+    Physical(PhysAddr),
+}
+
+/// A range of pages mapped with the same flags and backing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// Set for stacks, which are extended down to the pages faulting below
+    /// them, up to `USER_STACK_LIMIT`.
+    pub grows_down: bool,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Checks whether the flags of the area allow the access.
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => true,
+            Access::Write => self.flags.contains(PageTableFlags::WRITABLE),
+            Access::Execute => !self.flags.contains(PageTableFlags::NO_EXECUTE),
+        }
+    }
+
+    /// Returns the lowest address the area may cover, once grown.
+    fn lowest(&self) -> u64 {
+        if self.grows_down {
+            self.end().as_u64().saturating_sub(USER_STACK_LIMIT)
+        } else {
+            self.start.as_u64()
+        }
+    }
+
+    /// Returns the part of the area between `start` and `end`.
+    fn slice(&self, start: u64, end: u64) -> Vma {
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(addr) => Backing::Physical(addr + (start - self.start.as_u64())),
+        };
+        Vma {
+            start: VirtAddr::new(start),
+            size: end - start,
+            flags: self.flags,
+            backing,
+            grows_down: self.grows_down,
+        }
+    }
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "instruction fetch"),
+        }
+    }
+}
+
+/// A page fault, decoded from the error code pushed by the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub addr: VirtAddr,
+    pub access: Access,
+    /// The page was present, so the access broke its protection.
+    pub present: bool,
+    /// The access was made in user mode.
+    pub user: bool,
+    /// A reserved bit is set in one of the page table entries.
+    pub malformed: bool,
+}
+
+impl PageFault {
+    pub fn new(addr: VirtAddr, error_code: PageFaultErrorCode) -> Self {
+        let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        PageFault {
+            addr,
+            access,
+            present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            user: error_code.contains(PageFaultErrorCode::USER_MODE),
+            malformed: error_code.contains(PageFaultErrorCode::MALFORMED_TABLE),
+        }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let page = if self.present {
+            "present"
+        } else {
+            "non-present"
+        };
+        let mode = if self.user { "user" } else { "kernel" };
+        write!(
+            f,
+            "{} of {:#x} on a {} page in {} mode",
+            self.access,
+            self.addr.as_u64(),
+            page,
+            mode
+        )?;
+        if self.malformed {
+            write!(f, ", reserved bit set in the page tables")?;
+        }
+        Ok(())
+    }
+}
+
+/// A set of page tables and the areas mapped in them.
+pub struct AddressSpace {
+    page_table: PhysFrame,
+    /// Where areas may be mapped.
+    range: Range<u64>,
+    user: bool,
+    vmas: BTreeMap<u64, Vma>,
+    /// Guard pages below kernel stacks, with the name of the stack.
+    guards: BTreeMap<u64, &'static str>,
+}
+
+impl AddressSpace {
+    /// Creates the address space of a process.
+    ///
+    /// Only the user range is private, the kernel part of the level 4 table
+    /// is copied from the kernel address space.
+    pub fn new() -> Result<Self, VmError> {
+        let frame = PageTableFrames(&mut frame_allocator())
+            .allocate_frame()
+            .ok_or(VmError::OutOfMemory)?;
+
+        let kernel_table = unsafe { &*table_ptr(kernel_page_table()) };
+        let table = unsafe { &mut *table_ptr(frame) };
+        table.zero();
+        for (i, entry) in kernel_table.iter().enumerate() {
+            if !is_user_table_index(i) {
+                table[i] = entry.clone();
+            } else {
+                debug_assert!(entry.is_unused(), "the kernel uses the user range");
+            }
+        }
+
+        Ok(AddressSpace {
+            page_table: frame,
+            range: USER_START..USER_END,
+            user: true,
+            vmas: BTreeMap::new(),
+            guards: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the frame of the level 4 table, to be loaded in CR3.
+    pub fn page_table(&self) -> PhysFrame {
+        self.page_table
+    }
+
+    /// Returns the mapped areas, in ascending order.
+    pub fn regions(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Returns the area containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        let (_, vma) = self.vmas.range(..=addr.as_u64()).next_back()?;
+        vma.contains(addr).then_some(vma)
+    }
+
+    /// Returns the area containing `addr`, or else the closest one.
+    pub fn nearest(&self, addr: VirtAddr) -> Option<&Vma> {
+        let addr = addr.as_u64();
+        let below = self.vmas.range(..=addr).next_back().map(|(_, vma)| vma);
+        let above = self
+            .vmas
+            .range((Bound::Excluded(addr), Bound::Unbounded))
+            .next()
+            .map(|(_, vma)| vma);
+        match (below, above) {
+            (Some(below), Some(above)) => {
+                let distance = addr.saturating_sub(below.end().as_u64() - 1);
+                if distance <= above.start.as_u64() - addr {
+                    Some(below)
+                } else {
+                    Some(above)
+                }
+            }
+            (below, above) => below.or(above),
+        }
+    }
+
+    /// Returns the name of the stack whose guard page contains `addr`.
+    pub fn guarded_stack(&self, addr: VirtAddr) -> Option<&'static str> {
+        self.guards
+            .get(&addr.align_down(PAGE_SIZE).as_u64())
+            .copied()
+    }
+
+    /// Resolves a fault in the user range of a process, mapping the page
+    /// of an anonymous area on first access or growing a stack.
+    ///
+    /// Faults on present pages are protection violations, which are never
+    /// resolved.
+    pub fn handle_fault(&mut self, fault: &PageFault) -> Result<(), VmError> {
+        if !self.user || !is_user_range(fault.addr, 1) {
+            return Err(VmError::InvalidRange);
+        }
+        if fault.present || fault.malformed {
+            return Err(VmError::Protection);
+        }
+        self.fault_in(fault.addr, fault.access)
+    }
+
+    /// Maps `size` bytes at `start`, which must be page aligned. `PRESENT`
+    /// is added to `flags`, and `USER_ACCESSIBLE` in a process.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), VmError> {
+        let Range { start, end } = self.check_range(start, size)?;
+        if let Backing::Physical(addr) = backing {
+            if !addr.is_aligned(PAGE_SIZE) {
+                return Err(VmError::InvalidRange);
+            }
+        }
+        if self.overlapping(start, end).next().is_some() {
+            return Err(VmError::Overlap);
+        }
+
+        let flags = self.page_flags(flags);
+        if !(self.user && backing == Backing::Anonymous) {
+            self.map_pages(start, end, flags, backing)?;
+        }
+        self.vmas.insert(
+            start,
+            Vma {
+                start: VirtAddr::new(start),
+                size: end - start,
+                flags,
+                backing,
+                grows_down: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Maps `size` bytes in a free range and returns its start.
+    ///
+    /// The range is surrounded by unmapped guard pages, which catch stack
+    /// overflows and accesses running past the end of a buffer.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<VirtAddr, VmError> {
+        if size == 0 || size > self.range.end - self.range.start {
+            return Err(VmError::InvalidRange);
+        }
+        let size = align_up(size, PAGE_SIZE);
+
+        let mut start = self.range.start + PAGE_SIZE;
+        for vma in self.vmas.values() {
+            if start + size + PAGE_SIZE <= vma.lowest() {
+                break;
+            }
+            start = start.max(vma.end().as_u64() + PAGE_SIZE);
+        }
+        if start + size + PAGE_SIZE > self.range.end {
+            return Err(VmError::OutOfSpace);
+        }
+
+        let start = VirtAddr::new(start);
+        self.map(start, size, flags, backing)?;
+        Ok(start)
+    }
+
+    /// Unmaps the pages between `start` and `start + size`, splitting the
+    /// areas that are only partly in the range. Anonymous frames are freed.
+    ///
+    /// Pages of the range that are not mapped, or not accessed yet, are
+    /// skipped.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), VmError> {
+        let Range { start, end } = self.check_range(start, size)?;
+        let vmas: Vec<Vma> = self.overlapping(start, end).copied().collect();
+        if vmas.is_empty() {
+            return Ok(());
+        }
+
+        let mut anonymous_pages = 0;
+        for vma in vmas.iter() {
+            let (low, high) = overlap(vma, start, end);
+            if vma.backing == Backing::Anonymous {
+                anonymous_pages += (high - low) / PAGE_SIZE;
+            }
+            self.cut(vma, low, high);
+        }
+
+        // Collected first, the heap cannot be used while the mapper is locked
+        let mut frames = Vec::with_capacity(anonymous_pages as usize);
+        self.with_mapper(|mapper| {
+            for vma in vmas.iter() {
+                let (low, high) = overlap(vma, start, end);
+                for page in page_range(low, high) {
+                    if let Ok((frame, flush)) = mapper.unmap(page) {
+                        flush.ignore();
+                        if vma.backing == Backing::Anonymous {
+                            frames.push(frame);
+                        }
+                    }
+                }
+            }
+        });
+
+        // No CPU may still reach the frames once they are reused
+        smp::flush_tlb_everywhere();
+        let mut frame_allocator = frame_allocator();
+        for frame in frames {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of the pages between `start` and `start + size`,
+    /// which must all be part of an area.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), VmError> {
+        let Range { start, end } = self.check_range(start, size)?;
+        let vmas: Vec<Vma> = self.overlapping(start, end).copied().collect();
+        let covered: u64 = vmas
+            .iter()
+            .map(|vma| {
+                let (low, high) = overlap(vma, start, end);
+                high - low
+            })
+            .sum();
+        if covered != end - start {
+            return Err(VmError::NotMapped);
+        }
+
+        let flags = self.page_flags(flags);
+        for vma in vmas.iter() {
+            let (low, high) = overlap(vma, start, end);
+            self.cut(vma, low, high);
+            self.vmas.insert(
+                low,
+                Vma {
+                    flags,
+                    ..vma.slice(low, high)
+                },
+            );
+        }
+
+        self.with_mapper(|mapper| {
+            for page in page_range(start, end) {
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                    flush.ignore();
+                }
+            }
+        });
+        smp::flush_tlb_everywhere();
+        Ok(())
+    }
+
+    /// Maps the stack of the main thread of a process and returns its top.
+    ///
+    /// The stack starts with `USER_STACK_SIZE` bytes and grows on faults.
+    pub fn map_stack(&mut self) -> Result<VirtAddr, VmError> {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = USER_STACK_TOP - USER_STACK_SIZE;
+        self.map(
+            VirtAddr::new(start),
+            USER_STACK_SIZE,
+            flags,
+            Backing::Anonymous,
+        )?;
+        if let Some(stack) = self.vmas.get_mut(&start) {
+            stack.grows_down = true;
+        }
+        Ok(VirtAddr::new(USER_STACK_TOP))
+    }
+
+    /// Copies `data` to `addr`, failing at the first page that is not part
+    /// of an area.
+    ///
+    /// Works whether or not the address space is active.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), NotMapped> {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written;
+            // Copied through the physical memory mapping, so read-only
+            // pages can be filled as well
+            self.fault_in(addr, Access::Read)
+                .map_err(|_| NotMapped(addr))?;
+            let phys = self
+                .with_mapper(|mapper| mapper.translate_addr(addr))
+                .ok_or(NotMapped(addr))?;
+            let in_page = (PAGE_SIZE - addr.as_u64() % PAGE_SIZE) as usize;
+            let len = in_page.min(data.len() - written);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Checks that the process may access `len` bytes at `addr`.
+    ///
+    /// The pages of the range are mapped if they were not accessed yet, so
+    /// that the kernel can then use it without faulting.
+    pub fn is_accessible(&mut self, addr: VirtAddr, len: u64, writable: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if !self.user || !is_user_range(addr, len) {
+            return false;
+        }
+        let access = if writable {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::<Size4KiB>::containing_address(addr + (len - 1));
+        Page::range_inclusive(first, last)
+            .all(|page| self.fault_in(page.start_address(), access).is_ok())
+    }
+
+    fn kernel() -> Self {
+        AddressSpace {
+            page_table: kernel_page_table(),
+            range: KERNEL_VM_START..KERNEL_VM_START + KERNEL_VM_SIZE,
+            user: false,
+            vmas: BTreeMap::new(),
+            guards: BTreeMap::new(),
+        }
+    }
+
+    /// Checks that `size` bytes at `start` are within the address space, and
+    /// returns the range rounded up to whole pages.
+    fn check_range(&self, start: VirtAddr, size: u64) -> Result<Range<u64>, VmError> {
+        let aligned = start.is_aligned(PAGE_SIZE);
+        let start = start.as_u64();
+        // A size too large to round up is as invalid as an empty one
+        let size = size.checked_next_multiple_of(PAGE_SIZE).unwrap_or(0);
+        let valid = size > 0
+            && aligned
+            && self.range.contains(&start)
+            && size <= self.range.end - start;
+        if !valid {
+            return Err(VmError::InvalidRange);
+        }
+        Ok(start..start + size)
+    }
+
+    /// Returns the areas overlapping `start..end`, in descending order.
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Vma> {
+        self.vmas
+            .range(..end)
+            .rev()
+            .map(|(_, vma)| vma)
+            .take_while(move |vma| vma.end().as_u64() > start)
+    }
+
+    fn page_flags(&self, flags: PageTableFlags) -> PageTableFlags {
+        if self.user {
+            flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+        } else {
+            flags | PageTableFlags::PRESENT
+        }
+    }
+
+    /// Makes sure the page containing `addr` is mapped, if the area it is in
+    /// allows the access.
+    fn fault_in(&mut self, addr: VirtAddr, access: Access) -> Result<(), VmError> {
+        let page = addr.align_down(PAGE_SIZE).as_u64();
+        let vma = match self.find(addr) {
+            Some(vma) => *vma,
+            None => self.grow_stack(page)?,
+        };
+        if !vma.allows(access) {
+            return Err(VmError::Protection);
+        }
+        if self.with_mapper(|mapper| mapper.translate_addr(addr).is_some()) {
+            return Ok(());
+        }
+        let backing = vma.slice(page, page + PAGE_SIZE).backing;
+        self.map_pages(page, page + PAGE_SIZE, vma.flags, backing)
+    }
+
+    /// Extends the stack above `page` down to it and returns the grown area.
+    ///
+    /// A stack does not grow past its limit, nor closer than a guard page to
+    /// the area below it.
+    fn grow_stack(&mut self, page: u64) -> Result<Vma, VmError> {
+        let (_, stack) = self.vmas.range(page..).next().ok_or(VmError::NotMapped)?;
+        let stack = *stack;
+        if !stack.grows_down || page < stack.lowest() {
+            return Err(VmError::NotMapped);
+        }
+        if let Some((_, below)) = self.vmas.range(..page).next_back() {
+            if below.end().as_u64() + PAGE_SIZE > page {
+                return Err(VmError::NotMapped);
+            }
+        }
+
+        self.vmas.remove(&stack.start.as_u64());
+        let grown = Vma {
+            start: VirtAddr::new(page),
+            size: stack.end().as_u64() - page,
+            ..stack
+        };
+        self.vmas.insert(page, grown);
+        Ok(grown)
+    }
+
+    /// Removes `low..high` from `vma`, keeping the parts around it.
+    fn cut(&mut self, vma: &Vma, low: u64, high: u64) {
+        let (start, end) = (vma.start.as_u64(), vma.end().as_u64());
+        self.vmas.remove(&start);
+        if start < low {
+            self.vmas.insert(start, vma.slice(start, low));
+        }
+        if high < end {
+            self.vmas.insert(high, vma.slice(high, end));
+        }
+    }
+
+    /// Runs `f` with the page table of the address space.
+    fn with_mapper<R>(&mut self, f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+        if self.user {
+            let offset = phys_to_virt(PhysAddr::zero());
+            let mut mapper =
+                unsafe { OffsetPageTable::new(&mut *table_ptr(self.page_table), offset) };
+            f(&mut mapper)
+        } else {
+            f(&mut mapper())
+        }
+    }
+
+    /// Maps the pages between `start` and `end`, nothing stays mapped if it
+    /// fails part way.
+    fn map_pages(
+        &mut self,
+        start: u64,
+        end: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), VmError> {
+        let mut parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if self.user {
+            parent_flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+
+        self.with_mapper(|mapper| {
+            let pages = page_range(start, end);
+            for (i, page) in pages.clone().enumerate() {
+                // Locked for each page, so that large mappings do not keep
+                // other CPUs from allocating frames
+                let mut frame_allocator = frame_allocator();
+                let frame = match backing {
+                    Backing::Anonymous => zeroed_frame(&mut frame_allocator),
+                    Backing::Physical(addr) => {
+                        Some(PhysFrame::containing_address(addr + i as u64 * PAGE_SIZE))
+                    }
+                };
+                let result =
+                    frame
+                        .ok_or(MapToError::FrameAllocationFailed)
+                        .and_then(|frame| unsafe {
+                            mapper.map_to_with_table_flags(
+                                page,
+                                frame,
+                                flags,
+                                parent_flags,
+                                &mut PageTableFrames(&mut frame_allocator),
+                            )
+                        });
+                match result {
+                    // Pages that were not present are not in any TLB
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => {
+                        if let (Backing::Anonymous, Some(frame)) = (backing, frame) {
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                        }
+                        for page in pages.take(i) {
+                            let (frame, flush) = mapper.unmap(page).expect("page was just mapped");
+                            flush.ignore();
+                            if backing == Backing::Anonymous {
+                                unsafe { frame_allocator.deallocate_frame(frame) };
+                            }
+                        }
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the frames of a process and the page tables mapping them.
+    ///
+    /// The address space is not active on any CPU anymore, so there is
+    /// nothing to flush.
+    fn drop(&mut self) {
+        if !self.user {
+            return;
+        }
+
+        let vmas = mem::take(&mut self.vmas);
+        let page_table = self.page_table;
+        self.with_mapper(|mapper| {
+            let mut frame_allocator = frame_allocator();
+            for vma in vmas
+                .values()
+                .filter(|vma| vma.backing == Backing::Anonymous)
+            {
+                for page in page_range(vma.start.as_u64(), vma.end().as_u64()) {
+                    if let Ok((frame, flush)) = mapper.unmap(page) {
+                        flush.ignore();
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+            }
+
+            let mut tables = PageTableFrames(&mut frame_allocator);
+            let table = unsafe { &*table_ptr(page_table) };
+            for (i, entry) in table.iter().enumerate() {
+                if let (true, Ok(frame)) = (is_user_table_index(i), entry.frame()) {
+                    unsafe { free_table(frame, 3, &mut tables) };
+                }
+            }
+            unsafe { tables.deallocate_frame(page_table) };
+        });
+    }
+}
+
+/// Creates the level 4 entry of the kernel window and the kernel address
+/// space.
+///
+/// This function is unsafe because it must be called once, after the frame
+/// allocator is set up and before any process is created.
+pub(super) unsafe fn init() {
+    let table = &mut *table_ptr(kernel_page_table());
+    let entry = &mut table[(KERNEL_VM_START >> 39) as usize & 0x1FF];
+    if entry.is_unused() {
+        let frame = frame_allocator()
+            .allocate_frame()
+            .expect("no frame for the kernel address space");
+        (*table_ptr(frame)).zero();
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    KERNEL_SPACE.init_once(|| Mutex::new(AddressSpace::kernel()));
+    slab::register(&PAGE_TABLES);
+}
+
+/// Returns the kernel address space.
+///
+/// Interrupts stay enabled while waiting for the lock but are disabled
+/// while it is held, as with `mapper`.
+pub fn kernel_space() -> IrqSafeGuard<AddressSpace> {
+    IrqSafeGuard::lock(KERNEL_SPACE.get().expect("memory not initialized"))
+}
+
+/// Returns the kernel address space if it is not locked, for fault
+/// handlers that cannot wait.
+pub fn try_kernel_space() -> Option<IrqSafeGuard<AddressSpace>> {
+    IrqSafeGuard::try_lock(KERNEL_SPACE.get()?)
+}
+
+/// Maps a kernel stack of `size` bytes that is never freed, and returns its
+/// top.
+///
+/// The page below the stack stays unmapped, so an overflow faults instead
+/// of corrupting memory, and the fault is reported with `name`.
+pub fn allocate_stack(name: &'static str, size: u64) -> Result<VirtAddr, VmError> {
+    let stack = KernelStack::new(name, size)?;
+    let top = stack.top();
+    mem::forget(stack);
+    Ok(top)
+}
+
+/// A kernel stack with a guard page below it, unmapped when dropped.
+pub struct KernelStack {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl KernelStack {
+    pub fn new(name: &'static str, size: u64) -> Result<Self, VmError> {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let size = align_up(size, PAGE_SIZE);
+        let mut space = kernel_space();
+        // Allocated areas always have a free page below them
+        let start = space.allocate(size, flags, Backing::Anonymous)?;
+        space.guards.insert(start.as_u64() - PAGE_SIZE, name);
+        Ok(KernelStack { start, size })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start.as_mut_ptr(), self.size as usize) }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut space = kernel_space();
+        space.guards.remove(&(self.start.as_u64() - PAGE_SIZE));
+        space
+            .unmap(self.start, self.size)
+            .expect("kernel stack outside of the kernel window");
+    }
+}
+
+/// Registers the page below the stack the kernel was started on as its
+/// guard page, if the bootloader left it unmapped.
+///
+/// Must be called on that stack.
+pub fn guard_boot_stack() {
+    let marker = 0u8;
+    let mut page = VirtAddr::from_ptr(&marker).align_down(PAGE_SIZE);
+    let mut space = kernel_space();
+    let guard = space.with_mapper(|mapper| {
+        for _ in 0..MAX_BOOT_STACK_SIZE / PAGE_SIZE {
+            page -= PAGE_SIZE;
+            if mapper.translate_addr(page).is_none() {
+                return Some(page);
+            }
+        }
+        None
+    });
+    if let Some(guard) = guard {
+        space.guards.insert(guard.as_u64(), "boot stack");
+    } else {
+        serial_println!("WARNING: the boot stack has no guard page");
+    }
+}
+
+fn zeroed_frame(frame_allocator: &mut BuddyFrameAllocator) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_frame()?;
+    let page = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { ptr::write_bytes(page, 0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Returns the frame of a table reached through the physical memory mapping.
+fn table_frame(table: *mut u8) -> PhysFrame {
+    let offset = phys_to_virt(PhysAddr::zero()).as_u64();
+    PhysFrame::containing_address(PhysAddr::new(table as u64 - offset))
+}
+
+/// Frees a page table of the given level and the tables below it. The
+/// frames the tables map must have been unmapped already.
+///
+/// This function is unsafe because nothing may be using the tables anymore,
+/// including the TLB of any CPU.
+unsafe fn free_table(frame: PhysFrame, level: usize, tables: &mut PageTableFrames) {
+    if level > 1 {
+        let table = &*table_ptr(frame);
+        for entry in table.iter() {
+            if let Ok(child) = entry.frame() {
+                free_table(child, level - 1, tables);
+            }
+        }
+    }
+    tables.deallocate_frame(frame);
+}
+
+/// Allocates page tables from `PAGE_TABLES`, whose slabs are taken from the
+/// frame allocator.
+///
+/// Slabs are reached through the physical memory mapping, which is aligned
+/// to 2 MiB, so they stay aligned to their size there.
+struct PageTableFrames<'a>(&'a mut BuddyFrameAllocator);
+
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrames<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let table = PAGE_TABLES.lock().alloc(self);
+        (!table.is_null()).then(|| table_frame(table))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PageTableFrames<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        PAGE_TABLES.lock().dealloc(table_ptr(frame).cast(), self);
+    }
+}
+
+impl SlabSource for PageTableFrames<'_> {
+    fn alloc_slab(&mut self, layout: Layout) -> *mut u8 {
+        let count = layout.size() / PAGE_SIZE as usize;
+        match self.0.allocate_contiguous(count) {
+            Some(frame) => table_ptr(frame).cast(),
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn free_slab(&mut self, ptr: *mut u8, layout: Layout) {
+        let count = layout.size() / PAGE_SIZE as usize;
+        self.0.deallocate_contiguous(table_frame(ptr), count);
+    }
+}
+
+fn is_user_table_index(index: usize) -> bool {
+    let start = (USER_START >> 39) as usize & 0x1FF;
+    let end = ((USER_END - 1) >> 39) as usize & 0x1FF;
+    (start..=end).contains(&index)
+}
+
+fn page_range(start: u64, end: u64) -> impl Iterator<Item = Page<Size4KiB>> + Clone {
+    Page::range(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end)),
+    )
+}
+
+/// Returns the part of `vma` between `start` and `end`.
+fn overlap(vma: &Vma, start: u64, end: u64) -> (u64, u64) {
+    (vma.start.as_u64().max(start), vma.end().as_u64().min(end))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
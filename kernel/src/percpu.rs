@@ -0,0 +1,187 @@
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    future::Future,
+    mem,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    task::Waker,
+};
+use crossbeam_queue::SegQueue;
+use futures_util::task::AtomicWaker;
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    VirtAddr,
+};
+
+pub const MAX_CPUS: usize = 64;
+
+/// Offset of the kernel stack pointer loaded by the syscall entry.
+pub const KERNEL_STACK_OFFSET: usize = mem::offset_of!(PerCpu, kernel_stack);
+/// Offset of the slot the syscall entry saves the user stack pointer to.
+pub const USER_STACK_OFFSET: usize = mem::offset_of!(PerCpu, user_stack);
+
+const NO_TASK: u64 = u64::MAX;
+
+/// A future sent to another CPU to be spawned on its executor.
+pub type RemoteTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Data owned by a single CPU, reached through the GS segment base.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the structure itself, so it can be loaded with a single
+    /// `mov` from `gs:0`. Must stay the first field.
+    self_ptr: *const PerCpu,
+    /// Top of the kernel stack of the running thread.
+    kernel_stack: AtomicU64,
+    user_stack: AtomicU64,
+    id: usize,
+    apic_id: u32,
+    current_task: AtomicU64,
+    run_queue: SegQueue<RemoteTask>,
+    run_queue_waker: AtomicWaker,
+}
+
+// Only the atomics and the lock-free queue are accessed from other CPUs.
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+impl PerCpu {
+    /// Returns the index of the CPU, the bootstrap processor being 0.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Sets the stack the syscall entry switches to, which must be the
+    /// kernel stack of the running thread.
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        self.kernel_stack
+            .store(stack_top.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Returns the id of the task the executor of this CPU is polling, if any.
+    pub fn current_task(&self) -> Option<u64> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn set_current_task(&self, task_id: Option<u64>) {
+        self.current_task
+            .store(task_id.unwrap_or(NO_TASK), Ordering::Relaxed);
+    }
+
+    /// Queues a task to be spawned by the executor running on this CPU.
+    pub fn push_task(&self, task: RemoteTask) {
+        self.run_queue.push(task);
+        self.run_queue_waker.wake();
+    }
+
+    /// Registers the waker of the executor that spawns the queued tasks.
+    pub fn register_run_queue_waker(&self, waker: &Waker) {
+        self.run_queue_waker.register(waker);
+    }
+
+    pub fn pop_task(&self) -> Option<RemoteTask> {
+        self.run_queue.pop()
+    }
+
+    pub fn has_queued_tasks(&self) -> bool {
+        !self.run_queue.is_empty()
+    }
+}
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets up the per-CPU data area of the executing CPU.
+///
+/// Must be called once on every CPU, after the heap has been initialized.
+pub fn init(id: usize, apic_id: u32) {
+    assert!(id < MAX_CPUS, "too many CPUs");
+
+    let cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: ptr::null(),
+        kernel_stack: AtomicU64::new(0),
+        user_stack: AtomicU64::new(0),
+        id,
+        apic_id,
+        current_task: AtomicU64::new(NO_TASK),
+        run_queue: SegQueue::new(),
+        run_queue_waker: AtomicWaker::new(),
+    }));
+    cpu.self_ptr = cpu;
+
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    CPUS[id].store(cpu, Ordering::Release);
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Returns the per-CPU data of the executing CPU.
+///
+/// Panics if `init` has not been called on this CPU.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU data not initialized")
+}
+
+/// Returns the per-CPU data of the executing CPU, if it has been set up.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        // In user mode the GS base is swapped out, an interrupt handler
+        // finds it in the kernel GS base instead
+        let cpu = KernelGsBase::read();
+        return unsafe { cpu.as_ptr::<PerCpu>().as_ref() };
+    }
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:0", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        Some(&*cpu)
+    }
+}
+
+/// Returns the per-CPU data of the CPU with the given index.
+pub fn get(id: usize) -> Option<&'static PerCpu> {
+    let cpu = CPUS.get(id)?.load(Ordering::Acquire);
+    unsafe { cpu.as_ref() }
+}
+
+/// Returns the number of CPUs that have set up their per-CPU data.
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// The GS base registers, which differ between threads running kernel and
+/// user code.
+#[derive(Debug, Clone, Copy)]
+pub struct GsState {
+    gs_base: VirtAddr,
+    kernel_gs_base: VirtAddr,
+}
+
+/// Saves the GS base registers, to be restored with `restore_gs`.
+pub fn save_gs() -> GsState {
+    GsState {
+        gs_base: GsBase::read(),
+        kernel_gs_base: KernelGsBase::read(),
+    }
+}
+
+/// Restores the GS base registers saved with `save_gs` on the same CPU.
+pub fn restore_gs(state: GsState) {
+    GsBase::write(state.gs_base);
+    KernelGsBase::write(state.kernel_gs_base);
+}
+
+/// Points the GS base to the per-CPU data, as expected in kernel mode.
+pub fn reset_gs() {
+    if let Some(cpu) = try_current() {
+        GsBase::write(VirtAddr::from_ptr(cpu));
+        KernelGsBase::write(VirtAddr::zero());
+    }
+}
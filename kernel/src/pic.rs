@@ -6,9 +6,15 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-pub fn init() {
+/// Remaps the legacy PICs and masks all of their lines.
+///
+/// Interrupts are routed through the APIC instead, but the 8259s can still
+/// raise spurious interrupts, so they are first moved away from the vectors
+/// used by CPU exceptions.
+pub fn disable() {
     unsafe {
-        PICS.lock().initialize();
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.disable();
     }
-    x86_64::instructions::interrupts::enable();
-}
\ No newline at end of file
+}
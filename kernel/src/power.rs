@@ -0,0 +1,169 @@
+use crate::{
+    acpi::{self, AddressSpace},
+    hlt_loop, memory, serial_println,
+};
+use core::ptr;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+/// Set in the PM1 control register while the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_PULSE_RESET: u8 = 0xFE;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+/// Turns the machine off by entering the ACPI S5 sleep state.
+///
+/// Halts forever if the firmware does not describe how to enter S5.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    match (acpi::pm1_control_ports(), acpi::s5_sleep_type()) {
+        (Some((pm1a, pm1b)), Some(sleep_type)) => {
+            enable_acpi_mode(pm1a);
+            unsafe {
+                enter_sleep_state(pm1a, sleep_type.a);
+                if let Some(pm1b) = pm1b {
+                    enter_sleep_state(pm1b, sleep_type.b);
+                }
+            }
+            // The machine may need a moment to actually lose power
+            delay(100_000);
+            serial_println!("WARNING: ACPI shutdown failed");
+        }
+        _ => {
+            serial_println!("WARNING: ACPI shutdown is not supported on this machine");
+        }
+    }
+
+    hlt_loop();
+}
+
+/// Restarts the machine.
+///
+/// Tries the reset register of the FADT first, then pulses the reset line
+/// through the 8042 keyboard controller, and triple faults as a last resort.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(reset_register) = fadt.reset_register {
+            unsafe { write_reset_register(reset_register, fadt.reset_value) };
+            delay(100_000);
+        }
+    }
+
+    unsafe { pulse_keyboard_controller_reset() };
+    delay(100_000);
+
+    triple_fault();
+}
+
+/// Hands the power management hardware over from the firmware, if needed.
+fn enable_acpi_mode(pm1a: u16) {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+
+    let mut pm1a_control: Port<u16> = Port::new(pm1a);
+    if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+        return; // already in ACPI mode
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return; // hardware-reduced or ACPI-only machine
+    }
+
+    let mut smi_command: Port<u8> = Port::new(fadt.smi_command_port);
+    unsafe { smi_command.write(fadt.acpi_enable) };
+
+    for _ in 0..1000 {
+        if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+            return;
+        }
+        delay(1000);
+    }
+    serial_println!("WARNING: firmware did not enable ACPI mode");
+}
+
+unsafe fn enter_sleep_state(pm1_control_port: u16, sleep_type: u8) {
+    let mut pm1_control: Port<u16> = Port::new(pm1_control_port);
+    let value = pm1_control.read() & !SLP_TYP_MASK;
+    pm1_control.write(value | ((sleep_type as u16) << SLP_TYP_SHIFT) | SLP_EN);
+}
+
+unsafe fn write_reset_register(register: acpi::GenericAddress, value: u8) {
+    match register.address_space {
+        AddressSpace::SystemIo => {
+            let mut port: Port<u8> = Port::new(register.address as u16);
+            port.write(value);
+        }
+        AddressSpace::SystemMemory => {
+            if let Ok(addr) = memory::map_mmio(PhysAddr::new(register.address), 1) {
+                ptr::write_volatile(addr.as_mut_ptr::<u8>(), value);
+            }
+        }
+        AddressSpace::PciConfig => {
+            // The address encodes the device, function and register offset
+            // of a function on bus 0
+            let device = (register.address >> 32) & 0xFFFF;
+            let function = (register.address >> 16) & 0xFFFF;
+            let offset = register.address & 0xFFFF;
+            let config_address =
+                (1 << 31) | (device << 11) as u32 | (function << 8) as u32 | (offset & 0xFC) as u32;
+
+            let mut address_port: Port<u32> = Port::new(PCI_CONFIG_ADDRESS);
+            let mut data_port: Port<u8> = Port::new(PCI_CONFIG_DATA + (offset & 0b11) as u16);
+            address_port.write(config_address);
+            data_port.write(value);
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+unsafe fn pulse_keyboard_controller_reset() {
+    let mut status: Port<u8> = Port::new(KEYBOARD_CONTROLLER_STATUS);
+    for _ in 0..10_000 {
+        if status.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+            break;
+        }
+        delay(10);
+    }
+    status.write(KEYBOARD_CONTROLLER_PULSE_RESET);
+}
+
+/// Loads an empty IDT and raises an exception, which the CPU can only
+/// answer by resetting itself.
+fn triple_fault() -> ! {
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty_idt);
+    }
+    x86_64::instructions::interrupts::int3();
+
+    hlt_loop();
+}
+
+/// Waits roughly the given number of microseconds.
+///
+/// This does not depend on any timer being set up: writes to the unused
+/// port 0x80 take about a microsecond each.
+fn delay(microseconds: u64) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..microseconds {
+        unsafe { port.write(0) };
+    }
+}
@@ -0,0 +1,178 @@
+//! User processes, each made of an address space and a thread running in
+//! ring 3.
+
+use crate::{
+    gdt, serial_println,
+    thread::{self, Thread},
+};
+use alloc::sync::Arc;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame, VirtAddr};
+
+pub use crate::memory::AddressSpace;
+
+/// Start of the part of each address space that belongs to the process.
+///
+/// The kernel never maps anything in this range, so the rest of the level
+/// 4 table can be shared by every address space.
+pub const USER_START: u64 = 0x0000_6000_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Top of the stack of the main thread of a process.
+pub const USER_STACK_TOP: u64 = USER_END - 4096;
+/// Initial size of the stack, in bytes.
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+/// Size the stack may grow to when the process touches the pages below it.
+pub const USER_STACK_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Exit code of a process killed because of a fault.
+pub const EXIT_CODE_FAULT: i64 = -1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// An address that is not mapped in an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotMapped(pub VirtAddr);
+
+pub struct Process {
+    id: ProcessId,
+    /// Cached, the scheduler cannot lock the address space.
+    page_table: PhysFrame,
+    address_space: Mutex<AddressSpace>,
+    exit_code: Mutex<Option<i64>>,
+}
+
+impl Process {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn address_space(&self) -> &Mutex<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn page_table(&self) -> PhysFrame {
+        self.page_table
+    }
+}
+
+/// A handle to wait for a process to exit.
+pub struct ProcessHandle {
+    process: Arc<Process>,
+    thread: Arc<Thread>,
+}
+
+impl ProcessHandle {
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    /// Blocks until the process exits and returns its exit code.
+    pub fn wait(self) -> i64 {
+        self.thread.wait_for_exit();
+        self.process.exit_code.lock().unwrap_or(EXIT_CODE_FAULT)
+    }
+}
+
+/// Starts a process executing `entry` in ring 3 with the stack pointer set
+/// to `stack_top`.
+pub fn spawn(address_space: AddressSpace, entry: VirtAddr, stack_top: VirtAddr) -> ProcessHandle {
+    let process = Arc::new(Process {
+        id: ProcessId::new(),
+        page_table: address_space.page_table(),
+        address_space: Mutex::new(address_space),
+        exit_code: Mutex::new(None),
+    });
+    let handle = thread::spawn_thread_in(Some(process.clone()), move || unsafe {
+        enter_user_mode(entry, stack_top)
+    });
+    ProcessHandle {
+        process,
+        thread: handle.thread().clone(),
+    }
+}
+
+/// Returns the process the running thread belongs to.
+pub fn current() -> Option<Arc<Process>> {
+    thread::current().and_then(|thread| thread.process().cloned())
+}
+
+/// Ends the process of the running thread.
+pub fn exit(code: i64) -> ! {
+    if let Some(process) = current() {
+        *process.exit_code.lock() = Some(code);
+    }
+    thread::exit();
+}
+
+/// Ends the process of the running thread after a fault in user mode.
+pub fn kill_current(reason: &str) -> ! {
+    let id = current().map_or(0, |process| process.id.as_u64());
+    serial_println!("Process {} killed: {}", id, reason);
+    exit(EXIT_CODE_FAULT);
+}
+
+/// Switches to ring 3, jumping to `entry` with the given stack.
+///
+/// This function is unsafe because `entry` and `stack_top` must be mapped
+/// in the active address space.
+unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let rflags: u64 = 0x202; // interrupts enabled
+
+    interrupts::disable();
+    // The GS base must not be visible to user code, the per-CPU data is
+    // reached through the kernel GS base from now on
+    asm!(
+        "swapgs",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) selectors.user_data.0 as u64,
+        rsp = in(reg) stack_top.as_u64(),
+        rflags = in(reg) rflags,
+        cs = in(reg) selectors.user_code.0 as u64,
+        rip = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
+
+/// Checks that `len` bytes at `addr` are all part of the user range.
+pub fn is_user_range(addr: VirtAddr, len: u64) -> bool {
+    let start = addr.as_u64();
+    start >= USER_START && start.checked_add(len).is_some_and(|end| end <= USER_END)
+}
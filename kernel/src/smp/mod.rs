@@ -0,0 +1,267 @@
+use crate::{
+    acpi, apic, clock, cpu, gdt, interrupts, memory,
+    percpu::{self, RemoteTask},
+    serial_println,
+    task::executor::Executor,
+    syscall, thread, time,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    future::Future,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use trampoline::{
+    ap_trampoline_cpu_id, ap_trampoline_cr3, ap_trampoline_end, ap_trampoline_entry,
+    ap_trampoline_nx, ap_trampoline_stack, ap_trampoline_start,
+};
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+mod trampoline;
+
+/// Size of the kernel stack of each application processor.
+pub const AP_STACK_SIZE: usize = 4096 * 16;
+
+/// How long to wait for an AP to respond to its startup IPIs, in seconds.
+const AP_STARTUP_TIMEOUT: f64 = 0.1;
+
+/// Set by an AP once it no longer needs the trampoline.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+/// Bit `i` is set once the CPU with index `i` is online.
+static ONLINE_MASK: AtomicU64 = AtomicU64::new(1);
+
+/// Set while a CPU waits for the others to flush their TLB.
+static TLB_SHOOTDOWN_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// CPUs that have not flushed their TLB yet for the shootdown in progress.
+static TLB_SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Starts every usable processor listed in the MADT other than the
+/// bootstrap one, whose per-CPU data must already be set up.
+///
+/// Each application processor loads its own GDT and TSS, the shared IDT,
+/// enables its local APIC, starts its scheduler and then runs an executor
+/// worker, which picks up futures sent to it with `spawn_on`.
+pub fn init() {
+    let bsp_apic_id = apic::local_apic().id();
+
+    let ap_apic_ids: Vec<u32> = acpi::apic_ids().filter(|&id| id != bsp_apic_id).collect();
+    if ap_apic_ids.is_empty() {
+        return;
+    }
+
+    let trampoline_frame = memory::frame_allocator()
+        .allocate_low_frame()
+        .expect("no frame below 1 MiB for the AP trampoline");
+    identity_map(trampoline_frame).expect("failed to map the AP trampoline");
+
+    for (i, apic_id) in ap_apic_ids.into_iter().enumerate() {
+        if apic_id > u8::MAX as u32 {
+            serial_println!("WARNING: skipping CPU with x2APIC id {}", apic_id);
+            continue;
+        }
+        let cpu_id = i + 1;
+        if cpu_id >= percpu::MAX_CPUS {
+            serial_println!("WARNING: only {} CPUs are supported", percpu::MAX_CPUS);
+            break;
+        }
+
+        let stack_top = match memory::allocate_stack("AP boot stack", AP_STACK_SIZE as u64) {
+            Ok(stack_top) => stack_top,
+            Err(err) => {
+                serial_println!("WARNING: no stack for CPU {}: {:?}", cpu_id, err);
+                break;
+            }
+        };
+        unsafe { copy_trampoline(trampoline_frame, cpu_id, stack_top) };
+
+        if !start_ap(apic_id, trampoline_frame) {
+            serial_println!("WARNING: CPU with APIC id {} did not start", apic_id);
+        }
+    }
+
+    let expected = acpi::apic_ids().count();
+    let start = clock::uptime();
+    while online_cpus() < expected && clock::uptime() - start < AP_STARTUP_TIMEOUT {
+        time::sleep(0.001);
+    }
+    serial_println!("SMP: {} of {} CPUs online", online_cpus(), expected);
+}
+
+/// Returns the number of CPUs that finished their initialization.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Spawns `future` on the executor of the CPU with the given index.
+///
+/// Returns the future back if there is no such CPU.
+pub fn spawn_on<F>(cpu_id: usize, future: F) -> Result<(), RemoteTask>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let task: RemoteTask = Box::pin(future);
+    match percpu::get(cpu_id) {
+        Some(cpu) => {
+            cpu.push_task(task);
+            Ok(())
+        }
+        None => Err(task),
+    }
+}
+
+/// Interrupts the CPU with the given index, in case it is halted.
+pub fn wake_cpu(cpu_id: usize) {
+    let current = percpu::try_current().map(|cpu| cpu.id());
+    if current == Some(cpu_id) {
+        return;
+    }
+    if let Some(cpu) = percpu::get(cpu_id) {
+        apic::local_apic().send_ipi(cpu.apic_id(), apic::WAKEUP_VECTOR);
+    }
+}
+
+fn identity_map(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = memory::mapper();
+    let mut frame_allocator = memory::frame_allocator();
+    match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Copies the trampoline to `frame` and fills in its parameters.
+///
+/// This function is unsafe because `frame` must not be used for anything
+/// else, and no AP may be executing the previous copy.
+unsafe fn copy_trampoline(frame: PhysFrame, cpu_id: usize, stack_top: VirtAddr) {
+    let start = &raw const ap_trampoline_start;
+    let len = (&raw const ap_trampoline_end).offset_from(start) as usize;
+    assert!(len <= 4096, "AP trampoline does not fit in a page");
+
+    let copy = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    ptr::copy_nonoverlapping(start, copy, len);
+
+    let parameter = |symbol: *const u8| copy.offset(symbol.offset_from(start)) as *mut u64;
+    let (cr3_frame, _) = Cr3::read();
+    // The trampoline loads CR3 while still in protected mode
+    assert!(cr3_frame.start_address().as_u64() <= u32::MAX as u64);
+    parameter(&raw const ap_trampoline_cr3).write_volatile(cr3_frame.start_address().as_u64());
+    parameter(&raw const ap_trampoline_stack).write_volatile(stack_top.as_u64());
+    parameter(&raw const ap_trampoline_entry)
+        .write_volatile(ap_main as extern "C" fn(usize) -> ! as usize as u64);
+    parameter(&raw const ap_trampoline_cpu_id).write_volatile(cpu_id as u64);
+    // Setting EFER.NXE raises #GP on CPUs without no-execute pages
+    parameter(&raw const ap_trampoline_nx).write_volatile(cpu::features().nx as u64);
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to an AP and waits for it to leave
+/// the trampoline.
+fn start_ap(apic_id: u32, trampoline_frame: PhysFrame) -> bool {
+    let local_apic = apic::local_apic();
+    let page = (trampoline_frame.start_address().as_u64() >> 12) as u8;
+
+    AP_STARTED.store(false, Ordering::Release);
+
+    local_apic.send_init(apic_id);
+    time::nanowait(10_000_000);
+
+    for _ in 0..2 {
+        local_apic.send_startup(apic_id, page);
+        time::nanowait(200_000);
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+
+    let start = clock::uptime();
+    while clock::uptime() - start < AP_STARTUP_TIMEOUT {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Flushes the TLB of every online CPU, after page table entries have been
+/// removed or their permissions reduced.
+///
+/// Returns once every CPU has flushed, so the frames that were unmapped can
+/// be freed. The other CPUs must be able to take the interrupt, so this must
+/// not be called while holding a lock they may wait for with interrupts
+/// disabled.
+pub fn flush_tlb_everywhere() {
+    tlb::flush_all();
+    let current = percpu::try_current().map_or(0, |cpu| cpu.id());
+
+    // Another CPU may be waiting for this one, possibly with interrupts
+    // disabled, so its shootdown is handled here until it is done
+    while TLB_SHOOTDOWN_ACTIVE
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        acknowledge_tlb_shootdown(current);
+        core::hint::spin_loop();
+    }
+
+    let targets = ONLINE_MASK.load(Ordering::Acquire) & !(1 << current);
+    TLB_SHOOTDOWN_PENDING.store(targets, Ordering::Release);
+    for cpu in (0..percpu::MAX_CPUS).filter(|id| targets & (1 << id) != 0) {
+        if let Some(cpu) = percpu::get(cpu) {
+            apic::local_apic().send_ipi(cpu.apic_id(), apic::TLB_SHOOTDOWN_VECTOR);
+        }
+    }
+    while TLB_SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    TLB_SHOOTDOWN_ACTIVE.store(false, Ordering::Release);
+}
+
+/// Flushes the TLB of the executing CPU if a shootdown is waiting for it.
+///
+/// Called by the handler of `apic::TLB_SHOOTDOWN_VECTOR`.
+pub fn handle_tlb_shootdown() {
+    let current = percpu::try_current().map_or(0, |cpu| cpu.id());
+    acknowledge_tlb_shootdown(current);
+}
+
+fn acknowledge_tlb_shootdown(cpu_id: usize) {
+    let bit = 1 << cpu_id;
+    if TLB_SHOOTDOWN_PENDING.load(Ordering::Acquire) & bit != 0 {
+        tlb::flush_all();
+        TLB_SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::AcqRel);
+    }
+}
+
+/// Entry point of the application processors, called by the trampoline.
+extern "C" fn ap_main(cpu_id: usize) -> ! {
+    // The stack and parameters have been read, the trampoline can be reused
+    AP_STARTED.store(true, Ordering::Release);
+
+    gdt::init_ap(cpu_id);
+    interrupts::init_idt();
+    cpu::init();
+    apic::init_ap();
+    percpu::init(cpu_id, apic::local_apic().id());
+    thread::init();
+    syscall::init();
+
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    ONLINE_MASK.fetch_or(1 << cpu_id, Ordering::AcqRel);
+    serial_println!("SMP: CPU {} checked in", cpu_id);
+
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
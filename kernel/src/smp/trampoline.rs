@@ -0,0 +1,140 @@
+//! Startup code for the application processors.
+//!
+//! A startup IPI makes an AP execute real mode code at a page-aligned
+//! address below 1 MiB, so this code is copied to such a page before each
+//! AP is started. It switches to protected mode and then to long mode using
+//! the page table of the kernel, and finally calls the entry point given
+//! in the parameter block at its end on the stack given there.
+//!
+//! The code is position independent: the physical base of the copy is
+//! computed from CS and kept in `ebx`, and the pointers used by the far
+//! jumps are patched at runtime.
+
+use core::arch::global_asm;
+
+global_asm!(
+    r#"
+.pushsection .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu_id
+.global ap_trampoline_nx
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    movzwl %ax, %ebx
+    shl $4, %ebx
+
+    lea (ap_gdt - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_gdt_pointer - ap_trampoline_start + 2)
+    lea (ap_protected_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_protected_mode_pointer - ap_trampoline_start)
+
+    lgdtl (ap_gdt_pointer - ap_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(ap_protected_mode_pointer - ap_trampoline_start)
+
+.code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    // Physical address extension
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    mov (ap_trampoline_cr3 - ap_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+
+    // Long mode and, if the CPU has it, no-execute enable, in EFER
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $(1 << 8), %eax
+    testl $1, (ap_trampoline_nx - ap_trampoline_start)(%ebx)
+    jz 2f
+    or $(1 << 11), %eax
+2:
+    wrmsr
+
+    // Paging and write protect
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16)), %eax
+    mov %eax, %cr0
+
+    lea (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_long_mode_pointer - ap_trampoline_start)(%ebx)
+    ljmpl *(ap_long_mode_pointer - ap_trampoline_start)(%ebx)
+
+.code64
+ap_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    // The upper half of rbx is undefined after the mode switch
+    mov %ebx, %ebx
+    mov (ap_trampoline_stack - ap_trampoline_start)(%rbx), %rsp
+    mov (ap_trampoline_cpu_id - ap_trampoline_start)(%rbx), %rdi
+    mov (ap_trampoline_entry - ap_trampoline_start)(%rbx), %rax
+    xor %rbp, %rbp
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+.balign 16
+ap_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF // 0x08: 32-bit code
+    .quad 0x00CF92000000FFFF // 0x10: data
+    .quad 0x00AF9A000000FFFF // 0x18: 64-bit code
+ap_gdt_end:
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long 0
+ap_protected_mode_pointer:
+    .long 0
+    .word 0x08
+ap_long_mode_pointer:
+    .long 0
+    .word 0x18
+
+.balign 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu_id:
+    .quad 0
+ap_trampoline_nx:
+    .quad 0
+ap_trampoline_end:
+.popsection
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    pub static ap_trampoline_start: u8;
+    pub static ap_trampoline_end: u8;
+    pub static ap_trampoline_cr3: u8;
+    pub static ap_trampoline_stack: u8;
+    pub static ap_trampoline_entry: u8;
+    pub static ap_trampoline_cpu_id: u8;
+    pub static ap_trampoline_nx: u8;
+}
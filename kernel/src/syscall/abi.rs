@@ -0,0 +1,62 @@
+//! The system call ABI shared with user programs.
+//!
+//! The number of the system call goes in `rax` and its arguments in `rdi`,
+//! `rsi`, `rdx`, `r10`, `r8` and `r9`, in that order. The result is returned
+//! in `rax`; values from -4095 to -1 are negated `Error` codes. Only `rcx`
+//! and `r11` are clobbered, by the `syscall` instruction itself.
+//!
+//! Numbers and error codes are never reused or renumbered.
+
+/// System call numbers.
+pub mod number {
+    /// `write(fd, buffer, len) -> written`
+    pub const WRITE: u64 = 0;
+    /// `exit(code) -> !`
+    pub const EXIT: u64 = 1;
+    /// `mmap(len, prot) -> address`, maps zeroed memory anywhere.
+    pub const MMAP: u64 = 2;
+    /// `sleep(nanoseconds)`
+    pub const SLEEP: u64 = 3;
+    /// `time(clock) -> nanoseconds`
+    pub const TIME: u64 = 4;
+    /// `yield()`
+    pub const YIELD: u64 = 5;
+}
+
+/// Protection flags of `mmap`.
+pub mod prot {
+    pub const READ: u64 = 1 << 0;
+    pub const WRITE: u64 = 1 << 1;
+    pub const EXEC: u64 = 1 << 2;
+}
+
+/// Clocks of `time`.
+pub mod clock {
+    /// Nanoseconds since the Unix epoch.
+    pub const REALTIME: u64 = 0;
+    /// Nanoseconds since boot.
+    pub const MONOTONIC: u64 = 1;
+}
+
+/// File descriptors of `write`, both going to the serial port for now.
+pub mod fd {
+    pub const STDOUT: u64 = 1;
+    pub const STDERR: u64 = 2;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Error {
+    NoSuchSyscall = 1,
+    InvalidArgument = 2,
+    BadAddress = 3,
+    BadFileDescriptor = 4,
+    OutOfMemory = 5,
+}
+
+impl Error {
+    /// Returns the value of `rax` reporting this error.
+    pub fn to_raw(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
@@ -0,0 +1,224 @@
+//! The `syscall` entry point of user programs.
+//!
+//! The entry switches to the kernel stack of the running thread, saves the
+//! user registers in a `SyscallFrame` and calls the handler registered for
+//! the number in `rax`. See `abi` for the calling convention.
+
+use crate::{
+    clock, gdt,
+    memory::{self, Backing, VmError},
+    percpu, process,
+    serial::SERIAL1,
+    thread,
+};
+use core::arch::global_asm;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+pub mod abi;
+
+use abi::{number, Error};
+
+type Handler = fn(&SyscallFrame) -> Result<u64, Error>;
+
+const SYSCALL_COUNT: usize = 6;
+/// Bytes `write` copies from the user buffer at a time.
+const WRITE_CHUNK_SIZE: usize = 256;
+
+static HANDLERS: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut handlers: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    handlers[number::WRITE as usize] = Some(sys_write);
+    handlers[number::EXIT as usize] = Some(sys_exit);
+    handlers[number::MMAP as usize] = Some(sys_mmap);
+    handlers[number::SLEEP as usize] = Some(sys_sleep);
+    handlers[number::TIME as usize] = Some(sys_time);
+    handlers[number::YIELD as usize] = Some(sys_yield);
+    handlers
+};
+
+/// The user registers saved by `syscall_entry`, in the order they are pushed.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// Return address, saved by the `syscall` instruction.
+    pub rcx: u64,
+    /// Flags, saved by the `syscall` instruction.
+    pub r11: u64,
+    pub rsp: u64,
+}
+
+// Interrupts are masked on entry until the kernel stack is in place, and
+// again from the end of the handler until `sysretq`, since an interrupt
+// taken on the user stack in ring 0 would trust memory the process owns.
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    push qword ptr gs:[{user_stack}]
+    push r11
+    push rcx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call syscall_dispatch
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rcx
+    pop r11
+    cli
+    pop rsp
+    swapgs
+    sysretq
+"#,
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables the `syscall` instruction on the executing CPU.
+///
+/// Must be called once on every CPU, after its GDT has been loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT is not laid out for syscall/sysret");
+    LStar::write(VirtAddr::new(
+        syscall_entry as unsafe extern "C" fn() as usize as u64,
+    ));
+    // Clearing AC keeps user code from turning off SMAP for the kernel
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &SyscallFrame) -> u64 {
+    interrupts::enable();
+
+    let handler = HANDLERS.get(frame.rax as usize).copied().flatten();
+    match handler.map_or(Err(Error::NoSuchSyscall), |handler| handler(frame)) {
+        Ok(value) => value,
+        Err(err) => err.to_raw(),
+    }
+}
+
+fn sys_write(frame: &SyscallFrame) -> Result<u64, Error> {
+    let (fd, buffer, len) = (frame.rdi, frame.rsi, frame.rdx);
+    if fd != abi::fd::STDOUT && fd != abi::fd::STDERR {
+        return Err(Error::BadFileDescriptor);
+    }
+
+    let process = process::current().ok_or(Error::BadAddress)?;
+    let addr = VirtAddr::try_new(buffer).map_err(|_| Error::BadAddress)?;
+    let mut address_space = process.address_space().lock();
+    if !address_space.is_accessible(addr, len, false) {
+        return Err(Error::BadAddress);
+    }
+
+    let mut buffer = [0; WRITE_CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let chunk = &mut buffer[..(len - written).min(WRITE_CHUNK_SIZE as u64) as usize];
+        // The address space of the process is the active one, and it cannot
+        // change while its lock is held
+        unsafe { memory::copy_from_user(chunk, addr + written) }
+            .map_err(|_| Error::BadAddress)?;
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.lock();
+            for &byte in chunk.iter() {
+                serial.send(byte);
+            }
+        });
+        written += chunk.len() as u64;
+    }
+    Ok(len)
+}
+
+fn sys_exit(frame: &SyscallFrame) -> Result<u64, Error> {
+    process::exit(frame.rdi as i64);
+}
+
+fn sys_mmap(frame: &SyscallFrame) -> Result<u64, Error> {
+    let (len, prot) = (frame.rdi, frame.rsi);
+    if prot & !(abi::prot::READ | abi::prot::WRITE | abi::prot::EXEC) != 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if prot & abi::prot::WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & abi::prot::EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let process = process::current().ok_or(Error::OutOfMemory)?;
+    let addr = process
+        .address_space()
+        .lock()
+        .allocate(len, flags, Backing::Anonymous)
+        .map_err(|err| match err {
+            VmError::InvalidRange => Error::InvalidArgument,
+            _ => Error::OutOfMemory,
+        })?;
+    Ok(addr.as_u64())
+}
+
+fn sys_sleep(frame: &SyscallFrame) -> Result<u64, Error> {
+    thread::sleep(frame.rdi as f64 / 1e9);
+    Ok(0)
+}
+
+fn sys_time(frame: &SyscallFrame) -> Result<u64, Error> {
+    match frame.rdi {
+        abi::clock::REALTIME => Ok((clock::realtime() * 1e9) as u64),
+        abi::clock::MONOTONIC => Ok(clock::uptime_nanoseconds()),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+fn sys_yield(_frame: &SyscallFrame) -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
@@ -1,59 +1,197 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{ready_queue::ReadyQueue, AbortHandle, Priority, Task, TaskId, TaskInfo};
+use crate::{
+    allocator::slab::{ArcCache, CacheArc},
+    clock,
+    clocksource::{self, ClockSource},
+    percpu::{self, RemoteTask},
+    serial_println, smp,
+    thread::{self, Thread},
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    time::Duration,
+};
+use crossbeam_queue::SegQueue;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Number of tasks polled before the executor looks for newly spawned ones.
+const POLL_BUDGET: usize = 32;
+
+/// The spawner of the executor `task::spawn` uses, see `Executor::set_global`.
+static GLOBAL_SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+
+/// The tasks of every executor, see `tasks`.
+static LIVE_TASKS: Mutex<BTreeMap<TaskId, LiveTask>> = Mutex::new(BTreeMap::new());
+
+struct LiveTask {
+    info: CacheArc<TaskInfo>,
+    /// The CPU the executor of the task was created on.
+    cpu_id: Option<usize>,
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    ready_queue: Arc<ReadyQueue>,
+    /// Tasks taken from `ready_queue` that have not been polled yet.
+    ready: [VecDeque<CacheArc<TaskInfo>>; Priority::COUNT],
     waker_cache: BTreeMap<TaskId, Waker>,
+    notifier: Arc<Notifier>,
+    spawned: Arc<SpawnQueue>,
 }
 
 impl Executor {
+    /// Creates an executor for the running thread.
+    ///
+    /// Once the scheduler is running the executor parks its thread while no
+    /// task is ready, otherwise it halts the CPU.
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            ready_queue: Arc::new(ReadyQueue::new()),
+            ready: [const { VecDeque::new() }; Priority::COUNT],
             waker_cache: BTreeMap::new(),
+            notifier: Arc::new(Notifier {
+                thread: thread::current(),
+                cpu_id: percpu::try_current().map(|cpu| cpu.id()),
+            }),
+            spawned: Arc::new(SpawnQueue {
+                tasks: SegQueue::new(),
+                waker: AtomicWaker::new(),
+            }),
+        }
+    }
+
+    /// Returns a handle that spawns tasks on this executor, also once it
+    /// runs and from other threads.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            queue: self.spawned.clone(),
+            priority: Priority::Normal,
         }
     }
 
+    /// Makes this the executor `task::spawn` spawns on, so that code with
+    /// no `Spawner` at hand can still start tasks once it runs.
+    ///
+    /// Panics if an executor was made global already.
+    pub fn set_global(&self) {
+        GLOBAL_SPAWNER
+            .try_init_once(|| self.spawner())
+            .expect("Executor::set_global should only be called once");
+    }
+
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let info = task.info.clone();
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let cpu_id = self.notifier.cpu_id;
+        self.ready_queue.push(&info);
+        LIVE_TASKS.lock().insert(task_id, LiveTask { info, cpu_id });
     }
 
     pub fn run(&mut self) -> ! {
         loop {
+            self.spawn_remote_tasks();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
+    /// Spawns the tasks sent to this CPU with `smp::spawn_on` and those sent
+    /// to this executor through a `Spawner`.
+    fn spawn_remote_tasks(&mut self) {
+        if let Some(cpu) = percpu::try_current() {
+            cpu.register_run_queue_waker(&Waker::from(self.notifier.clone()));
+            while let Some(future) = cpu.pop_task() {
+                self.spawn(Task::new(future));
+            }
+        }
+        self.spawned
+            .waker
+            .register(&Waker::from(self.notifier.clone()));
+        while let Some((future, info)) = self.spawned.tasks.pop() {
+            self.spawn(Task::with_info(info, future));
+        }
+    }
+
+    /// Polls the woken tasks, highest priority first, but no more than
+    /// `POLL_BUDGET` of them. A task woken while others are waiting goes
+    /// behind them, so that it cannot starve its level by waking itself.
     fn run_ready_tasks(&mut self) {
+        let cpu = percpu::try_current();
+        let tsc = clocksource::tsc();
+
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
-            task_queue,
+            ready_queue,
+            ready,
             waker_cache,
+            notifier,
+            ..
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
+        ready_queue.take(ready);
+        for _ in 0..POLL_BUDGET {
+            let info = match ready.iter_mut().find_map(VecDeque::pop_front) {
+                Some(info) => info,
+                None => break,
+            };
+            // Wakeups from now on queue the task again
+            info.queued.store(false, Ordering::Release);
+            let task_id = info.id;
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let poll = if task.info.is_aborted() {
+                Poll::Ready(())
+            } else {
+                let waker = waker_cache.entry(task_id).or_insert_with(|| {
+                    let waker = TaskWaker::waker(&info, ready_queue.clone(), notifier.clone());
+                    task.info.waker.register(&waker);
+                    waker
+                });
+                let mut context = Context::from_waker(waker);
+                if let Some(cpu) = cpu {
+                    cpu.set_current_task(Some(task_id.0));
+                }
+                let start = tsc.map(|tsc| tsc.read());
+                let poll = task.poll(&mut context);
+                if let (Some(tsc), Some(start)) = (tsc, start) {
+                    let cycles = tsc.read() - start;
+                    task.info.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+                }
+                task.info.polls.fetch_add(1, Ordering::Relaxed);
+                if let Some(cpu) = cpu {
+                    cpu.set_current_task(None);
+                }
+                poll
+            };
+            match poll {
                 Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
+                    // task done or aborted -> remove it and its cached waker
+                    LIVE_TASKS.lock().remove(&task_id);
+                    if let Some(task) = tasks.remove(&task_id) {
+                        // The waker refers to the info, which would never be freed
+                        task.info.waker.take();
+                    }
                     waker_cache.remove(&task_id);
                 }
                 Poll::Pending => {}
@@ -61,11 +199,27 @@ impl Executor {
         }
     }
 
+    fn has_ready_tasks(&self) -> bool {
+        !self.ready_queue.is_empty() || self.ready.iter().any(|level| !level.is_empty())
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
+        let has_remote_tasks = || {
+            !self.spawned.tasks.is_empty()
+                || percpu::try_current().is_some_and(|cpu| cpu.has_queued_tasks())
+        };
+        if self.notifier.thread.is_some() {
+            // A wakeup between the check and `park` makes `park` return
+            if !self.has_ready_tasks() && !has_remote_tasks() {
+                thread::park();
+            }
+            return;
+        }
+
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if !self.has_ready_tasks() && !has_remote_tasks() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -73,30 +227,309 @@ impl Executor {
     }
 }
 
+/// Returns the spawner of the global executor, once there is one.
+pub fn global_spawner() -> Option<&'static Spawner> {
+    GLOBAL_SPAWNER.try_get().ok()
+}
+
+/// Tasks sent to an executor through its `Spawner`s.
+struct SpawnQueue {
+    tasks: SegQueue<(RemoteTask, CacheArc<TaskInfo>)>,
+    waker: AtomicWaker,
+}
+
+/// A handle to spawn tasks on an `Executor`, see `Executor::spawner`.
+#[derive(Clone)]
+pub struct Spawner {
+    queue: Arc<SpawnQueue>,
+    priority: Priority,
+}
+
+impl Spawner {
+    /// Returns a spawner giving its tasks the given priority.
+    pub fn with_priority(&self, priority: Priority) -> Spawner {
+        Spawner {
+            queue: self.queue.clone(),
+            priority,
+        }
+    }
+
+    /// Spawns `future` on the executor and returns a handle to await its
+    /// output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_info(TaskInfo::new(None), future)
+    }
+
+    /// Like `spawn`, naming the task.
+    pub fn spawn_named<F>(&self, name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_info(TaskInfo::new(Some(name.into())), future)
+    }
+
+    fn spawn_with_info<F>(&self, info: CacheArc<TaskInfo>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(JoinState {
+            output: Mutex::new(None),
+            waker: AtomicWaker::new(),
+        });
+        let mut completion = Completion {
+            state: state.clone(),
+            finished: false,
+        };
+        info.set_priority(self.priority);
+        let abort_handle = AbortHandle { info: info.clone() };
+        let future: RemoteTask = Box::pin(async move {
+            completion.finish(future.await);
+        });
+        self.queue.tasks.push((future, info));
+        self.queue.waker.wake();
+        JoinHandle {
+            state,
+            abort_handle,
+        }
+    }
+}
+
+struct JoinState<T> {
+    output: Mutex<Option<Result<T, JoinError>>>,
+    waker: AtomicWaker,
+}
+
+/// Owned by the future of a spawned task, tells its `JoinHandle` that the
+/// task is over once dropped, be it finished or aborted.
+struct Completion<T> {
+    state: Arc<JoinState<T>>,
+    finished: bool,
+}
+
+impl<T> Completion<T> {
+    fn finish(&mut self, output: T) {
+        *self.state.output.lock() = Some(Ok(output));
+        self.finished = true;
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            *self.state.output.lock() = Some(Err(JoinError::Aborted));
+        }
+        self.state.waker.wake();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was dropped before it finished, see `AbortHandle`.
+    Aborted,
+}
+
+/// A future resolving to the output of a task spawned with a `Spawner`, or
+/// to `JoinError::Aborted` once the task is aborted.
+///
+/// Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+    abort_handle: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn is_finished(&self) -> bool {
+        self.state.output.lock().is_some()
+    }
+
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort_handle.clone()
+    }
+
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        // Registered first, so that an output stored meanwhile is not missed
+        self.state.waker.register(cx.waker());
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(output),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wakes an idle executor, see `Executor::sleep_if_idle`.
+struct Notifier {
+    thread: Option<Arc<Thread>>,
+    cpu_id: Option<usize>,
+}
+
+impl Notifier {
+    fn notify(&self) {
+        match (&self.thread, self.cpu_id) {
+            (Some(thread), _) => thread.unpark(),
+            (None, Some(cpu_id)) => smp::wake_cpu(cpu_id),
+            (None, None) => {}
+        }
+    }
+}
+
+impl Wake for Notifier {
+    fn wake(self: Arc<Self>) {
+        self.notify();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.notify();
+    }
+}
+
+/// The wakers of every task.
+static TASK_WAKERS: ArcCache<TaskWaker> = ArcCache::new("task-wakers");
+
+/// `Waker::from` only takes an `Arc` of the global allocator, so task wakers
+/// are built around a `CacheArc` with this table.
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    TaskWaker::clone_raw,
+    TaskWaker::wake_raw,
+    TaskWaker::wake_by_ref_raw,
+    TaskWaker::drop_raw,
+);
+
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    info: CacheArc<TaskInfo>,
+    ready_queue: Arc<ReadyQueue>,
+    notifier: Arc<Notifier>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn waker(
+        info: &CacheArc<TaskInfo>,
+        ready_queue: Arc<ReadyQueue>,
+        notifier: Arc<Notifier>,
+    ) -> Waker {
+        let waker = Arc::new_in(
+            TaskWaker {
+                info: info.clone(),
+                ready_queue,
+                notifier,
+            },
+            &TASK_WAKERS,
+        );
+        // The reference is owned by the waker, and dropped by `drop_raw`
+        let (data, _) = Arc::into_raw_with_allocator(waker);
+        unsafe { Waker::from_raw(RawWaker::new(data.cast(), &TASK_WAKER_VTABLE)) }
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let now = clock::uptime_nanoseconds();
+        self.info.last_wake.store(now, Ordering::Relaxed);
+        self.ready_queue.push(&self.info);
+        self.notifier.notify();
+    }
+
+    unsafe fn clone_raw(data: *const ()) -> RawWaker {
+        Arc::increment_strong_count_in(data.cast::<TaskWaker>(), &TASK_WAKERS);
+        RawWaker::new(data, &TASK_WAKER_VTABLE)
+    }
+
+    unsafe fn wake_raw(data: *const ()) {
+        Arc::from_raw_in(data.cast::<TaskWaker>(), &TASK_WAKERS).wake_task();
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        (*data.cast::<TaskWaker>()).wake_task();
+    }
+
+    unsafe fn drop_raw(data: *const ()) {
+        drop(Arc::from_raw_in(data.cast::<TaskWaker>(), &TASK_WAKERS));
     }
 }
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+/// A snapshot of a live task, see `tasks`.
+#[derive(Debug, Clone)]
+pub struct TaskStats {
+    pub id: u64,
+    pub name: Option<String>,
+    pub priority: Priority,
+    /// The CPU of the executor running the task, if known.
+    pub cpu_id: Option<usize>,
+    /// Whether the task is being polled right now.
+    pub running: bool,
+    pub polls: u64,
+    /// Time spent polling the task, zero without a calibrated TSC.
+    pub poll_time: Duration,
+    /// Uptime in nanoseconds of the last wakeup, if it was ever woken.
+    pub last_wake: Option<u64>,
+}
+
+impl fmt::Display for TaskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task {}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        if let Some(cpu_id) = self.cpu_id {
+            write!(f, " on CPU {}", cpu_id)?;
+        }
+        if self.priority != Priority::Normal {
+            write!(f, " at {:?} priority", self.priority)?;
+        }
+        write!(f, ": {} polls in {:?}", self.polls, self.poll_time)?;
+        if let Some(last_wake) = self.last_wake {
+            write!(f, ", last woken at {} ms", last_wake / 1_000_000)?;
+        }
+        if self.running {
+            write!(f, ", running")?;
+        }
+        Ok(())
     }
+}
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+/// Returns the tasks of every executor that have not finished yet.
+pub fn tasks() -> Vec<TaskStats> {
+    let frequency = clocksource::tsc().map_or(0, |tsc| tsc.frequency());
+    let live = LIVE_TASKS.lock();
+    live.values()
+        .map(|LiveTask { info, cpu_id }| {
+            let running = cpu_id
+                .and_then(percpu::get)
+                .is_some_and(|cpu| cpu.current_task() == Some(info.id.0));
+            let cycles = info.poll_cycles.load(Ordering::Relaxed);
+            let poll_time = match frequency {
+                0 => 0,
+                frequency => (cycles as u128 * 1_000_000_000 / frequency as u128) as u64,
+            };
+            let last_wake = info.last_wake.load(Ordering::Relaxed);
+            TaskStats {
+                id: info.id.0,
+                name: info.name.clone(),
+                priority: info.priority(),
+                cpu_id: *cpu_id,
+                running,
+                polls: info.polls.load(Ordering::Relaxed),
+                poll_time: Duration::from_nanos(poll_time),
+                last_wake: (last_wake != 0).then_some(last_wake),
+            }
+        })
+        .collect()
+}
+
+/// Logs every live task, to see what is running and what is stuck.
+pub fn print_tasks() {
+    for task in tasks() {
+        serial_println!("Tasks: {}", task);
     }
 }
@@ -1,7 +1,6 @@
-use crate::serial_println;
-use alloc::boxed::Box;
-use alloc::vec::Vec;
-use conquer_once::spin::OnceCell;
+use crate::{clock, serial_println};
+use alloc::collections::BTreeSet;
+use conquer_once::spin::{Lazy, OnceCell};
 use core::{
     pin::Pin,
     task::{Context, Poll},
@@ -11,15 +10,22 @@ use futures_util::{
     stream::{Stream, StreamExt},
     task::AtomicWaker,
 };
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use sync::broadcast;
+
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+/// How many events a subscriber may fall behind before it misses some.
+const EVENT_CAPACITY: usize = 64;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
-pub static mut LISTENERS: Vec<Box<dyn FnMut(DecodedKey)>> = Vec::new();
+static EVENTS: Lazy<broadcast::Sender<KeyEvent>> =
+    Lazy::new(|| broadcast::channel(EVENT_CAPACITY).0);
 
 /// Called by the keyboard interrupt handler
 /// Must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
+pub fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
             serial_println!("WARNING: scancode queue full; dropping keyboard input");
@@ -68,19 +74,174 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
+/// A key going down or coming back up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// What the key stands for in the US layout with the current modifiers,
+    /// only set when a key other than a modifier goes down.
+    pub key: Option<DecodedKey>,
+    /// The modifiers after this event, so pressing Shift reports it held.
+    pub modifiers: Modifiers,
+    /// Set when the key went down while already held, i.e. it is repeating.
+    pub repeat: bool,
+    /// Uptime in seconds when the event was decoded.
+    pub timestamp: f64,
+}
+
+impl KeyEvent {
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    /// The Windows keys.
+    pub meta: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+impl Default for Modifiers {
+    /// Nothing held, with Num Lock on as keyboards come up.
+    fn default() -> Self {
+        Modifiers {
+            shift: false,
+            ctrl: false,
+            alt: false,
+            meta: false,
+            caps_lock: false,
+            num_lock: true,
+        }
+    }
+}
+
+/// Turns scancodes into key events, keeping track of which keys are held.
+pub struct KeyDecoder {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    pressed: BTreeSet<KeyCode>,
+    modifiers: Modifiers,
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        KeyDecoder {
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+            pressed: BTreeSet::new(),
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    /// Adds a byte received from the keyboard, returning the event it
+    /// completes, if any. Malformed sequences are skipped.
+    pub fn add_byte(&mut self, scancode: u8, timestamp: f64) -> Option<KeyEvent> {
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let (code, state) = (event.code, event.state);
+        let repeat = match state {
+            KeyState::Down => !self.pressed.insert(code),
+            KeyState::Up => {
+                self.pressed.remove(&code);
+                false
+            }
+        };
+
+        match code {
+            // The decoder would toggle the lock again on every repeat
+            KeyCode::CapsLock | KeyCode::NumpadLock if repeat => {
+                return Some(self.event(code, state, None, true, timestamp));
+            }
+            KeyCode::CapsLock if state == KeyState::Down => {
+                self.modifiers.caps_lock = !self.modifiers.caps_lock;
+            }
+            KeyCode::NumpadLock if state == KeyState::Down => {
+                self.modifiers.num_lock = !self.modifiers.num_lock;
+            }
+            _ => {}
+        }
+        let key = self.keyboard.process_keyevent(event);
+        self.modifiers.shift = self.is_held(KeyCode::ShiftLeft, KeyCode::ShiftRight);
+        self.modifiers.ctrl = self.is_held(KeyCode::ControlLeft, KeyCode::ControlRight);
+        self.modifiers.alt = self.is_held(KeyCode::AltLeft, KeyCode::AltRight);
+        self.modifiers.meta = self.is_held(KeyCode::WindowsLeft, KeyCode::WindowsRight);
+        Some(self.event(code, state, key, repeat, timestamp))
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    fn is_held(&self, left: KeyCode, right: KeyCode) -> bool {
+        self.pressed.contains(&left) || self.pressed.contains(&right)
+    }
+
+    fn event(
+        &self,
+        code: KeyCode,
+        state: KeyState,
+        key: Option<DecodedKey>,
+        repeat: bool,
+        timestamp: f64,
+    ) -> KeyEvent {
+        KeyEvent {
+            code,
+            state,
+            key,
+            modifiers: self.modifiers,
+            repeat,
+            timestamp,
+        }
+    }
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Subscribes to the key events decoded from now on.
+///
+/// Every subscription gets each event, and stops receiving them once
+/// dropped. One that falls more than `EVENT_CAPACITY` events behind misses
+/// the oldest ones.
+pub fn subscribe() -> KeyEvents {
+    KeyEvents {
+        receiver: EVENTS.subscribe(),
+    }
+}
+
+pub fn subscriber_count() -> usize {
+    EVENTS.receiver_count()
+}
+
+/// The stream of key events of a subscription.
+pub struct KeyEvents {
+    receiver: broadcast::Receiver<KeyEvent>,
+}
+
+impl Stream for KeyEvents {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Decodes the scancodes from the keyboard interrupt and hands the key
+/// events to the subscribers.
+pub async fn dispatch_key_events() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut decoder = KeyDecoder::new();
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                unsafe {
-                    for callback in &mut LISTENERS {
-                        callback(key);
-                    }
-                }
-            }
+        if let Some(event) = decoder.add_byte(scancode, clock::uptime()) {
+            // Without subscribers the event is simply dropped
+            let _ = EVENTS.send(event);
         }
     }
 }
@@ -1,26 +1,65 @@
-use alloc::boxed::Box;
+use crate::allocator::slab::{ArcCache, CacheArc};
+use alloc::{boxed::Box, string::String, sync::Arc};
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering},
     task::{Context, Poll},
 };
+use executor::JoinHandle;
+use futures_util::task::AtomicWaker;
 
 pub mod executor;
 pub mod keyboard;
 pub mod mouse;
 pub mod simple_executor;
+pub mod timer;
+
+mod ready_queue;
+
+/// Every task has one, shared by its wakers and handles.
+static TASK_INFOS: ArcCache<TaskInfo> = ArcCache::new("task-info");
 
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    info: CacheArc<TaskInfo>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_info(TaskInfo::new(None), future)
+    }
+
+    /// Creates a task with a name, shown when the live tasks are listed.
+    pub fn named(name: impl Into<String>, future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_info(TaskInfo::new(Some(name.into())), future)
+    }
+
+    fn with_info(info: CacheArc<TaskInfo>, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
-            id: TaskId::new(),
+            id: info.id,
             future: Box::pin(future),
+            info,
+        }
+    }
+
+    /// Sets the priority the task is polled with, `Priority::Normal` by
+    /// default.
+    pub fn with_priority(self, priority: Priority) -> Task {
+        self.info.set_priority(priority);
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.info.name.as_deref()
+    }
+
+    /// Returns a handle to cancel the task once it has been spawned.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            info: self.info.clone(),
         }
     }
 
@@ -29,6 +68,114 @@ impl Task {
     }
 }
 
+/// Spawns `future` on the global executor, the one running the kernel
+/// tasks, from any thread.
+///
+/// Panics if there is no global executor yet, see `Executor::set_global`.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    executor::global_spawner()
+        .expect("no global executor to spawn on")
+        .spawn(future)
+}
+
+/// The order ready tasks are polled in: input handling and rendering come
+/// before background work.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::High,
+            1 => Priority::Normal,
+            _ => Priority::Low,
+        }
+    }
+}
+
+/// What is known about a task outside of its future, shared with its
+/// `AbortHandle`s and wakers.
+struct TaskInfo {
+    id: TaskId,
+    name: Option<String>,
+    priority: AtomicU8,
+    /// Whether the task is in the ready queue of its executor.
+    queued: AtomicBool,
+    /// The next task in the ready queue, see `ready_queue`.
+    next: AtomicPtr<TaskInfo>,
+    aborted: AtomicBool,
+    /// Wakes the task once aborted, so that its executor drops it.
+    waker: AtomicWaker,
+    polls: AtomicU64,
+    /// TSC cycles spent polling the task.
+    poll_cycles: AtomicU64,
+    /// Uptime in nanoseconds of the last wakeup, 0 if never woken.
+    last_wake: AtomicU64,
+}
+
+impl TaskInfo {
+    fn new(name: Option<String>) -> CacheArc<Self> {
+        Arc::new_in(
+            TaskInfo {
+                id: TaskId::new(),
+                name,
+                priority: AtomicU8::new(Priority::Normal as u8),
+                queued: AtomicBool::new(false),
+                next: AtomicPtr::new(ptr::null_mut()),
+                aborted: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+                polls: AtomicU64::new(0),
+                poll_cycles: AtomicU64::new(0),
+                last_wake: AtomicU64::new(0),
+            },
+            &TASK_INFOS,
+        )
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::from_u8(self.priority.load(Ordering::Relaxed))
+    }
+
+    fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority as u8, Ordering::Relaxed);
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+/// Cancels a task: its future is dropped instead of being polled again, and
+/// its `JoinHandle`, if it has one, resolves to `JoinError::Aborted`.
+///
+/// A task that is being polled finishes that poll first.
+#[derive(Clone)]
+pub struct AbortHandle {
+    info: CacheArc<TaskInfo>,
+}
+
+impl AbortHandle {
+    pub fn abort(&self) {
+        self.info.aborted.store(true, Ordering::Release);
+        self.info.waker.wake();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.info.is_aborted()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
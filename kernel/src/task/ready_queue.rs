@@ -0,0 +1,75 @@
+//! The queue of tasks an executor has to poll.
+//!
+//! Wakers may run in interrupt handlers, so waking a task must neither
+//! allocate nor fail. Every level is an intrusive stack linking the
+//! `TaskInfo`s themselves: a push is a single compare-and-swap, and a task
+//! is in the queue at most once however often it is woken.
+
+use super::{Priority, TaskInfo, TASK_INFOS};
+use crate::allocator::slab::CacheArc;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+pub(super) struct ReadyQueue {
+    /// Woken tasks of each priority, most recent first.
+    woken: [AtomicPtr<TaskInfo>; Priority::COUNT],
+}
+
+impl ReadyQueue {
+    pub(super) const fn new() -> Self {
+        ReadyQueue {
+            woken: [const { AtomicPtr::new(ptr::null_mut()) }; Priority::COUNT],
+        }
+    }
+
+    /// Queues `task` unless it is queued already.
+    pub(super) fn push(&self, task: &CacheArc<TaskInfo>) {
+        if task.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let head = &self.woken[task.priority() as usize];
+        let node = Arc::into_raw_with_allocator(task.clone()).0 as *mut TaskInfo;
+        let mut next = head.load(Ordering::Relaxed);
+        loop {
+            task.next.store(next, Ordering::Relaxed);
+            match head.compare_exchange_weak(next, node, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    /// Moves the tasks woken since the last call to the back of `ready`,
+    /// in the order they were woken.
+    ///
+    /// Must only be called by the executor owning the queue.
+    pub(super) fn take(&self, ready: &mut [VecDeque<CacheArc<TaskInfo>>; Priority::COUNT]) {
+        for (head, ready) in self.woken.iter().zip(ready) {
+            let start = ready.len();
+            let mut node = head.swap(ptr::null_mut(), Ordering::Acquire);
+            while !node.is_null() {
+                // Every node holds a reference leaked by `push`
+                let task = unsafe { Arc::from_raw_in(node, &TASK_INFOS) };
+                node = task.next.swap(ptr::null_mut(), Ordering::Relaxed);
+                ready.push_back(task);
+            }
+            ready.make_contiguous()[start..].reverse();
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.woken
+            .iter()
+            .all(|head| head.load(Ordering::Acquire).is_null())
+    }
+}
+
+impl Drop for ReadyQueue {
+    fn drop(&mut self) {
+        let mut ready = [const { VecDeque::new() }; Priority::COUNT];
+        self.take(&mut ready);
+    }
+}
@@ -0,0 +1,134 @@
+//! Futures waiting for the uptime clock, for tasks that would otherwise
+//! block their executor with `time::sleep`.
+
+use crate::{
+    clock,
+    timer::{self, TimerId},
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+
+/// A future completing once `clock::uptime_nanoseconds` reaches its
+/// deadline.
+pub struct Timer {
+    deadline: u64,
+    registration: Option<(TimerId, Waker)>,
+}
+
+impl Timer {
+    /// Creates a timer for the given uptime, in nanoseconds.
+    pub fn at(deadline: u64) -> Self {
+        Timer {
+            deadline,
+            registration: None,
+        }
+    }
+
+    /// Creates a timer for `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        Timer::at(deadline_after(duration))
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Completes once the deadline is reached, registering the waker of
+    /// `cx` with the timer of the executing CPU until then.
+    fn poll_deadline(&mut self, cx: &mut Context) -> Poll<()> {
+        if clock::uptime_nanoseconds() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        let registered =
+            matches!(&self.registration, Some((_, waker)) if waker.will_wake(cx.waker()));
+        if !registered {
+            self.cancel();
+            let id = timer::add(self.deadline, cx.waker().clone());
+            self.registration = Some((id, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+
+    fn cancel(&mut self) {
+        if let Some((id, _)) = self.registration.take() {
+            timer::cancel(id);
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.poll_deadline(cx)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Waits for `duration` without blocking the executor.
+pub fn sleep(duration: Duration) -> Timer {
+    Timer::after(duration)
+}
+
+/// Waits until the given uptime, in nanoseconds.
+pub fn sleep_until(deadline: u64) -> Timer {
+    Timer::at(deadline)
+}
+
+/// A stream yielding the uptime, in nanoseconds, every `period`.
+///
+/// Ticks missed because the task was not polled in time are skipped rather
+/// than yielded in a burst.
+pub struct Interval {
+    period: u64,
+    timer: Timer,
+}
+
+/// Creates an interval whose first tick is one `period` from now.
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+    Interval {
+        period: u64::try_from(period.as_nanos()).unwrap_or(u64::MAX),
+        timer: Timer::after(period),
+    }
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        Duration::from_nanos(self.period)
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        if self.timer.poll_deadline(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let now = clock::uptime_nanoseconds();
+        let deadline = self.timer.deadline;
+        let missed = now.saturating_sub(deadline) / self.period;
+        let next = (missed + 1).saturating_mul(self.period);
+        self.timer = Timer::at(deadline.saturating_add(next));
+        Poll::Ready(Some(now))
+    }
+}
+
+fn deadline_after(duration: Duration) -> u64 {
+    let nanoseconds = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    clock::uptime_nanoseconds().saturating_add(nanoseconds)
+}
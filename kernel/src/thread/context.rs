@@ -0,0 +1,149 @@
+use crate::cpu;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::{
+    arch::{asm, global_asm},
+    ptr::NonNull,
+};
+
+/// Number of registers pushed by `switch_context` below the return address.
+const SAVED_REGISTERS: usize = 6;
+
+/// Alignment `xsave` requires, `fxsave` only needs 16 bytes.
+const EXTENDED_STATE_ALIGN: usize = 64;
+/// Offsets of the x87 control word and of MXCSR in the save area.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// Values set by `fninit` and at reset: every exception is masked.
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+// Only the callee-saved registers need to be saved: the caller of
+// `switch_context` has already saved the others, as for any function call.
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    /// Saves the stack pointer of the running thread to `previous_rsp` and
+    /// resumes the thread whose stack pointer is `next_rsp`.
+    ///
+    /// Returns once another thread switches back to the saved one.
+    pub fn switch_context(previous_rsp: *mut u64, next_rsp: u64);
+}
+
+/// Prepares `stack` so that switching to it calls `entry`, and returns the
+/// stack pointer to resume.
+pub fn initial_stack(stack: &mut [u8], entry: extern "C" fn() -> !) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+    let mut rsp = top as *mut u64;
+    unsafe {
+        // Fake return address of `entry`, which keeps the stack aligned
+        // as if `entry` had been called
+        rsp = rsp.sub(1);
+        rsp.write(0);
+        rsp = rsp.sub(1);
+        rsp.write(entry as usize as u64);
+        for _ in 0..SAVED_REGISTERS {
+            rsp = rsp.sub(1);
+            rsp.write(0);
+        }
+    }
+    rsp as u64
+}
+
+/// The x87, SSE and AVX registers of a thread while it is not running.
+///
+/// They are switched eagerly together with the other registers, as the
+/// kernel never uses them itself.
+pub struct ExtendedState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+// The area is only accessed by the scheduler of the thread's CPU.
+unsafe impl Send for ExtendedState {}
+
+impl ExtendedState {
+    /// Allocates a save area holding the initial state of the registers.
+    pub fn new() -> Self {
+        let layout =
+            Layout::from_size_align(cpu::extended_state_size(), EXTENDED_STATE_ALIGN).unwrap();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        // The header after the legacy area stays zeroed, which makes
+        // `xrstor` load the initial state of the other components
+        unsafe {
+            area.as_ptr()
+                .add(FCW_OFFSET)
+                .cast::<u16>()
+                .write(FCW_DEFAULT);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(MXCSR_DEFAULT);
+        }
+        ExtendedState { area, layout }
+    }
+
+    /// Saves the registers of the executing CPU.
+    ///
+    /// This function is unsafe because it must not race with `restore` on
+    /// the same area.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        if cpu::xsave_enabled() {
+            asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack)
+            );
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+
+    /// Loads the saved registers into the executing CPU.
+    ///
+    /// This function is unsafe because the registers of the running code
+    /// are replaced.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        if cpu::xsave_enabled() {
+            asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, readonly)
+            );
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+        }
+    }
+}
+
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
@@ -0,0 +1,359 @@
+//! Preemptive kernel threads.
+//!
+//! Every CPU runs a round-robin scheduler, preempting threads from the
+//! interrupt of its local APIC timer.
+//! A thread always runs on the CPU it was spawned on; new threads are
+//! spread over the CPUs in turn. The context a CPU booted in becomes a
+//! thread as well once `init` has been called on it.
+
+use crate::{clock, memory::KernelStack, percpu, process::Process, time, timer};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    mem,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+mod context;
+mod scheduler;
+
+use context::ExtendedState;
+
+pub use scheduler::tick;
+
+/// Size of the kernel stack of each thread.
+pub const THREAD_STACK_SIZE: usize = 4096 * 16;
+
+/// Every thread that has not exited yet, so that parked threads stay alive.
+static THREADS: Mutex<BTreeMap<ThreadId, Arc<Thread>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Parked,
+    Exited,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    cpu_id: usize,
+    /// Stack pointer saved by `context::switch_context` while the thread
+    /// is not running. Only accessed by the scheduler of its CPU.
+    rsp: UnsafeCell<u64>,
+    /// The SSE and AVX registers, saved and restored like `rsp`.
+    extended_state: UnsafeCell<ExtendedState>,
+    state: Mutex<State>,
+    unpark_token: AtomicBool,
+    exit: Mutex<ExitState>,
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    /// `None` for the threads running on the stack a CPU booted with.
+    stack: Option<KernelStack>,
+    /// The user process the thread belongs to, whose address space it runs in.
+    process: Option<Arc<Process>>,
+}
+
+// `rsp` and `extended_state` are only accessed by one CPU, with interrupts
+// disabled.
+unsafe impl Sync for Thread {}
+
+struct ExitState {
+    exited: bool,
+    joiners: Vec<Arc<Thread>>,
+}
+
+impl Thread {
+    fn new(cpu_id: usize, entry: extern "C" fn() -> !, process: Option<Arc<Process>>) -> Self {
+        let mut stack = KernelStack::new("thread stack", THREAD_STACK_SIZE as u64)
+            .expect("no memory for a thread stack");
+        let rsp = context::initial_stack(stack.as_mut_slice(), entry);
+        Thread {
+            rsp: UnsafeCell::new(rsp),
+            stack: Some(stack),
+            process,
+            ..Thread::from_boot_context(cpu_id)
+        }
+    }
+
+    fn from_boot_context(cpu_id: usize) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            cpu_id,
+            rsp: UnsafeCell::new(0),
+            extended_state: UnsafeCell::new(ExtendedState::new()),
+            state: Mutex::new(State::Running),
+            unpark_token: AtomicBool::new(false),
+            exit: Mutex::new(ExitState {
+                exited: false,
+                joiners: Vec::new(),
+            }),
+            entry: Mutex::new(None),
+            stack: None,
+            process: None,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns the index of the CPU the thread runs on.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
+    /// Wakes the thread if it is blocked in `park`, otherwise makes its
+    /// next call to `park` return immediately.
+    pub fn unpark(self: &Arc<Self>) {
+        if let Some(scheduler) = scheduler::get(self.cpu_id) {
+            scheduler.unpark(self);
+        }
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    /// Returns the top of the kernel stack of the thread, unless it runs on
+    /// the stack its CPU booted with.
+    fn stack_top(&self) -> Option<VirtAddr> {
+        Some(self.stack.as_ref()?.top().align_down(16u64))
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit.lock().exited
+    }
+
+    /// Blocks until the thread exits.
+    pub fn wait_for_exit(&self) {
+        if let Some(current) = current() {
+            let mut exit = self.exit.lock();
+            if !exit.exited {
+                exit.joiners.push(current);
+            }
+        }
+        while !self.has_exited() {
+            park();
+        }
+    }
+}
+
+impl Wake for Thread {
+    fn wake(self: Arc<Self>) {
+        self.unpark();
+    }
+}
+
+/// A handle to wait for a thread to finish and get its result.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.has_exited()
+    }
+
+    /// Blocks until the thread finishes and returns its result.
+    pub fn join(self) -> T {
+        self.thread.wait_for_exit();
+        self.result
+            .lock()
+            .take()
+            .expect("thread exited without a result")
+    }
+}
+
+/// Turns the running context into a thread and starts the scheduler of
+/// the executing CPU.
+///
+/// Must be called once on every CPU, after `percpu::init` and, on the
+/// bootstrap processor, after `time::init`.
+pub fn init() {
+    let cpu_id = percpu::current().id();
+    let boot = Arc::new(Thread::from_boot_context(cpu_id));
+    let idle = Arc::new(Thread::new(cpu_id, idle_entry, None));
+    *idle.state.lock() = State::Ready;
+    register(&boot);
+
+    scheduler::init(boot, idle);
+}
+
+/// Spawns a thread running `f` and returns a handle to join it.
+pub fn spawn_thread<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread_in(None, f)
+}
+
+/// Spawns a thread running `f` on the CPU with index `cpu_id`.
+///
+/// Threads never migrate, so it stays there until it exits. Panics if
+/// threads are not initialized on that CPU.
+pub fn spawn_thread_on<F, T>(cpu_id: usize, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn(cpu_id, None, f)
+}
+
+/// Spawns a thread running `f` in the address space of `process`.
+pub(crate) fn spawn_thread_in<F, T>(process: Option<Arc<Process>>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn(next_cpu(), process, f)
+}
+
+fn spawn<F, T>(cpu_id: usize, process: Option<Arc<Process>>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let thread = Arc::new(Thread::new(cpu_id, thread_entry, process));
+
+    let thread_result = result.clone();
+    *thread.entry.lock() = Some(Box::new(move || {
+        let value = f();
+        *thread_result.lock() = Some(value);
+    }));
+
+    register(&thread);
+    match scheduler::get(cpu_id) {
+        Some(scheduler) => scheduler.add(thread.clone()),
+        None => panic!("threads are not initialized on CPU {}", cpu_id),
+    }
+    JoinHandle { thread, result }
+}
+
+/// Returns the running thread, if the scheduler of this CPU has been set up.
+pub fn current() -> Option<Arc<Thread>> {
+    scheduler::current().map(|scheduler| scheduler.current_thread())
+}
+
+/// Gives up the rest of the time slice of the running thread.
+pub fn yield_now() {
+    match scheduler::current() {
+        Some(scheduler) => interrupts::without_interrupts(|| scheduler.schedule()),
+        None => core::hint::spin_loop(),
+    }
+}
+
+/// Blocks the running thread for the given number of seconds.
+pub fn sleep(seconds: f64) {
+    let scheduler = match scheduler::current() {
+        Some(scheduler) => scheduler,
+        None => return time::sleep(seconds),
+    };
+    let deadline = clock::uptime_nanoseconds() + (seconds * 1e9) as u64;
+    let timer = timer::add(deadline, scheduler.current_thread().into());
+    while clock::uptime_nanoseconds() < deadline {
+        scheduler.park();
+    }
+    timer::cancel(timer);
+}
+
+/// Blocks the running thread until `Thread::unpark` is called on it.
+///
+/// Like its `std` counterpart, this may also return spuriously.
+pub fn park() {
+    match scheduler::current() {
+        Some(scheduler) => scheduler.park(),
+        None => time::halt(),
+    }
+}
+
+/// Picks the CPU of a new thread, going over the CPUs in turn.
+fn next_cpu() -> usize {
+    static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+    let cpus: Vec<usize> = (0..percpu::MAX_CPUS)
+        .filter(|&id| scheduler::get(id).is_some())
+        .collect();
+    if cpus.is_empty() {
+        return 0;
+    }
+    cpus[NEXT_CPU.fetch_add(1, Ordering::Relaxed) % cpus.len()]
+}
+
+fn register(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().insert(thread.id, thread.clone());
+    });
+}
+
+extern "C" fn thread_entry() -> ! {
+    percpu::reset_gs();
+    let scheduler = scheduler::current().expect("thread started without a scheduler");
+    scheduler.finish_switch();
+    let entry = scheduler.current_thread().entry.lock().take();
+    interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+extern "C" fn idle_entry() -> ! {
+    percpu::reset_gs();
+    let scheduler = scheduler::current().expect("thread started without a scheduler");
+    scheduler.finish_switch();
+    loop {
+        interrupts::disable();
+        if scheduler.has_ready_threads() {
+            scheduler.schedule();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Ends the running thread, waking the threads joining it.
+pub fn exit() -> ! {
+    let scheduler = scheduler::current().expect("thread exited without a scheduler");
+    let thread = scheduler.current_thread();
+
+    let joiners = {
+        let mut exit = thread.exit.lock();
+        exit.exited = true;
+        mem::take(&mut exit.joiners)
+    };
+    for joiner in joiners {
+        joiner.unpark();
+    }
+
+    interrupts::disable();
+    THREADS.lock().remove(&thread.id);
+    *thread.state.lock() = State::Exited;
+    drop(thread);
+
+    scheduler.schedule();
+    unreachable!("exited thread was scheduled again");
+}
@@ -0,0 +1,238 @@
+use super::{context, State, Thread};
+use crate::{clock, gdt, memory, percpu, smp, timer};
+use alloc::{collections::VecDeque, sync::Arc};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, registers::control::Cr3};
+
+/// Nanoseconds a thread may run for before being preempted.
+const TIME_SLICE: u64 = 10_000_000;
+
+/// The round-robin scheduler of a single CPU.
+///
+/// The lock is only ever taken with interrupts disabled, and before the
+/// state lock of any thread.
+pub(super) struct Scheduler {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    current: Arc<Thread>,
+    /// Runs whenever no other thread is ready, never queued.
+    idle: Arc<Thread>,
+    run_queue: VecDeque<Arc<Thread>>,
+    /// A thread that exited, whose stack is freed after switching away.
+    dead: Option<Arc<Thread>>,
+    /// Uptime at which the running thread is preempted if others are ready.
+    slice_end: u64,
+}
+
+static SCHEDULERS: [OnceCell<Scheduler>; percpu::MAX_CPUS] =
+    [const { OnceCell::uninit() }; percpu::MAX_CPUS];
+
+/// Sets up the scheduler of the executing CPU, with `boot` as the running
+/// thread, and starts preempting it.
+pub(super) fn init(boot: Arc<Thread>, idle: Arc<Thread>) {
+    let cpu_id = percpu::current().id();
+    SCHEDULERS[cpu_id].init_once(|| Scheduler {
+        inner: Mutex::new(Inner {
+            current: boot,
+            idle,
+            run_queue: VecDeque::new(),
+            dead: None,
+            slice_end: clock::uptime_nanoseconds() + TIME_SLICE,
+        }),
+    });
+    if let Some(scheduler) = SCHEDULERS[cpu_id].get() {
+        scheduler.arm_timer();
+    }
+}
+
+/// Returns the scheduler of the executing CPU, if it has been set up.
+pub(super) fn current() -> Option<&'static Scheduler> {
+    let cpu = percpu::try_current()?;
+    SCHEDULERS[cpu.id()].get()
+}
+
+pub(super) fn get(cpu_id: usize) -> Option<&'static Scheduler> {
+    SCHEDULERS.get(cpu_id)?.get()
+}
+
+impl Scheduler {
+    pub(super) fn current_thread(&self) -> Arc<Thread> {
+        interrupts::without_interrupts(|| self.inner.lock().current.clone())
+    }
+
+    /// Queues a new thread.
+    pub(super) fn add(&self, thread: Arc<Thread>) {
+        let cpu_id = thread.cpu_id;
+        let idle = interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            *thread.state.lock() = State::Ready;
+            inner.run_queue.push_back(thread);
+            Arc::ptr_eq(&inner.current, &inner.idle)
+        });
+        // An idle CPU has no timer interrupt coming
+        if idle {
+            smp::wake_cpu(cpu_id);
+        }
+    }
+
+    /// Makes `thread`, which must belong to this scheduler, runnable if it
+    /// is parked, or makes its next call to `park` return immediately.
+    pub(super) fn unpark(&self, thread: &Arc<Thread>) {
+        let idle = interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            unpark_locked(&mut inner.run_queue, thread);
+            Arc::ptr_eq(&inner.current, &inner.idle)
+        });
+        if idle {
+            smp::wake_cpu(thread.cpu_id);
+        }
+    }
+
+    /// Blocks the running thread until `unpark` is called on it, unless
+    /// that already happened since the last call.
+    pub(super) fn park(&self) {
+        interrupts::without_interrupts(|| {
+            {
+                let inner = self.inner.lock();
+                let thread = &inner.current;
+                if thread.unpark_token.swap(false, Ordering::Acquire) {
+                    return;
+                }
+                *thread.state.lock() = State::Parked;
+            }
+            self.schedule();
+            self.current_thread()
+                .unpark_token
+                .store(false, Ordering::Release);
+        });
+    }
+
+    pub(super) fn has_ready_threads(&self) -> bool {
+        interrupts::without_interrupts(|| !self.inner.lock().run_queue.is_empty())
+    }
+
+    /// Switches to the next ready thread, putting the running one back in
+    /// the queue if it can still run.
+    ///
+    /// Must be called with interrupts disabled.
+    pub(super) fn schedule(&self) {
+        let (previous_rsp, next_rsp, next_stack_top, next_page_table) = {
+            let mut inner = self.inner.lock();
+            inner.slice_end = clock::uptime_nanoseconds() + TIME_SLICE;
+
+            let previous = inner.current.clone();
+            let previous_is_idle = Arc::ptr_eq(&previous, &inner.idle);
+            {
+                let mut state = previous.state.lock();
+                if *state == State::Running {
+                    if inner.run_queue.is_empty() {
+                        return;
+                    }
+                    if !previous_is_idle {
+                        *state = State::Ready;
+                        inner.run_queue.push_back(previous.clone());
+                    }
+                }
+            }
+
+            let next = match inner.run_queue.pop_front() {
+                Some(thread) => thread,
+                None => inner.idle.clone(),
+            };
+            *next.state.lock() = State::Running;
+            if Arc::ptr_eq(&next, &previous) {
+                return;
+            }
+
+            if *previous.state.lock() == State::Exited {
+                inner.dead = Some(previous.clone());
+            }
+            inner.current = next.clone();
+            // The kernel is built without the extended registers, so they
+            // can already be switched here
+            unsafe {
+                (*previous.extended_state.get()).save();
+                (*next.extended_state.get()).restore();
+            }
+            let page_table = match &next.process {
+                Some(process) => process.page_table(),
+                None => memory::kernel_page_table(),
+            };
+            (
+                previous.rsp.get(),
+                unsafe { *next.rsp.get() },
+                next.stack_top(),
+                page_table,
+            )
+        };
+
+        if let Some(stack_top) = next_stack_top {
+            gdt::set_kernel_stack(stack_top);
+            percpu::current().set_kernel_stack(stack_top);
+        }
+        let (page_table, flags) = Cr3::read();
+        if page_table != next_page_table {
+            unsafe { Cr3::write(next_page_table, flags) };
+        }
+
+        // No reference to either thread may live on the stack across the
+        // switch: the previous one never comes back if it exited
+        let gs = percpu::save_gs();
+        unsafe { context::switch_context(previous_rsp, next_rsp) };
+        percpu::restore_gs(gs);
+        self.finish_switch();
+    }
+
+    /// Cleans up after a switch, on the stack of the thread switched to.
+    pub(super) fn finish_switch(&self) {
+        let dead = self.inner.lock().dead.take();
+        drop(dead);
+        self.arm_timer();
+    }
+
+    /// Programs the timer for the next deadline, or for the end of the time
+    /// slice unless the CPU is idle.
+    fn arm_timer(&self) {
+        let slice_end = interrupts::without_interrupts(|| {
+            let inner = self.inner.lock();
+            let idle = Arc::ptr_eq(&inner.current, &inner.idle);
+            (!idle).then_some(inner.slice_end)
+        });
+        timer::arm(slice_end);
+    }
+}
+
+fn unpark_locked(run_queue: &mut VecDeque<Arc<Thread>>, thread: &Arc<Thread>) {
+    thread.unpark_token.store(true, Ordering::Release);
+    let mut state = thread.state.lock();
+    if *state == State::Parked {
+        *state = State::Ready;
+        run_queue.push_back(thread.clone());
+    }
+}
+
+/// Handles a timer interrupt of the executing CPU: wakes whatever waited
+/// for a deadline, preempts the running thread once its time slice is used
+/// up and programs the timer again.
+pub fn tick() {
+    timer::expire();
+    let scheduler = match current() {
+        Some(scheduler) => scheduler,
+        None => return timer::arm(None),
+    };
+
+    let now = clock::uptime_nanoseconds();
+    let preempt = {
+        let inner = scheduler.inner.lock();
+        let idle = Arc::ptr_eq(&inner.current, &inner.idle);
+        (now >= inner.slice_end || idle) && !inner.run_queue.is_empty()
+    };
+    if preempt {
+        scheduler.schedule();
+    }
+    scheduler.arm_timer();
+}
@@ -1,28 +1,23 @@
 use crate::clock;
+use crate::clocksource::{self, ClockEvent, Pit};
 use crate::cmos::CMOS;
 use crate::interrupts::set_irq_handler;
+use crate::timer;
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Waker;
 use x86_64::instructions::interrupts;
-use x86_64::instructions::port::Port;
 
-pub const PIT_FREQUENCY: f64 = 3_579_545.0 / 3.0; // 1_193_181.666 Hz
-const PIT_DIVIDER: usize = 1193;
-const PIT_INTERVAL: f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
+/// Number of PIT interrupts per second, until a better clock source
+/// stops it.
+pub const PIT_TICK_FREQUENCY: u32 = 1000;
 
-static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
-static LAST_RTC_UPDATE: AtomicUsize = AtomicUsize::new(0);
-static CLOCKS_PER_NANOSECOND: AtomicU64 = AtomicU64::new(0);
+/// Uptime in nanoseconds at the last RTC update interrupt.
+static LAST_RTC_UPDATE: AtomicU64 = AtomicU64::new(0);
 
-pub fn ticks() -> usize {
-    PIT_TICKS.load(Ordering::Relaxed)
-}
-
-pub fn time_between_ticks() -> f64 {
-    PIT_INTERVAL
-}
-
-pub fn last_rtc_update() -> usize {
+/// Returns the uptime in nanoseconds at which the RTC last ticked over to a
+/// new second.
+pub fn last_rtc_update() -> u64 {
     LAST_RTC_UPDATE.load(Ordering::Relaxed)
 }
 
@@ -34,68 +29,43 @@ pub fn halt() {
     }
 }
 
-fn rdtsc() -> u64 {
-    unsafe {
-        core::arch::x86_64::_mm_lfence();
-        core::arch::x86_64::_rdtsc()
-    }
-}
-
 pub fn sleep(seconds: f64) {
-    let start = clock::uptime();
-    while clock::uptime() - start < seconds {
+    let deadline = clock::uptime_nanoseconds() + (seconds * 1e9) as u64;
+    // Nothing needs waking, but the timer has to fire to end the halt
+    let timer = timer::add(deadline, Waker::noop().clone());
+    while clock::uptime_nanoseconds() < deadline {
         halt();
     }
+    timer::cancel(timer);
 }
 
+/// Spins for at least the given time, as precisely as the clock source
+/// allows.
 pub fn nanowait(nanoseconds: u64) {
-    let start = rdtsc();
-    let delta = nanoseconds * CLOCKS_PER_NANOSECOND.load(Ordering::Relaxed);
-    while rdtsc() - start < delta {
+    let start = clocksource::nanoseconds();
+    while clocksource::nanoseconds() - start < nanoseconds {
         spin_loop();
     }
 }
 
-/// The frequency divider must be between 0 and 65535, with 0 acting as 65536
-pub fn set_pit_frequency_divider(divider: u16, channel: u8) {
-    interrupts::without_interrupts(|| {
-        let bytes = divider.to_le_bytes();
-        let mut cmd: Port<u8> = Port::new(0x43);
-        let mut data: Port<u8> = Port::new(0x40 + channel as u16);
-        let operating_mode = 6; // Square wave generator
-        let access_mode = 3; // Lobyte + Hibyte
-        unsafe {
-            cmd.write((channel << 6) | (access_mode << 4) | operating_mode);
-            data.write(bytes[0]);
-            data.write(bytes[1]);
-        }
-    });
-}
-
 pub fn pit_interrupt_handler() {
-    PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+    Pit.tick();
 }
 
 pub fn rtc_interrupt_handler() {
-    LAST_RTC_UPDATE.store(ticks(), Ordering::Relaxed);
+    LAST_RTC_UPDATE.store(clock::uptime_nanoseconds(), Ordering::Relaxed);
     CMOS::new().notify_end_of_interrupt();
 }
 
 pub fn init() {
     // PIT timmer
-    let divider = if PIT_DIVIDER < 65536 { PIT_DIVIDER } else { 0 };
-    let channel = 0;
-    set_pit_frequency_divider(divider as u16, channel);
+    Pit.start_periodic(PIT_TICK_FREQUENCY);
     set_irq_handler(0, pit_interrupt_handler);
+    clocksource::register(&Pit);
 
     // RTC timmer
     set_irq_handler(8, rtc_interrupt_handler);
     CMOS::new().enable_update_interrupt();
 
-    // TSC timmer
-    let calibration_time = 250_000; // 0.25 seconds
-    let a = rdtsc();
-    sleep(calibration_time as f64 / 1e6);
-    let b = rdtsc();
-    CLOCKS_PER_NANOSECOND.store((b - a) / calibration_time, Ordering::Relaxed);
+    clocksource::init();
 }
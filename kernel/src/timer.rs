@@ -0,0 +1,119 @@
+//! Deadlines on the uptime clock, each waking a `Waker` once reached.
+//!
+//! Every CPU keeps its own queue, served by its local APIC timer. The
+//! timer does not tick at a fixed rate: it is programmed in one-shot mode
+//! for the earliest deadline, which the scheduler may bring forward to the
+//! end of the time slice of the running thread.
+
+use crate::{
+    clock,
+    clocksource::{ClockEvent, LocalApicTimer},
+    percpu,
+};
+use alloc::collections::BTreeMap;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::Waker,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const NOT_ARMED: u64 = u64::MAX;
+
+/// Deadlines of each CPU, with a sequence number to keep them apart.
+static QUEUES: [Mutex<BTreeMap<(u64, u64), Waker>>; percpu::MAX_CPUS] =
+    [const { Mutex::new(BTreeMap::new()) }; percpu::MAX_CPUS];
+/// Deadline the timer of each CPU is programmed for.
+static ARMED: [AtomicU64; percpu::MAX_CPUS] =
+    [const { AtomicU64::new(NOT_ARMED) }; percpu::MAX_CPUS];
+
+/// A deadline added with `add`, to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    cpu_id: usize,
+    key: (u64, u64),
+}
+
+/// Wakes `waker` from the timer interrupt of the executing CPU once
+/// `clock::uptime_nanoseconds` reaches `deadline`.
+///
+/// The waker must not block, it may be woken with interrupts disabled.
+pub fn add(deadline: u64, waker: Waker) -> TimerId {
+    static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    let cpu_id = cpu_id();
+    let key = (deadline, NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed));
+    interrupts::without_interrupts(|| {
+        QUEUES[cpu_id].lock().insert(key, waker);
+        if deadline < ARMED[cpu_id].load(Ordering::Relaxed) {
+            program(cpu_id, Some(deadline));
+        }
+    });
+    TimerId { cpu_id, key }
+}
+
+/// Removes a deadline, unless it has already been reached.
+///
+/// The timer stays programmed, its interrupt then finds nothing to do.
+pub fn cancel(id: TimerId) {
+    interrupts::without_interrupts(|| {
+        QUEUES[id.cpu_id].lock().remove(&id.key);
+    });
+}
+
+/// Wakes the wakers of the deadlines of the executing CPU that are reached.
+///
+/// Called from the timer interrupt, which leaves the timer disarmed until
+/// the next call to `arm`.
+pub fn expire() {
+    let cpu_id = cpu_id();
+    ARMED[cpu_id].store(NOT_ARMED, Ordering::Relaxed);
+    loop {
+        let now = clock::uptime_nanoseconds();
+        // Woken one at a time, a waker may add another deadline
+        let waker = interrupts::without_interrupts(|| {
+            let mut queue = QUEUES[cpu_id].lock();
+            let entry = queue.first_entry().filter(|entry| entry.key().0 <= now);
+            entry.map(|entry| entry.remove())
+        });
+        match waker {
+            Some(waker) => waker.wake(),
+            None => break,
+        }
+    }
+}
+
+/// Programs the timer of the executing CPU for its earliest deadline, or
+/// for `deadline` if it comes first. Stops the timer if there is neither.
+pub fn arm(deadline: Option<u64>) {
+    let cpu_id = cpu_id();
+    interrupts::without_interrupts(|| {
+        let first = QUEUES[cpu_id].lock().keys().next().map(|&(first, _)| first);
+        let next = match (first, deadline) {
+            (Some(first), Some(deadline)) => Some(first.min(deadline)),
+            (first, deadline) => first.or(deadline),
+        };
+        program(cpu_id, next);
+    });
+}
+
+/// Must be called with interrupts disabled. Does nothing before the timer
+/// is calibrated by `clocksource::init`.
+fn program(cpu_id: usize, deadline: Option<u64>) {
+    if !LocalApicTimer.is_calibrated() {
+        return;
+    }
+    ARMED[cpu_id].store(deadline.unwrap_or(NOT_ARMED), Ordering::Relaxed);
+    match deadline {
+        Some(deadline) => {
+            let delay = deadline.saturating_sub(clock::uptime_nanoseconds());
+            LocalApicTimer.start_one_shot(delay);
+        }
+        None => LocalApicTimer.stop(),
+    }
+}
+
+/// Returns the executing CPU, the bootstrap processor before `percpu::init`.
+fn cpu_id() -> usize {
+    percpu::try_current().map_or(0, |cpu| cpu.id())
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    acpi, apic, clock, clocksource, exit_qemu, serial_print, test_check, time, QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    apic::init();
+    time::init();
+
+    serial_print!("clocksource::best_source_is_used...\t");
+    let sources = clocksource::sources();
+    let current = clocksource::current();
+    let has_hpet = sources.iter().any(|source| source.name() == "hpet");
+    test_check(
+        sources.iter().any(|source| source.name() == "pit")
+            && has_hpet == acpi::hpet().is_some()
+            && sources
+                .iter()
+                .all(|source| source.rating() <= current.rating()),
+    );
+
+    serial_print!("clocksource::uptime_is_monotonic...\t");
+    let mut previous = clock::uptime_nanoseconds();
+    let mut monotonic = true;
+    for _ in 0..10_000 {
+        let now = clock::uptime_nanoseconds();
+        monotonic &= now >= previous;
+        previous = now;
+    }
+    test_check(monotonic);
+
+    serial_print!("clocksource::sources_agree...\t");
+    // The PIT stands still once a better source has taken over
+    let sources: Vec<_> = sources
+        .into_iter()
+        .filter(|source| source.name() != "pit" || current.name() == "pit")
+        .collect();
+    let starts: Vec<u64> = sources.iter().map(|source| source.nanoseconds()).collect();
+    let start = clock::uptime_nanoseconds();
+    time::nanowait(50_000_000);
+    let elapsed = clock::uptime_nanoseconds() - start;
+    // The PIT only counts whole milliseconds
+    let agree = sources.iter().zip(starts).all(|(source, start)| {
+        let source_elapsed = source.nanoseconds() - start;
+        source_elapsed.abs_diff(elapsed) < 3_000_000
+    });
+    test_check(elapsed >= 50_000_000 && agree);
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{cpu, exit_qemu, serial_print, test_check, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    serial_print!("cpu::info_is_decoded...\t");
+    let info = cpu::info();
+    let caches_valid = info
+        .caches()
+        .all(|cache| cache.level >= 1 && cache.size >= u64::from(cache.line_size * cache.ways));
+    test_check(
+        info.vendor().len() == 12
+            && info.family > 0
+            && info.features.tsc
+            && info.features.apic
+            && caches_valid,
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use kernel::{
+    allocator::slab,
+    apic, exit_qemu, percpu, serial_print,
+    task::{
+        self,
+        executor::{self, Executor, JoinError, Spawner},
+        timer, Priority, Task,
+    },
+    test_check, thread, time, QemuExitCode,
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    apic::init();
+    time::init();
+    percpu::init(0, apic::local_apic().id());
+    thread::init();
+
+    let mut executor = Executor::new();
+    executor.set_global();
+    executor.spawn(Task::new(run_tests(executor.spawner())));
+    executor.run();
+}
+
+async fn run_tests(spawner: Spawner) {
+    serial_print!("executor::task_spawns_task...\t");
+    let handle = spawner.spawn(async { 6 * 7 });
+    test_check(handle.await == Ok(42));
+
+    serial_print!("executor::join_waits_for_output...\t");
+    let handle = spawner.spawn(async {
+        timer::sleep(Duration::from_millis(10)).await;
+        "done"
+    });
+    test_check(!handle.is_finished() && handle.await == Ok("done"));
+
+    serial_print!("executor::thread_spawns_task...\t");
+    let thread_spawner = spawner.clone();
+    let handle = thread::spawn_thread(move || thread_spawner.spawn(async { 7 }));
+    test_check(handle.join().await == Ok(7));
+
+    serial_print!("executor::global_spawn_reaches_running_executor...\t");
+    let handle = thread::spawn_thread(|| task::spawn(async { 8 }));
+    test_check(handle.join().await == Ok(8) && task::spawn(async { 9 }).await == Ok(9));
+
+    serial_print!("executor::named_tasks_are_listed...\t");
+    let handle = spawner.spawn_named("waiter", future::pending::<()>());
+    timer::sleep(Duration::from_millis(1)).await;
+    let listed = executor::tasks()
+        .into_iter()
+        .find(|task| task.name.as_deref() == Some("waiter"));
+    test_check(listed.is_some_and(|task| task.polls == 1 && !task.running));
+
+    serial_print!("executor::aborted_task_is_dropped...\t");
+    handle.abort();
+    timer::sleep(Duration::from_millis(1)).await;
+    let listed = executor::tasks()
+        .iter()
+        .any(|task| task.name.as_deref() == Some("waiter"));
+    test_check(handle.abort_handle().is_aborted() && !listed);
+
+    serial_print!("executor::aborted_task_stops_running...\t");
+    static ITERATIONS: AtomicUsize = AtomicUsize::new(0);
+    let handle = spawner.spawn(async {
+        loop {
+            ITERATIONS.fetch_add(1, Ordering::Relaxed);
+            timer::sleep(Duration::from_millis(1)).await;
+        }
+    });
+    timer::sleep(Duration::from_millis(5)).await;
+    handle.abort();
+    timer::sleep(Duration::from_millis(1)).await;
+    let iterations = ITERATIONS.load(Ordering::Relaxed);
+    timer::sleep(Duration::from_millis(5)).await;
+    test_check(iterations > 0 && ITERATIONS.load(Ordering::Relaxed) == iterations);
+
+    serial_print!("executor::aborted_task_join_completes...\t");
+    let handle = spawner.spawn(future::pending::<()>());
+    let abort_handle = handle.abort_handle();
+    let joiner = spawner.spawn(handle);
+    timer::sleep(Duration::from_millis(1)).await;
+    abort_handle.abort();
+    test_check(joiner.await == Ok(Err(JoinError::Aborted)));
+
+    serial_print!("executor::many_wakes_are_queued...\t");
+    let handles: Vec<_> = (0..500)
+        .map(|id| {
+            spawner.spawn(async move {
+                timer::sleep(Duration::from_millis(5)).await;
+                id
+            })
+        })
+        .collect();
+    let mut completed = 0;
+    for (id, handle) in handles.into_iter().enumerate() {
+        if handle.await == Ok(id) {
+            completed += 1;
+        }
+    }
+    test_check(completed == 500);
+
+    serial_print!("executor::high_priority_runs_first...\t");
+    static ORDER: Mutex<Vec<Priority>> = Mutex::new(Vec::new());
+    let handles = [Priority::Low, Priority::Normal, Priority::High].map(|priority| {
+        spawner
+            .with_priority(priority)
+            .spawn(async move { ORDER.lock().push(priority) })
+    });
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    test_check(*ORDER.lock() == [Priority::High, Priority::Normal, Priority::Low]);
+
+    serial_print!("executor::tasks_use_object_caches...\t");
+    let handle = spawner.spawn(future::pending::<()>());
+    timer::sleep(Duration::from_millis(1)).await;
+    let (infos, wakers) = (cache_allocated("task-info"), cache_allocated("task-wakers"));
+    handle.abort();
+    drop(handle);
+    timer::sleep(Duration::from_millis(1)).await;
+    test_check(
+        cache_allocated("task-info") + 1 == infos && cache_allocated("task-wakers") + 1 == wakers,
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
+
+fn cache_allocated(name: &str) -> usize {
+    slab::caches()
+        .find(|cache| cache.name == name)
+        .map_or(0, |cache| cache.allocated)
+}
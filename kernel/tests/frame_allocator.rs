@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    exit_qemu,
+    memory::{self, MAX_ORDER},
+    serial_print, test_check, QemuExitCode,
+};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    serial_print!("frame_allocator::stats_add_up...\t");
+    let stats = memory::frame_stats();
+    test_check(stats.total == stats.used + stats.free && stats.free > 0);
+
+    serial_print!("frame_allocator::free_reuses_frames...\t");
+    let mut frames = [None::<PhysFrame>; 64];
+    let mut allocator = memory::frame_allocator();
+    for frame in frames.iter_mut() {
+        *frame = allocator.allocate_frame();
+    }
+    let distinct = frames
+        .iter()
+        .enumerate()
+        .all(|(i, frame)| frame.is_some() && frames[..i].iter().all(|other| other != frame));
+    let used = allocator.stats().used;
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+    drop(allocator);
+    test_check(distinct && used == stats.used + 64 && memory::frame_stats() == stats);
+
+    serial_print!("frame_allocator::contiguous_is_aligned...\t");
+    let mut allocator = memory::frame_allocator();
+    let start = allocator.allocate_contiguous(5).unwrap();
+    let aligned = start.start_address().as_u64() % (8 * 4096) == 0;
+    let used = allocator.stats().used;
+    unsafe { allocator.deallocate_contiguous(start, 5) };
+    drop(allocator);
+    test_check(aligned && used == stats.used + 5 && memory::frame_stats() == stats);
+
+    serial_print!("frame_allocator::blocks_merge_back...\t");
+    let mut allocator = memory::frame_allocator();
+    let before = allocator.allocate_contiguous(1 << MAX_ORDER);
+    if let Some(block) = before {
+        unsafe { allocator.deallocate_contiguous(block, 1 << MAX_ORDER) };
+    }
+    // Split a large block into single frames and free them again
+    let mut frames = [None::<PhysFrame>; 1 << MAX_ORDER];
+    for frame in frames.iter_mut() {
+        *frame = allocator.allocate_frame();
+    }
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+    let after = allocator.allocate_contiguous(1 << MAX_ORDER);
+    if let Some(block) = after {
+        unsafe { allocator.deallocate_contiguous(block, 1 << MAX_ORDER) };
+    }
+    drop(allocator);
+    test_check(before.is_some() && after.is_some() && memory::frame_stats() == stats);
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
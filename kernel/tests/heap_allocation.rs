@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::{
+        self,
+        slab::{self, CacheStats, ObjectCache},
+        HeapStats, HEAP_INITIAL_SIZE,
+    },
+    exit_qemu, serial_print, test_check, QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    serial_print!("heap_allocation::stats_count_live_allocations...\t");
+    let before = allocator::heap_stats();
+    let boxes: Vec<Box<[u8; 24]>> = (0..10).map(|_| Box::new([0; 24])).collect();
+    let during = allocator::heap_stats();
+    // The vector itself is one more allocation
+    let counted = during.allocator.allocations == before.allocator.allocations + 11
+        && size_class(&during, 24).allocated == size_class(&before, 24).allocated + 10;
+    drop(boxes);
+    let after = allocator::heap_stats();
+    test_check(
+        counted
+            && after.allocator.allocations == before.allocator.allocations
+            && after.allocator.bytes == before.allocator.bytes
+            && size_class(&after, 24).free >= 10,
+    );
+
+    serial_print!("heap_allocation::peak_is_kept...\t");
+    let large = Vec::<u8>::with_capacity(64 * 1024);
+    let peak = allocator::heap_stats().allocator.peak_bytes;
+    drop(large);
+    let stats = allocator::heap_stats();
+    test_check(peak >= 64 * 1024 && stats.allocator.peak_bytes == peak);
+
+    serial_print!("heap_allocation::empty_slabs_are_freed...\t");
+    let before = allocator::heap_stats();
+    let boxes: Vec<Box<[u8; 200]>> = (0..1000).map(|_| Box::new([0; 200])).collect();
+    drop(boxes);
+    let after = allocator::heap_stats();
+    let class = size_class(&after, 200);
+    test_check(
+        class.freed_slabs > size_class(&before, 200).freed_slabs
+            && after.fallback_bytes <= before.fallback_bytes + class.slab_size,
+    );
+
+    serial_print!("heap_allocation::object_cache_reuses_objects...\t");
+    static OBJECTS: ObjectCache<[u64; 5]> = ObjectCache::new("test-objects");
+    let first = OBJECTS.alloc([1; 5]).unwrap();
+    let addr = &*first as *const _ as usize;
+    drop(first);
+    let second = OBJECTS.alloc([2; 5]).unwrap();
+    let reused = &*second as *const _ as usize == addr && *second == [2; 5];
+    let stats = OBJECTS.stats();
+    drop(second);
+    test_check(
+        reused && stats.allocated == 1 && slab::caches().any(|cache| cache.name == "test-objects"),
+    );
+
+    serial_print!("heap_allocation::constructed_objects_keep_state...\t");
+    static COUNTERS: ObjectCache<u64> = ObjectCache::constructed("test-counters");
+    let mut counter = COUNTERS.get().unwrap();
+    let fresh = *counter == 0;
+    *counter = 7;
+    drop(counter);
+    test_check(fresh && *COUNTERS.get().unwrap() == 7);
+
+    serial_print!("heap_allocation::reclaim_frees_empty_slabs...\t");
+    let slabs = OBJECTS.stats().slabs;
+    let freed = slab::reclaim_caches();
+    test_check(slabs == 1 && freed >= OBJECTS.stats().slab_size && OBJECTS.stats().slabs == 0);
+
+    serial_print!("heap_allocation::heap_grows...\t");
+    let before = allocator::heap_stats().size;
+    // As large as the whole heap so far, so it cannot fit without growing
+    let large = vec![0xAB_u8; before.max(2 * HEAP_INITIAL_SIZE)];
+    let after = allocator::heap_stats().size;
+    test_check(
+        after > before && after > HEAP_INITIAL_SIZE && large.iter().all(|&byte| byte == 0xAB),
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
+
+/// Returns the size class objects of `size` bytes are allocated from.
+fn size_class(stats: &HeapStats, size: usize) -> CacheStats {
+    stats
+        .size_classes
+        .into_iter()
+        .find(|class| class.object_size >= size)
+        .expect("no size class is large enough")
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    pin::Pin,
+    task::{Context, Waker},
+    time::Duration,
+};
+use futures_util::stream::{Stream, StreamExt};
+use kernel::{
+    apic, exit_qemu, percpu, serial_print,
+    task::{
+        executor::Executor,
+        keyboard::{self, DecodedKey, KeyCode, KeyDecoder, KeyEvent, KeyState},
+        timer, Task,
+    },
+    test_check, thread, time, QemuExitCode,
+};
+
+entry_point!(main);
+
+// Scancode set 1, releases are the press codes with the top bit set
+const A: u8 = 0x1E;
+const LEFT_SHIFT: u8 = 0x2A;
+const CAPS_LOCK: u8 = 0x3A;
+const RELEASE: u8 = 0x80;
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    serial_print!("keyboard::press_and_release...\t");
+    let mut decoder = KeyDecoder::new();
+    let press = decoder.add_byte(A, 1.0);
+    let release = decoder.add_byte(A | RELEASE, 2.0);
+    test_check(
+        press.is_some_and(|event| {
+            event.code == KeyCode::A
+                && event.is_press()
+                && event.key == Some(DecodedKey::Unicode('a'))
+                && !event.repeat
+                && event.timestamp == 1.0
+        }) && release.is_some_and(|event| event.state == KeyState::Up && event.key.is_none()),
+    );
+
+    serial_print!("keyboard::held_key_repeats...\t");
+    let mut decoder = KeyDecoder::new();
+    let events = [A, A, A | RELEASE, A].map(|scancode| decoder.add_byte(scancode, 0.0));
+    let repeats = events.map(|event| event.map(|event| event.repeat));
+    test_check(repeats == [Some(false), Some(true), Some(false), Some(false)]);
+
+    serial_print!("keyboard::modifiers_are_reported...\t");
+    let mut decoder = KeyDecoder::new();
+    let shift = decoder.add_byte(LEFT_SHIFT, 0.0);
+    let shifted = decoder.add_byte(A, 0.0);
+    decoder.add_byte(LEFT_SHIFT | RELEASE, 0.0);
+    test_check(
+        shift.is_some_and(|event| event.modifiers.shift && event.key.is_none())
+            && shifted.is_some_and(|event| {
+                event.modifiers.shift && event.key == Some(DecodedKey::Unicode('A'))
+            })
+            && !decoder.modifiers().shift,
+    );
+
+    serial_print!("keyboard::repeated_lock_toggles_once...\t");
+    let mut decoder = KeyDecoder::new();
+    for scancode in [CAPS_LOCK, CAPS_LOCK, CAPS_LOCK, CAPS_LOCK | RELEASE] {
+        decoder.add_byte(scancode, 0.0);
+    }
+    let upper = decoder.add_byte(A, 0.0);
+    test_check(
+        decoder.modifiers().caps_lock
+            && upper.is_some_and(|event| event.key == Some(DecodedKey::Unicode('A'))),
+    );
+
+    serial_print!("keyboard::subscriptions_end_on_drop...\t");
+    let before = keyboard::subscriber_count();
+    let mut first = keyboard::subscribe();
+    let second = keyboard::subscribe();
+    let during = keyboard::subscriber_count();
+    drop(second);
+    let mut cx = Context::from_waker(Waker::noop());
+    let pending: core::task::Poll<Option<KeyEvent>> = Pin::new(&mut first).poll_next(&mut cx);
+    test_check(
+        during == before + 2 && keyboard::subscriber_count() == before + 1 && pending.is_pending(),
+    );
+
+    apic::init();
+    time::init();
+    percpu::init(0, apic::local_apic().id());
+    thread::init();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::dispatch_key_events()));
+    executor.spawn(Task::new(run_pipeline_tests()));
+    executor.run();
+}
+
+async fn run_pipeline_tests() {
+    serial_print!("keyboard::events_reach_every_subscriber...\t");
+    let before = keyboard::subscriber_count();
+    let mut first = keyboard::subscribe();
+    let mut second = keyboard::subscribe();
+    let mut dropped = keyboard::subscribe();
+    // Let the dispatcher set up the scancode queue before feeding it
+    timer::sleep(Duration::from_millis(1)).await;
+    keyboard::add_scancode(A);
+    let press = first.next().await;
+    let seen = [second.next().await, dropped.next().await];
+    drop(dropped);
+    keyboard::add_scancode(A | RELEASE);
+    let release = first.next().await;
+    test_check(
+        press.is_some_and(|event| event.code == KeyCode::A && event.is_press())
+            && seen == [press; 2]
+            && release.is_some_and(|event| event.state == KeyState::Up)
+            && second.next().await == release
+            && keyboard::subscriber_count() == before + 2,
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
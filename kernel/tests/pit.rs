@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::hint::spin_loop;
+use kernel::{
+    apic,
+    clocksource::{ClockEvent, ClockSource, Pit},
+    exit_qemu,
+    interrupts::set_irq_handler,
+    serial_print, test_check, time, QemuExitCode,
+};
+
+entry_point!(main);
+
+/// Spins waiting for a PIT interrupt at most this many times, far longer
+/// than the few milliseconds two ticks take.
+const MAX_SPINS: u64 = 1_000_000_000;
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+    apic::init();
+
+    // Without `time::init`, no HPET is set up and the PIT is the only clock,
+    // as on machines that have no HPET
+    serial_print!("pit::ticks_reach_the_handler...\t");
+    Pit.start_periodic(time::PIT_TICK_FREQUENCY);
+    set_irq_handler(0, time::pit_interrupt_handler);
+    let start = Pit.read();
+    let mut spins = 0;
+    while Pit.read() < start + 2 * Pit.divider() && spins < MAX_SPINS {
+        spin_loop();
+        spins += 1;
+    }
+    test_check(Pit.read() >= start + 2 * Pit.divider());
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::{cpu, exit_qemu, memory, serial_print, test_check, QemuExitCode};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+static TABLE: [u64; 4] = [1, 2, 3, 4];
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+    memory::protect_kernel();
+
+    let writable = PageTableFlags::WRITABLE;
+    let no_execute = PageTableFlags::NO_EXECUTE;
+
+    serial_print!("protection::code_is_read_only...\t");
+    let code = flags(VirtAddr::new(
+        main as fn(&'static mut BootInfo) -> ! as usize as u64,
+    ));
+    test_check(!code.contains(writable) && !code.contains(no_execute));
+
+    serial_print!("protection::data_is_not_executable...\t");
+    COUNTER.fetch_add(1, Ordering::Relaxed);
+    let data = flags(VirtAddr::from_ptr(&COUNTER));
+    let rodata = flags(VirtAddr::from_ptr(&TABLE));
+    test_check(
+        data.contains(writable | no_execute)
+            && !rodata.contains(writable)
+            && rodata.contains(no_execute),
+    );
+
+    serial_print!("protection::heap_is_not_executable...\t");
+    let boxed = Box::new(7u64);
+    test_check(flags(VirtAddr::from_ptr(&*boxed)).contains(writable | no_execute));
+
+    serial_print!("protection::cpu_features_are_enabled...\t");
+    let features = cpu::features();
+    let cr4 = Cr4::read();
+    test_check(
+        Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+            && features.smep == cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)
+            && features.smap == cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+            && cpu::smap_enabled() == features.smap,
+    );
+
+    serial_print!("protection::user_copies_check_the_range...\t");
+    let mut buffer = [0; 8];
+    let kernel_addr = VirtAddr::from_ptr(&TABLE);
+    test_check(
+        unsafe { memory::copy_from_user(&mut buffer, kernel_addr) }.is_err()
+            && unsafe { memory::copy_to_user(kernel_addr, &buffer) }.is_err(),
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
+
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    match memory::mapper().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => PageTableFlags::empty(),
+    }
+}
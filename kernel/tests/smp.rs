@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::{
+    acpi, apic, clock, exit_qemu, percpu, serial_print, smp, test_check, time, QemuExitCode,
+};
+
+entry_point!(main);
+
+/// Number of processors QEMU is started with, see the boot runner.
+const EXPECTED_CPUS: usize = 4;
+
+static WORKERS_RAN: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    apic::init();
+    time::init();
+    percpu::init(0, apic::local_apic().id());
+    smp::init();
+
+    serial_print!("smp::all_cpus_checked_in...\t");
+    test_check(smp::online_cpus() == EXPECTED_CPUS && acpi::apic_ids().count() == EXPECTED_CPUS);
+
+    serial_print!("smp::executor_on_every_cpu...\t");
+    for cpu_id in 1..smp::online_cpus() {
+        let spawned = smp::spawn_on(cpu_id, async move {
+            if percpu::current().id() == cpu_id {
+                WORKERS_RAN.fetch_add(1, Ordering::AcqRel);
+            }
+        });
+        assert!(spawned.is_ok());
+    }
+    let start = clock::uptime();
+    while WORKERS_RAN.load(Ordering::Acquire) < EXPECTED_CPUS - 1 && clock::uptime() - start < 1.0 {
+        time::sleep(0.001);
+    }
+    test_check(WORKERS_RAN.load(Ordering::Acquire) == EXPECTED_CPUS - 1);
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    exit_qemu, gdt, interrupts,
+    memory::{self, KernelStack},
+    serial_print, serial_println, test_check, QemuExitCode,
+};
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+    gdt::init_interrupt_stacks();
+    memory::guard_boot_stack();
+
+    serial_print!("stack_guard::stacks_have_named_guard_pages...\t");
+    let stack = KernelStack::new("test stack", 4 * 4096).unwrap();
+    let guard = stack.top() - 4 * 4096u64 - 1u64;
+    let named = memory::kernel_space().guarded_stack(guard) == Some("test stack");
+    let inside = memory::kernel_space().guarded_stack(stack.top() - 1u64);
+    drop(stack);
+    let removed = memory::kernel_space().guarded_stack(guard).is_none();
+    test_check(named && inside.is_none() && removed);
+
+    serial_print!("stack_guard::boot_stack_overflow...\t");
+    TEST_IDT.load();
+    stack_overflow();
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after stack overflow");
+    exit_qemu(QemuExitCode::Failed);
+    kernel::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(&0).read(); // prevent tail recursion optimizations
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(interrupts::PAGE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let stack = memory::try_kernel_space().and_then(|space| space.guarded_stack(Cr2::read()));
+    test_check(stack == Some("boot stack"));
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
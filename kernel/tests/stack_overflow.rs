@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{exit_qemu, gdt, interrupts, serial_print, serial_println, test_check, QemuExitCode};
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    kernel::test_init(boot_info);
+    init_test_idt();
+
+    stack_overflow();
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after stack overflow");
+    exit_qemu(QemuExitCode::Failed);
+    kernel::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(&0).read(); // prevent tail recursion optimizations
+}
+
+lazy_static! {
+    // Without a page fault handler the overflow escalates to a double fault,
+    // which still runs on the interrupt stack the kernel's TSS provides
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(interrupts::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    let marker = 0u8;
+    let stack_top = gdt::interrupt_stack_top(interrupts::DOUBLE_FAULT_IST_INDEX);
+    let stack = (stack_top - gdt::IST_STACK_SIZE as u64)..stack_top;
+    let on_ist_stack = stack.contains(&VirtAddr::from_ptr(&marker));
+    let overflowed_elsewhere = !stack.contains(&stack_frame.stack_pointer);
+    test_check(on_ist_stack && overflowed_elsewhere);
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use kernel::{
+    apic, clock, exit_qemu, percpu, serial_print, test_check, thread, time, QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    apic::init();
+    time::init();
+    percpu::init(0, apic::local_apic().id());
+    thread::init();
+
+    serial_print!("threads::join_returns_result...\t");
+    let handle = thread::spawn_thread(|| 6 * 7);
+    test_check(handle.join() == 42);
+
+    serial_print!("threads::busy_thread_is_preempted...\t");
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+    // Both on this CPU, so the ticker only gets to run if the busy thread
+    // is preempted
+    let cpu_id = percpu::current().id();
+    let busy = thread::spawn_thread_on(cpu_id, || {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    });
+    let ticker = thread::spawn_thread_on(cpu_id, || {
+        for _ in 0..3 {
+            thread::sleep(0.005);
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    ticker.join();
+    let busy_was_running = !busy.is_finished();
+    STOP.store(true, Ordering::Relaxed);
+    busy.join();
+    test_check(busy_was_running && TICKS.load(Ordering::Relaxed) == 3);
+
+    serial_print!("threads::sleep_waits...\t");
+    let start = clock::uptime();
+    thread::sleep(0.02);
+    test_check(clock::uptime() - start >= 0.02);
+
+    serial_print!("threads::yield_now_interleaves...\t");
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let handles = [0, 1].map(|parity| {
+        thread::spawn_thread(move || {
+            let mut in_turn = 0;
+            while COUNTER.load(Ordering::Acquire) < 10 {
+                if COUNTER.load(Ordering::Acquire) % 2 == parity {
+                    COUNTER.fetch_add(1, Ordering::AcqRel);
+                    in_turn += 1;
+                }
+                thread::yield_now();
+            }
+            in_turn
+        })
+    });
+    let [a, b] = handles.map(|handle| handle.join());
+    test_check(a == 5 && b == 5);
+
+    serial_print!("threads::simd_registers_are_preserved...\t");
+    let handles = [0x1111_1111_1111_1111u64, 0x2222_2222_2222_2222].map(|value| {
+        thread::spawn_thread(move || {
+            // The kernel never uses xmm0, so only the scheduler can change it
+            unsafe { asm!("movq xmm0, {}", in(reg) value) };
+            for _ in 0..20 {
+                thread::yield_now();
+            }
+            let after: u64;
+            unsafe { asm!("movq {}, xmm0", out(reg) after) };
+            after == value
+        })
+    });
+    test_check(handles.into_iter().all(|handle| handle.join()));
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
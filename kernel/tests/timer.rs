@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::time::Duration;
+use futures_util::stream::StreamExt;
+use kernel::{
+    apic, clock, exit_qemu, serial_print,
+    task::{executor::Executor, timer, Task},
+    test_check, time, QemuExitCode,
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+static ORDER: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    apic::init();
+    time::init();
+
+    serial_print!("timer::blocking_sleep_waits...\t");
+    let start = clock::uptime();
+    time::sleep(0.02);
+    test_check(clock::uptime() - start >= 0.02);
+
+    let mut executor = Executor::new();
+    for (id, delay) in [(2, 30), (1, 10)] {
+        executor.spawn(Task::new(async move {
+            timer::sleep(Duration::from_millis(delay)).await;
+            ORDER.lock().push(id);
+        }));
+    }
+    executor.spawn(Task::new(run_tests()));
+    executor.run();
+}
+
+async fn run_tests() {
+    serial_print!("timer::sleep_waits...\t");
+    let start = clock::uptime_nanoseconds();
+    timer::sleep(Duration::from_millis(20)).await;
+    test_check(clock::uptime_nanoseconds() - start >= 20_000_000);
+
+    serial_print!("timer::timers_fire_in_order...\t");
+    timer::sleep_until(start + 40_000_000).await;
+    test_check(*ORDER.lock() == [1, 2]);
+
+    serial_print!("timer::interval_ticks...\t");
+    let start = clock::uptime_nanoseconds();
+    let ticks: Vec<u64> = timer::interval(Duration::from_millis(5))
+        .take(3)
+        .collect()
+        .await;
+    let spaced = ticks.windows(2).all(|pair| pair[1] - pair[0] >= 5_000_000);
+    test_check(ticks[0] - start >= 5_000_000 && spaced);
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
@@ -0,0 +1,242 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{arch::global_asm, slice};
+use kernel::{
+    apic,
+    elf::{self, ElfError},
+    exit_qemu,
+    memory::{self, Backing},
+    percpu,
+    process::{self, AddressSpace, EXIT_CODE_FAULT},
+    serial_print,
+    syscall::{self, abi},
+    test_check, thread, time, QemuExitCode,
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+// Position independent programs, copied to a user page before running.
+global_asm!(
+    r#"
+.global user_hello_start
+.global user_hello_end
+user_hello_start:
+    mov eax, {write}
+    mov edi, 1
+    lea rsi, [rip + user_hello_message]
+    mov edx, 18
+    syscall
+    cmp rax, 18
+    jne user_hello_fail
+
+    mov eax, {time}
+    mov edi, {monotonic}
+    syscall
+    test rax, rax
+    js user_hello_fail
+
+    mov eax, {mmap}
+    mov edi, 4096
+    mov esi, {read_write}
+    syscall
+    test rax, rax
+    js user_hello_fail
+    mov qword ptr [rax], 7
+    cmp qword ptr [rax], 7
+    jne user_hello_fail
+
+    mov eax, 999
+    syscall
+    cmp rax, -1
+    jne user_hello_fail
+
+    mov eax, {yield}
+    syscall
+
+    mov eax, {exit}
+    mov edi, 42
+    syscall
+user_hello_fail:
+    mov eax, {exit}
+    mov edi, 1
+    syscall
+user_hello_message:
+    .ascii "hello from ring 3\n"
+user_hello_end:
+
+.global user_fault_start
+.global user_fault_end
+user_fault_start:
+    mov rax, qword ptr [0]
+user_fault_end:
+
+.global user_privileged_start
+.global user_privileged_end
+user_privileged_start:
+    cli
+user_privileged_end:
+
+.global user_stack_start
+.global user_stack_end
+user_stack_start:
+    sub rsp, 0x100000
+    mov qword ptr [rsp], 7
+    cmp qword ptr [rsp], 7
+    jne user_stack_fail
+    mov eax, {exit}
+    mov edi, 42
+    syscall
+user_stack_fail:
+    mov eax, {exit}
+    mov edi, 1
+    syscall
+user_stack_end:
+
+.global user_stack_overflow_start
+.global user_stack_overflow_end
+user_stack_overflow_start:
+    sub rsp, 0x1000000
+    mov qword ptr [rsp], 7
+user_stack_overflow_end:
+"#,
+    write = const abi::number::WRITE,
+    time = const abi::number::TIME,
+    mmap = const abi::number::MMAP,
+    yield = const abi::number::YIELD,
+    exit = const abi::number::EXIT,
+    monotonic = const abi::clock::MONOTONIC,
+    read_write = const abi::prot::READ | abi::prot::WRITE,
+);
+
+extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_fault_start: u8;
+    static user_fault_end: u8;
+    static user_privileged_start: u8;
+    static user_privileged_end: u8;
+    static user_stack_start: u8;
+    static user_stack_end: u8;
+    static user_stack_overflow_start: u8;
+    static user_stack_overflow_end: u8;
+}
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+    memory::protect_kernel();
+
+    apic::init();
+    time::init();
+    percpu::init(0, apic::local_apic().id());
+    thread::init();
+    syscall::init();
+
+    serial_print!("usermode::syscalls...\t");
+    let code = unsafe { program(&raw const user_hello_start, &raw const user_hello_end) };
+    test_check(run(code) == 42);
+
+    serial_print!("usermode::page_fault_kills_process...\t");
+    let code = unsafe { program(&raw const user_fault_start, &raw const user_fault_end) };
+    test_check(run(code) == EXIT_CODE_FAULT);
+
+    serial_print!("usermode::privileged_instruction_kills_process...\t");
+    let code = unsafe {
+        program(
+            &raw const user_privileged_start,
+            &raw const user_privileged_end,
+        )
+    };
+    test_check(run(code) == EXIT_CODE_FAULT);
+
+    serial_print!("usermode::stack_grows_on_fault...\t");
+    let code = unsafe { program(&raw const user_stack_start, &raw const user_stack_end) };
+    test_check(run(code) == 42);
+
+    serial_print!("usermode::stack_limit_kills_process...\t");
+    let code = unsafe {
+        program(
+            &raw const user_stack_overflow_start,
+            &raw const user_stack_overflow_end,
+        )
+    };
+    test_check(run(code) == EXIT_CODE_FAULT);
+
+    serial_print!("usermode::elf_program...\t");
+    let code = unsafe { program(&raw const user_hello_start, &raw const user_hello_end) };
+    let image = elf_image(code);
+    let handle = elf::spawn(&image, &["hello"], &["TERM=xento"]);
+    test_check(handle.is_ok_and(|handle| handle.wait() == 42));
+
+    serial_print!("usermode::elf_errors...\t");
+    let mut bad_magic = image.clone();
+    bad_magic[0] = 0;
+    test_check(
+        elf::spawn(&image[..100], &[], &[]).err() == Some(ElfError::Truncated)
+            && elf::spawn(&bad_magic, &[], &[]).err() == Some(ElfError::InvalidMagic),
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
+
+unsafe fn program(start: *const u8, end: *const u8) -> &'static [u8] {
+    slice::from_raw_parts(start, end.offset_from(start) as usize)
+}
+
+/// Runs `code` in a new process and returns its exit code.
+fn run(code: &[u8]) -> i64 {
+    let mut address_space = AddressSpace::new().expect("failed to create an address space");
+    let entry = VirtAddr::new(process::USER_START);
+    address_space
+        .map(
+            entry,
+            code.len() as u64,
+            PageTableFlags::empty(),
+            Backing::Anonymous,
+        )
+        .expect("failed to map the program");
+    address_space.write(entry, code).unwrap();
+    let stack_top = address_space.map_stack().expect("failed to map the stack");
+    process::spawn(address_space, entry, stack_top).wait()
+}
+
+/// Wraps `code` in an executable with a single segment, loaded at the
+/// start of the user range together with the headers.
+fn elf_image(code: &[u8]) -> Vec<u8> {
+    const HEADERS_SIZE: u64 = 64 + 56;
+    let base = process::USER_START;
+    let size = HEADERS_SIZE + code.len() as u64;
+
+    let mut image = Vec::new();
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // executable
+    image.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(base + HEADERS_SIZE).to_le_bytes()); // entry
+    image.extend_from_slice(&64u64.to_le_bytes()); // program headers
+    image.extend_from_slice(&0u64.to_le_bytes()); // section headers
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&56u16.to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    image.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    image.extend_from_slice(&0b101u32.to_le_bytes()); // R+X
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&4096u64.to_le_bytes());
+
+    image.extend_from_slice(code);
+    image
+}
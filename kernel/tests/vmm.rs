@@ -0,0 +1,208 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::slab,
+    exit_qemu,
+    memory::{self, vmm::KERNEL_VM_START, AddressSpace, Backing, PageFault, VmError},
+    process::{USER_END, USER_STACK_LIMIT, USER_STACK_SIZE, USER_STACK_TOP, USER_START},
+    serial_print, test_check, QemuExitCode,
+};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::TranslateResult, FrameAllocator, FrameDeallocator, PageTableFlags, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::test_init(boot_info);
+
+    let writable = PageTableFlags::WRITABLE;
+
+    serial_print!("vmm::allocations_have_guard_pages...\t");
+    let first = memory::kernel_space()
+        .allocate(3 * PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let second = memory::kernel_space()
+        .allocate(PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let area = memory::kernel_space().find(first + 1u64).copied();
+    test_check(
+        first.as_u64() > KERNEL_VM_START
+            && second >= first + 4 * PAGE_SIZE
+            && area.is_some_and(|area| area.start == first && area.size == 3 * PAGE_SIZE),
+    );
+
+    serial_print!("vmm::unmap_splits_areas...\t");
+    let before = memory::frame_stats();
+    let start = memory::kernel_space()
+        .allocate(4 * PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let mut space = memory::kernel_space();
+    space.unmap(start + PAGE_SIZE, PAGE_SIZE).unwrap();
+    let split = space.find(start).map(|area| area.size) == Some(PAGE_SIZE)
+        && space.find(start + PAGE_SIZE).is_none()
+        && space.find(start + 2 * PAGE_SIZE).map(|area| area.size) == Some(2 * PAGE_SIZE);
+    drop(space);
+    let unmapped = !is_mapped(start + PAGE_SIZE) && is_mapped(start + 2 * PAGE_SIZE);
+    memory::kernel_space().unmap(start, 4 * PAGE_SIZE).unwrap();
+    test_check(split && unmapped && memory::frame_stats() == before);
+
+    serial_print!("vmm::protect_changes_flags...\t");
+    let start = memory::kernel_space()
+        .allocate(2 * PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let mut space = memory::kernel_space();
+    space
+        .protect(start, PAGE_SIZE, PageTableFlags::NO_EXECUTE)
+        .unwrap();
+    let areas = space.find(start).map(|area| area.size) == Some(PAGE_SIZE)
+        && space.find(start + PAGE_SIZE).map(|area| area.size) == Some(PAGE_SIZE);
+    let not_mapped = space.protect(start, 4 * PAGE_SIZE, writable);
+    drop(space);
+    test_check(
+        areas
+            && not_mapped == Err(VmError::NotMapped)
+            && !flags(start).contains(PageTableFlags::WRITABLE)
+            && flags(start + PAGE_SIZE).contains(PageTableFlags::WRITABLE),
+    );
+
+    serial_print!("vmm::physical_backing_is_kept...\t");
+    let frame = memory::frame_allocator().allocate_frame().unwrap();
+    unsafe { *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = 0x1234 };
+    let before = memory::frame_stats();
+    let addr = memory::kernel_space()
+        .allocate(
+            PAGE_SIZE,
+            writable,
+            Backing::Physical(frame.start_address()),
+        )
+        .unwrap();
+    let value = unsafe { *addr.as_ptr::<u64>() };
+    memory::kernel_space().unmap(addr, PAGE_SIZE).unwrap();
+    let kept = memory::frame_stats() == before;
+    unsafe { memory::frame_allocator().deallocate_frame(frame) };
+    test_check(value == 0x1234 && kept);
+
+    serial_print!("vmm::process_space_is_freed...\t");
+    let before = frames_in_use();
+    let tables = page_tables().allocated;
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START);
+    space
+        .map(start, 8 * PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let overlap = space.map(start + PAGE_SIZE, PAGE_SIZE, writable, Backing::Anonymous);
+    let outside = space.map(
+        VirtAddr::new(KERNEL_VM_START),
+        PAGE_SIZE,
+        writable,
+        Backing::Anonymous,
+    );
+    let overhanging = space.map(
+        VirtAddr::new(USER_END - PAGE_SIZE),
+        PAGE_SIZE + 1,
+        writable,
+        Backing::Anonymous,
+    );
+    let allocated = space.allocate(PAGE_SIZE, writable, Backing::Anonymous);
+    let accessible = space.is_accessible(start, 8 * PAGE_SIZE, true);
+    drop(space);
+    test_check(
+        overlap == Err(VmError::Overlap)
+            && outside == Err(VmError::InvalidRange)
+            && overhanging == Err(VmError::InvalidRange)
+            && allocated.is_ok_and(|addr| addr >= start + 9 * PAGE_SIZE)
+            && accessible
+            && frames_in_use() == before
+            && page_tables().allocated == tables,
+    );
+
+    serial_print!("vmm::page_tables_are_reused...\t");
+    let first = AddressSpace::new().unwrap().page_table();
+    let space = AddressSpace::new().unwrap();
+    test_check(space.page_table() == first && page_tables().allocated == tables + 1);
+    drop(space);
+
+    serial_print!("vmm::anonymous_memory_is_mapped_on_access...\t");
+    let mut space = AddressSpace::new().unwrap();
+    let before = memory::frame_stats();
+    let start = space
+        .allocate(16 * PAGE_SIZE, writable, Backing::Anonymous)
+        .unwrap();
+    let read_only = space
+        .allocate(PAGE_SIZE, PageTableFlags::empty(), Backing::Anonymous)
+        .unwrap();
+    let lazy = memory::frame_stats() == before;
+    let mapped = space.handle_fault(&user_write(start + 5 * PAGE_SIZE));
+    let used = memory::frame_stats().used - before.used;
+    let unmapped = space.handle_fault(&user_write(start + 16 * PAGE_SIZE));
+    let protected = space.handle_fault(&user_write(read_only));
+    drop(space);
+    test_check(
+        lazy && mapped.is_ok()
+            && used > 0
+            && unmapped == Err(VmError::NotMapped)
+            && protected == Err(VmError::Protection),
+    );
+
+    serial_print!("vmm::stacks_grow_down...\t");
+    let mut space = AddressSpace::new().unwrap();
+    let top = space.map_stack().unwrap();
+    let below = top - USER_STACK_SIZE - 3 * PAGE_SIZE;
+    let grown = space.handle_fault(&user_write(below));
+    let area = space.find(below).copied();
+    let too_far = space.handle_fault(&user_write(top - USER_STACK_LIMIT - PAGE_SIZE));
+    drop(space);
+    test_check(
+        top.as_u64() == USER_STACK_TOP
+            && grown.is_ok()
+            && area.is_some_and(|area| area.start == below && area.end() == top)
+            && too_far == Err(VmError::NotMapped),
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    kernel::hlt_loop();
+}
+
+fn user_write(addr: VirtAddr) -> PageFault {
+    PageFault::new(
+        addr,
+        PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE,
+    )
+}
+
+fn page_tables() -> slab::CacheStats {
+    slab::caches()
+        .find(|cache| cache.name == "page-tables")
+        .expect("no page table cache")
+}
+
+/// Frames in use, except for the slabs of the page table cache, which keeps
+/// the tables of dropped address spaces.
+fn frames_in_use() -> usize {
+    let tables = page_tables();
+    memory::frame_stats().used - tables.slabs * tables.slab_size / PAGE_SIZE as usize
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::mapper().translate_addr(addr).is_some()
+}
+
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    match memory::mapper().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => PageTableFlags::empty(),
+    }
+}